@@ -0,0 +1 @@
+DROP TABLE catch_all_cache;
@@ -0,0 +1,5 @@
+CREATE TABLE catch_all_cache (
+    domain TEXT PRIMARY KEY,
+    is_catch_all BOOLEAN NOT NULL,
+    probed_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
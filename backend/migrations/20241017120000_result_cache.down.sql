@@ -0,0 +1 @@
+DROP INDEX idx_v1_task_result_to_email;
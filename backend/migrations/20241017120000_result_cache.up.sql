@@ -0,0 +1 @@
+CREATE INDEX idx_v1_task_result_to_email ON v1_task_result ((payload->'input'->>'to_email'), created_at DESC);
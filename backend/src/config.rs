@@ -14,16 +14,31 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::proxy_list::{load_proxy_list, ProxyListConfig};
+use crate::storage::catch_all::PostgresCatchAllCache;
+use crate::storage::result_cache::{PostgresResultCache, ResultCache, ResultCacheConfig};
 use crate::storage::{postgres::PostgresStorage, StorageAdapter};
 use crate::throttle::ThrottleManager;
+use crate::timings::TimingStats;
 use crate::worker::do_work::TaskWebhook;
 use crate::worker::setup_rabbit_mq;
 use anyhow::{bail, Context};
-use check_if_email_exists::smtp::proxy_rotator::ProxyRotator;
+use check_if_email_exists::mx::resolver::{build_resolver, DnsConfig, DnsResolver};
+use check_if_email_exists::mx::MxFallbackStrategy;
+use check_if_email_exists::reachability::ReachabilityPolicy;
+use check_if_email_exists::rules::{init_rules, RulesConfig};
+use check_if_email_exists::score::ScoreWeights;
+use check_if_email_exists::smtp::catch_all::{
+        CatchAllCache, CatchAllCacheConfig, InMemoryCatchAllCache,
+};
+use check_if_email_exists::smtp::greylisting::GreylistingConfig;
+use check_if_email_exists::smtp::patterns::{init_patterns, SmtpPatternsConfig};
+use check_if_email_exists::smtp::proxy_rotator::{ProxyPoolRotators, ProxyRotator};
+use check_if_email_exists::smtp::session_pool::{SmtpSessionPool, SmtpSessionPoolConfig};
 use check_if_email_exists::smtp::verif_method::{
         EverythingElseVerifMethod, GmailVerifMethod, HotmailB2BVerifMethod, HotmailB2CVerifMethod,
-        MimecastVerifMethod, ProofpointVerifMethod, ProxyPoolConfig, VerifMethod,
-        VerifMethodSmtpConfig, YahooVerifMethod, DEFAULT_PROXY_ID,
+        MimecastVerifMethod, NamedProxyPool, ProofpointVerifMethod, ProxyPoolConfig, StartTlsMode,
+        VerifMethod, VerifMethodError, VerifMethodSmtpConfig, YahooVerifMethod, DEFAULT_PROXY_ID,
 };
 use check_if_email_exists::{CheckEmailInputProxy, WebdriverConfig, LOG_TARGET};
 use config::Config;
@@ -31,9 +46,9 @@ use lapin::Channel;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BackendConfig {
@@ -46,6 +61,13 @@ pub struct BackendConfig {
         /// Timeout for each SMTP connection, in seconds. Leaving it commented out
         /// will not set a timeout, i.e. the connection will wait indefinitely.
         pub smtp_timeout: Option<u64>,
+        /// Timeout of each of the Gravatar and HaveIBeenPwned lookups, in
+        /// seconds.
+        #[serde(default = "default_misc_timeout")]
+        pub misc_timeout: Option<u64>,
+        /// Whether to upgrade the SMTP session to TLS with STARTTLS.
+        #[serde(default)]
+        pub starttls: StartTlsMode,
         // This field is deprecated, but kept for backwards compatibility. If set,
         // it will be moved to the "default" proxy in the `verif_method.proxies`
         // field.
@@ -59,6 +81,63 @@ pub struct BackendConfig {
         /// proxies instead of always using the default proxy.
         pub proxy_pool: ProxyPoolConfig,
 
+        /// Named proxy pools, which the email providers in `overrides` can
+        /// rotate through instead of the global proxy pool.
+        #[serde(default)]
+        pub proxy_pools: HashMap<String, NamedProxyPool>,
+
+        /// Proxies loaded from a file, a URL or a single string, in addition to
+        /// `overrides.proxies`.
+        #[serde(default)]
+        pub proxy_list: ProxyListConfig,
+
+        /// Which MX hosts to try during the SMTP verification.
+        #[serde(default)]
+        pub mx_fallback: MxFallbackStrategy,
+
+        /// How to retry the SMTP verification when the server greylists us. In
+        /// worker mode, greylisted bulk verifications can also be deferred.
+        #[serde(default)]
+        pub greylisting: GreylistingConfig,
+
+        /// DNS resolver configuration for the MX lookups.
+        #[serde(default)]
+        pub dns: DnsConfig,
+
+        /// Patterns used to classify the SMTP replies by their text.
+        #[serde(default)]
+        pub smtp_patterns: SmtpPatternsConfig,
+
+        /// Per-domain and per-MX rules overriding the verification settings.
+        #[serde(default)]
+        pub rules: RulesConfig,
+
+        /// Reuse of the SMTP sessions across the verifications of bulk jobs.
+        #[serde(default)]
+        pub smtp_session_pool: SmtpSessionPoolConfig,
+
+        /// Cache of the catch-all probes by domain.
+        #[serde(default)]
+        pub catch_all_cache: CatchAllCacheConfig,
+
+        /// Which signals make an email risky or invalid. Requests can override
+        /// it with their own `reachability_policy`.
+        #[serde(default)]
+        pub reachability_policy: ReachabilityPolicy,
+
+        /// Weights of the signals in the deliverability score.
+        #[serde(default)]
+        pub score_weights: ScoreWeights,
+
+        /// Reuse of the recent verification results stored in Postgres.
+        #[serde(default)]
+        pub result_cache: ResultCacheConfig,
+
+        /// Whether to record the full SMTP conversation in the debug output of
+        /// each verification (and therefore in the stored results).
+        #[serde(default)]
+        pub smtp_transcript: bool,
+
         /// Webdriver configuration.
         pub webdriver_addr: String,
         pub webdriver: WebdriverConfig,
@@ -96,7 +175,26 @@ pub struct BackendConfig {
         throttle_manager: Arc<ThrottleManager>,
 
         #[serde(skip)]
-        proxy_rotator: Option<Arc<ProxyRotator>>,
+        proxy_state: Arc<RwLock<Arc<ProxyState>>>,
+
+        #[serde(skip)]
+        dns_resolver: Option<Arc<DnsResolver>>,
+
+        #[serde(skip)]
+        session_pool: Option<Arc<SmtpSessionPool>>,
+
+        #[serde(skip)]
+        shared_catch_all_cache: Option<Arc<dyn CatchAllCache>>,
+
+        #[serde(skip)]
+        shared_result_cache: Option<Arc<dyn ResultCache>>,
+
+        #[serde(skip)]
+        timing_stats: Arc<TimingStats>,
+}
+
+fn default_misc_timeout() -> Option<u64> {
+        Some(10)
 }
 
 impl BackendConfig {
@@ -109,9 +207,24 @@ impl BackendConfig {
                         from_email: "".to_string(),
                         hello_name: "".to_string(),
                         smtp_timeout: None,
+                        misc_timeout: default_misc_timeout(),
+                        starttls: StartTlsMode::default(),
                         proxy: None,
                         overrides: OverridesConfig::default(),
                         proxy_pool: ProxyPoolConfig::default(),
+                        proxy_pools: HashMap::new(),
+                        proxy_list: ProxyListConfig::default(),
+                        mx_fallback: MxFallbackStrategy::default(),
+                        greylisting: GreylistingConfig::default(),
+                        dns: DnsConfig::default(),
+                        smtp_patterns: SmtpPatternsConfig::default(),
+                        rules: RulesConfig::default(),
+                        smtp_session_pool: SmtpSessionPoolConfig::default(),
+                        catch_all_cache: CatchAllCacheConfig::default(),
+                        reachability_policy: ReachabilityPolicy::default(),
+                        score_weights: ScoreWeights::default(),
+                        result_cache: ResultCacheConfig::default(),
+                        smtp_transcript: false,
                         http_host: "127.0.0.1".to_string(),
                         http_port: 8080,
                         header_secret: None,
@@ -125,21 +238,55 @@ impl BackendConfig {
                         throttle_manager: Arc::new(
                                 ThrottleManager::new(ThrottleConfig::new_without_throttle()),
                         ),
-                        proxy_rotator: None,
+                        proxy_state: Arc::default(),
+                        dns_resolver: None,
+                        session_pool: None,
+                        shared_catch_all_cache: None,
+                        shared_result_cache: None,
+                        timing_stats: Arc::new(TimingStats::default()),
                 }
         }
 
+        fn get_proxy_state(&self) -> Arc<ProxyState> {
+                self.proxy_state
+                        .read()
+                        .map(|s| s.clone())
+                        .unwrap_or_else(|e| e.into_inner().clone())
+        }
+
         /// Get the shared proxy rotator for round-robin rotation across requests.
         pub fn get_proxy_rotator(&self) -> Option<Arc<ProxyRotator>> {
-                self.proxy_rotator.clone()
+                self.get_proxy_state().rotator.clone()
         }
 
-        /// Initialize the proxy rotator based on the configuration.
-        /// This should be called after loading the configuration.
-        pub fn init_proxy_rotator(&mut self) {
+        /// Get the shared rotators of the named proxy pools, if any.
+        pub fn get_proxy_pool_rotators(&self) -> Option<Arc<ProxyPoolRotators>> {
+                self.get_proxy_state().pool_rotators.clone()
+        }
+
+        /// Use the proxies loaded from the proxy list, along with the ones of
+        /// `overrides.proxies`, and rebuild the shared proxy rotators. This
+        /// should be called after loading the configuration, and again whenever
+        /// the proxy list is reloaded. If the proxies are invalid, e.g. a proxy
+        /// pool references a missing proxy, the previous ones are kept. The
+        /// proxies still in the list keep their health and in-flight
+        /// verifications across reloads.
+        pub fn set_loaded_proxies(
+                &self,
+                loaded_proxies: HashMap<String, CheckEmailInputProxy>,
+        ) -> Result<(), VerifMethodError> {
+                let mut proxies = loaded_proxies;
+                proxies.extend(self.overrides.proxies.clone());
+                let verif_method = self.build_verif_method(&proxies);
+                verif_method.validate_proxies()?;
+
+                let previous = self.get_proxy_state();
+                let mut state = ProxyState {
+                        proxies,
+                        ..Default::default()
+                };
                 if self.proxy_pool.enabled {
-                        let proxy_ids: Vec<String> = self
-                                .overrides
+                        let proxy_ids: Vec<String> = state
                                 .proxies
                                 .keys()
                                 .filter(|id| *id != DEFAULT_PROXY_ID)
@@ -147,14 +294,20 @@ impl BackendConfig {
                                 .collect();
 
                         if !proxy_ids.is_empty() {
-                                let rotator = ProxyRotator::new(proxy_ids.clone(), self.proxy_pool.strategy.clone());
+                                let rotator = ProxyRotator::new(proxy_ids.clone(), self.proxy_pool.strategy.clone())
+                                        .with_weights(&state.proxies)
+                                        .with_health_config(self.proxy_pool.health.clone());
+                                let rotator = match &previous.rotator {
+                                        Some(previous) => rotator.with_state_from(previous),
+                                        None => rotator,
+                                };
                                 info!(
                                         target: LOG_TARGET,
                                         proxy_count = proxy_ids.len(),
                                         strategy = ?self.proxy_pool.strategy,
                                         "Initialized shared proxy rotator for round-robin rotation"
                                 );
-                                self.proxy_rotator = Some(Arc::new(rotator));
+                                state.rotator = Some(Arc::new(rotator));
                         } else {
                                 warn!(
                                         target: LOG_TARGET,
@@ -162,10 +315,95 @@ impl BackendConfig {
                                 );
                         }
                 }
+
+                if !self.proxy_pools.is_empty() {
+                        let rotators: ProxyPoolRotators = verif_method
+                                .new_pool_rotators()
+                                .into_iter()
+                                .map(|(name, rotator)| {
+                                        let previous = previous
+                                                .pool_rotators
+                                                .as_ref()
+                                                .and_then(|rotators| rotators.get(&name));
+                                        match previous {
+                                                Some(previous) => (name, rotator.with_state_from(previous)),
+                                                None => (name, rotator),
+                                        }
+                                })
+                                .collect();
+                        info!(
+                                target: LOG_TARGET,
+                                pools = ?rotators.keys().collect::<Vec<_>>(),
+                                "Initialized shared proxy pool rotators"
+                        );
+                        state.pool_rotators = Some(Arc::new(rotators));
+                }
+
+                let state = Arc::new(state);
+                match self.proxy_state.write() {
+                        Ok(mut s) => *s = state,
+                        Err(e) => *e.into_inner() = state,
+                }
+                Ok(())
+        }
+
+        /// Get the shared DNS resolver, so that its cache is reused across
+        /// requests.
+        pub fn get_dns_resolver(&self) -> Option<Arc<DnsResolver>> {
+                self.dns_resolver.clone()
+        }
+
+        /// Initialize the shared DNS resolver based on the configuration.
+        /// This should be called after loading the configuration.
+        pub fn init_dns_resolver(&mut self) -> Result<(), anyhow::Error> {
+                let resolver = build_resolver(&self.dns).context("Invalid DNS configuration")?;
+                info!(
+                        target: LOG_TARGET,
+                        nameservers = ?self.dns.nameservers,
+                        protocol = ?self.dns.protocol,
+                        "Initialized shared DNS resolver"
+                );
+                self.dns_resolver = Some(Arc::new(resolver));
+                Ok(())
+        }
+
+        /// Get the shared pool of idle SMTP sessions, if session reuse is
+        /// enabled.
+        pub fn get_smtp_session_pool(&self) -> Option<Arc<SmtpSessionPool>> {
+                self.session_pool.clone()
+        }
+
+        /// Initialize the shared SMTP session pool based on the configuration.
+        /// This should be called after loading the configuration.
+        pub fn init_smtp_session_pool(&mut self) {
+                if self.smtp_session_pool.enabled {
+                        info!(
+                                target: LOG_TARGET,
+                                max_recipients_per_session = self.smtp_session_pool.max_recipients_per_session,
+                                "Initialized shared SMTP session pool"
+                        );
+                        self.session_pool = Some(Arc::new(SmtpSessionPool::new(self.smtp_session_pool.clone())));
+                }
+        }
+
+        /// Get the shared cache of the catch-all probes, if enabled.
+        pub fn get_catch_all_cache(&self) -> Option<Arc<dyn CatchAllCache>> {
+                self.shared_catch_all_cache.clone()
+        }
+
+        /// Get the shared result cache, if enabled.
+        pub fn get_result_cache(&self) -> Option<Arc<dyn ResultCache>> {
+                self.shared_result_cache.clone()
         }
 
         pub fn get_verif_method(&self) -> VerifMethod {
-                let mut proxies = self.overrides.proxies.clone();
+                self.build_verif_method(&self.get_proxy_state().proxies)
+        }
+
+        /// Build the verification method with the given proxies, to which the
+        /// default proxy is added.
+        fn build_verif_method(&self, proxies: &HashMap<String, CheckEmailInputProxy>) -> VerifMethod {
+                let mut proxies = proxies.clone();
                 if let Some(proxy) = self.proxy.as_ref() {
                         proxies.insert(DEFAULT_PROXY_ID.to_string(), proxy.clone());
                 }
@@ -175,12 +413,14 @@ impl BackendConfig {
                         hello_name: self.hello_name.clone(),
                         proxy: self.proxy.as_ref().map(|_| DEFAULT_PROXY_ID.to_string()),
                         smtp_timeout: self.smtp_timeout.map(Duration::from_secs),
+                        starttls: self.starttls,
                         ..Default::default()
                 };
 
                 VerifMethod {
                         proxies,
                         proxy_pool: self.proxy_pool.clone(),
+                        proxy_pools: self.proxy_pools.clone(),
                         gmail: self
                                 .overrides
                                 .gmail
@@ -263,6 +503,61 @@ impl BackendConfig {
                 // Initialize throttle manager
                 self.throttle_manager = Arc::new(ThrottleManager::new(self.throttle.clone()));
 
+                // Probe the proxies of the rotations in the background, if enabled.
+                // The rotators are looked up at each probe, as they're rebuilt when
+                // the proxy list is reloaded.
+                let health = &self.proxy_pool.health;
+                if health.enabled && health.probe_interval_secs > 0 {
+                        info!(
+                                target: LOG_TARGET,
+                                interval_secs = health.probe_interval_secs,
+                                "Starting background proxy probes"
+                        );
+                        let proxy_state = Arc::clone(&self.proxy_state);
+                        let mut interval = tokio::time::interval(Duration::from_secs(health.probe_interval_secs));
+                        tokio::spawn(async move {
+                                loop {
+                                        interval.tick().await;
+                                        let state = proxy_state
+                                                .read()
+                                                .map(|s| s.clone())
+                                                .unwrap_or_else(|e| e.into_inner().clone());
+                                        let rotators = state
+                                                .rotator
+                                                .iter()
+                                                .map(AsRef::as_ref)
+                                                .chain(state.pool_rotators.iter().flat_map(|r| r.values()));
+                                        futures::future::join_all(rotators.map(|r| r.probe(&state.proxies))).await;
+                                }
+                        });
+                }
+
+                // Initialize the catch-all cache, in the database if there's one
+                if self.catch_all_cache.enabled {
+                        let ttl = self.catch_all_cache.ttl();
+                        let cache: Arc<dyn CatchAllCache> = match self.get_pg_pool() {
+                                Some(pg_pool) => Arc::new(PostgresCatchAllCache::new(pg_pool, ttl)),
+                                None => Arc::new(InMemoryCatchAllCache::new(ttl)),
+                        };
+                        info!(target: LOG_TARGET, cache=?cache, "Initialized shared catch-all cache");
+                        self.shared_catch_all_cache = Some(cache);
+                }
+
+                // Initialize the result cache, which reads the stored results
+                if self.result_cache.enabled {
+                        match self.get_pg_pool() {
+                                Some(pg_pool) => {
+                                        let cache: Arc<dyn ResultCache> =
+                                                Arc::new(PostgresResultCache::new(pg_pool, self.result_cache.clone()));
+                                        info!(target: LOG_TARGET, cache=?cache, "Initialized result cache");
+                                        self.shared_result_cache = Some(cache);
+                                }
+                                None => {
+                                        warn!(target: LOG_TARGET, "The result cache requires a Postgres storage, it is disabled");
+                                }
+                        }
+                }
+
                 Ok(())
         }
 
@@ -282,6 +577,12 @@ impl BackendConfig {
         pub fn get_throttle_manager(&self) -> Arc<ThrottleManager> {
                 self.throttle_manager.clone()
         }
+
+        /// Get the timing statistics of the verifications made by this
+        /// backend process.
+        pub fn get_timing_stats(&self) -> Arc<TimingStats> {
+                self.timing_stats.clone()
+        }
 }
 
 #[derive(Debug, Default, Deserialize, Clone, Serialize)]
@@ -295,6 +596,16 @@ pub struct OverridesConfig {
         pub yahoo: Option<YahooVerifMethod>,
 }
 
+/// The proxies in use, i.e. the ones loaded from the proxy list and the ones
+/// of `overrides.proxies`, and the rotators built from them. It's replaced as
+/// a whole when the proxy list is reloaded.
+#[derive(Debug, Default)]
+struct ProxyState {
+        proxies: HashMap<String, CheckEmailInputProxy>,
+        rotator: Option<Arc<ProxyRotator>>,
+        pool_rotators: Option<Arc<ProxyPoolRotators>>,
+}
+
 #[derive(Debug, Default, Deserialize, Clone, Serialize)]
 pub struct WorkerConfig {
         pub enable: bool,
@@ -382,20 +693,126 @@ pub async fn load_config() -> Result<BackendConfig, anyhow::Error> {
                 }
         }
 
-        // 2. Validate the verif_method proxies, meaning that for each email
-        // provider's verification method, the proxy (if set) must exist in the
-        // `proxies` field.
-        cfg.get_verif_method().validate_proxies()?;
+        // 2. Load the proxy list, validate the verif_method proxies, meaning
+        // that for each email provider's verification method, the proxy (if
+        // set) must exist in the `proxies` field, and initialize the shared
+        // proxy rotators for round-robin rotation
+        let loaded_proxies = load_proxy_list(&cfg.proxy_list)
+                .await
+                .context("Invalid proxy list configuration")?;
+        cfg.set_loaded_proxies(loaded_proxies)?;
+
+        // 3. Initialize the shared DNS resolver
+        cfg.init_dns_resolver()?;
 
-        // 3. Initialize the shared proxy rotator for round-robin rotation
-        cfg.init_proxy_rotator();
+        // 4. Load the SMTP reply patterns, failing early on an invalid file
+        init_patterns(&cfg.smtp_patterns).context("Invalid SMTP patterns configuration")?;
+
+        // 5. Load the rules, failing early on an invalid file, and watch the
+        // file for changes if configured
+        init_rules(&cfg.rules).context("Invalid rules configuration")?;
+        spawn_rules_reloader(&cfg.rules);
+
+        // 6. Initialize the shared SMTP session pool
+        cfg.init_smtp_session_pool();
 
         Ok(cfg)
 }
 
+/// Reload the rules file whenever its modification time changes. An invalid
+/// file is logged, and the previous rules are kept.
+fn spawn_rules_reloader(config: &RulesConfig) {
+        let path = match &config.file {
+                Some(path) if config.reload_interval_secs > 0 => path.clone(),
+                _ => return,
+        };
+        let config = config.clone();
+        let modified = |path: &std::path::Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+        tokio::spawn(async move {
+                let mut last_modified = modified(&path);
+                let mut interval = tokio::time::interval(Duration::from_secs(config.reload_interval_secs));
+                loop {
+                        interval.tick().await;
+                        let current = modified(&path);
+                        if current == last_modified {
+                                continue;
+                        }
+                        last_modified = current;
+                        match init_rules(&config) {
+                                Ok(()) => info!(target: LOG_TARGET, file=?path, "Reloaded rules"),
+                                Err(e) => error!(target: LOG_TARGET, file=?path, error=?e, "Invalid rules file, keeping the previous rules"),
+                        }
+                }
+        });
+}
+
+/// Reload the proxy list every `reload_interval_secs` and, on Unix, on
+/// SIGHUP, rebuilding the shared proxy rotators. An invalid list is logged,
+/// and the previous proxies are kept.
+pub fn spawn_proxy_list_reloader(config: Arc<BackendConfig>) {
+        if !config.proxy_list.is_enabled() {
+                return;
+        }
+
+        tokio::spawn(async move {
+                #[cfg(unix)]
+                let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                        Ok(signal) => Some(signal),
+                        Err(e) => {
+                                error!(target: LOG_TARGET, error=?e, "Cannot listen to SIGHUP to reload the proxy list");
+                                None
+                        }
+                };
+                let reload_interval_secs = config.proxy_list.reload_interval_secs;
+                let mut interval = tokio::time::interval(Duration::from_secs(reload_interval_secs.max(1)));
+                // The proxy list was just loaded by `load_config`.
+                interval.tick().await;
+
+                loop {
+                        let on_interval = async {
+                                if reload_interval_secs > 0 {
+                                        interval.tick().await;
+                                } else {
+                                        futures::future::pending::<()>().await;
+                                }
+                        };
+                        #[cfg(unix)]
+                        let on_sighup = async {
+                                match sighup.as_mut() {
+                                        Some(signal) => {
+                                                signal.recv().await;
+                                        }
+                                        None => futures::future::pending::<()>().await,
+                                }
+                        };
+                        #[cfg(not(unix))]
+                        let on_sighup = futures::future::pending::<()>();
+                        tokio::select! {
+                                _ = on_interval => {}
+                                _ = on_sighup => {}
+                        }
+
+                        let loaded = load_proxy_list(&config.proxy_list)
+                                .await
+                                .map_err(anyhow::Error::from)
+                                .and_then(|proxies| {
+                                        let count = proxies.len();
+                                        config.set_loaded_proxies(proxies)?;
+                                        Ok(count)
+                                });
+                        match loaded {
+                                Ok(count) => info!(target: LOG_TARGET, proxy_count=count, "Reloaded proxy list"),
+                                Err(e) => error!(target: LOG_TARGET, error=?e, "Invalid proxy list, keeping the previous proxies"),
+                        }
+                }
+        });
+}
+
 #[cfg(test)]
 mod tests {
         use super::*;
+        use crate::proxy_list::parse_proxy_list;
         use serial_test::serial;
         use std::{env, time::Duration};
         use {
@@ -423,6 +840,59 @@ mod tests {
                 env::remove_var("RCH__OVERRIDES__PROXIES__PROXY3__PORT");
         }
 
+        #[tokio::test]
+        #[serial]
+        async fn test_proxy_list() {
+                env::set_var("RCH__PROXY_LIST__PROXIES", "host1:1080,host2:1081:user:pass");
+                env::set_var("RCH__PROXY_POOL__ENABLED", "true");
+                let cfg = load_config().await.unwrap();
+                let proxies = cfg.get_verif_method().proxies;
+                assert_eq!(proxies.len(), 2);
+                assert_eq!(
+                        proxies.get("host2:1081:user").unwrap().username.as_deref(),
+                        Some("user")
+                );
+                assert_eq!(cfg.get_proxy_rotator().unwrap().len(), 2);
+
+                // Reloading rebuilds the rotator.
+                let mut reloaded = HashMap::new();
+                reloaded.insert("file1".to_string(), CheckEmailInputProxy::default());
+                cfg.set_loaded_proxies(reloaded).unwrap();
+                assert_eq!(cfg.get_verif_method().proxies.len(), 1);
+                assert_eq!(cfg.get_proxy_rotator().unwrap().len(), 1);
+
+                env::remove_var("RCH__PROXY_LIST__PROXIES");
+                env::remove_var("RCH__PROXY_POOL__ENABLED");
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_proxy_list_reload_keeps_health() {
+                env::set_var("RCH__PROXY_LIST__PROXIES", "host1:1080,host2:1081");
+                env::set_var("RCH__PROXY_POOL__ENABLED", "true");
+                env::set_var("RCH__PROXY_POOL__HEALTH__FAILURE_THRESHOLD", "1");
+                let cfg = load_config().await.unwrap();
+                cfg.get_proxy_rotator().unwrap().record_failure("host1:1080");
+
+                // host1 moved down the list.
+                let mut reloaded = parse_proxy_list("host0:1079,host2:1081,host1:1080", &['\n', ',']).unwrap();
+                reloaded.insert("file1".to_string(), CheckEmailInputProxy::default());
+                cfg.set_loaded_proxies(reloaded).unwrap();
+                let rotator = cfg.get_proxy_rotator().unwrap();
+                assert_eq!(rotator.len(), 4);
+                let health = rotator.health();
+                let host1 = health.iter().find(|h| h.proxy_id == "host1:1080").unwrap();
+                assert!(!host1.healthy);
+                assert_eq!(host1.ejections, 1);
+                for _ in 0..8 {
+                        assert_ne!(rotator.get_next_proxy_id(), Some(&"host1:1080".to_string()));
+                }
+
+                env::remove_var("RCH__PROXY_LIST__PROXIES");
+                env::remove_var("RCH__PROXY_POOL__ENABLED");
+                env::remove_var("RCH__PROXY_POOL__HEALTH__FAILURE_THRESHOLD");
+        }
+
         #[tokio::test]
         #[serial]
         async fn test_default_proxy() {
@@ -545,7 +1015,9 @@ type = "smtp"
                                 smtp_port: 465,
                                 retries: 3,
                                 proxy: Some("proxy1".to_string()),
+                                proxy_pool: None,
                                 smtp_timeout: Some(Duration::from_secs(23)),
+                                starttls: StartTlsMode::Disabled,
                         })
                 );
 
@@ -557,7 +1029,9 @@ type = "smtp"
                                 smtp_port: 25,
                                 retries: 1,
                                 proxy: None,
+                                proxy_pool: None,
                                 smtp_timeout: None,
+                                starttls: StartTlsMode::Disabled,
                         })
                 );
 
@@ -50,7 +50,11 @@ pub fn create_routes(
 		.or(v1::bulk::get_progress::v1_get_bulk_job_progress(
 			Arc::clone(&config),
 		))
-		.or(v1::bulk::get_results::v1_get_bulk_job_results(config))
+		.or(v1::bulk::get_results::v1_get_bulk_job_results(Arc::clone(
+			&config,
+		)))
+		.or(v1::timings::get::v1_get_timings(Arc::clone(&config)))
+		.or(v1::proxies::get_health::v1_get_proxies_health(config))
 		.recover(handle_rejection)
 }
 
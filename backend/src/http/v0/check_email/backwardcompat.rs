@@ -46,6 +46,7 @@ impl BackwardCompatYahooVerifMethod {
 					None
 				},
 				retries,
+				..Default::default()
 			}),
 		}
 	}
@@ -83,6 +84,7 @@ impl BackwardCompatHotmailB2CVerifMethod {
 						None
 					},
 					retries,
+					..Default::default()
 				})
 			}
 		}
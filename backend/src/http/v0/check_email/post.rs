@@ -16,6 +16,7 @@
 
 //! This file implements the `POST /v0/check_email` endpoint.
 
+use check_if_email_exists::reachability::ReachabilityPolicy;
 use check_if_email_exists::smtp::verif_method::VerifMethod;
 use check_if_email_exists::{check_email, CheckEmailInput, CheckEmailInputProxy, LOG_TARGET};
 use serde::{Deserialize, Serialize};
@@ -26,6 +27,7 @@ use warp::{http, Filter};
 use super::backwardcompat::{BackwardCompatHotmailB2CVerifMethod, BackwardCompatYahooVerifMethod};
 use crate::config::BackendConfig;
 use crate::http::{check_header, ReacherResponseError};
+use crate::storage::result_cache::CachedResult;
 
 /// The request body for the `POST /v0/check_email` endpoint.
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -36,6 +38,16 @@ pub struct CheckEmailRequest {
         pub proxy: Option<CheckEmailInputProxy>,
         pub smtp_timeout: Option<Duration>,
         pub smtp_port: Option<u16>,
+        /// Whether to record the SMTP conversation in the debug output.
+        /// Defaults to the backend's `smtp_transcript` config.
+        pub smtp_transcript: Option<bool>,
+        /// Which signals make the email risky or invalid. Defaults to the
+        /// backend's `reachability_policy` config.
+        pub reachability_policy: Option<ReachabilityPolicy>,
+        /// Whether to verify the email even if a recent result is in the
+        /// result cache. Cached results have a `cached_at` field.
+        #[serde(default)]
+        pub bypass_cache: bool,
         // The following fields are for backward compatibility.
         pub yahoo_verif_method: Option<BackwardCompatYahooVerifMethod>,
         pub hotmailb2c_verif_method: Option<BackwardCompatHotmailB2CVerifMethod>,
@@ -102,13 +114,34 @@ impl CheckEmailRequest {
                 CheckEmailInput {
                         to_email: self.to_email.clone(),
                         verif_method,
+                        mx_fallback: config.mx_fallback.clone(),
+                        greylisting: config.greylisting.clone(),
+                        reachability_policy: self
+                                .reachability_policy
+                                .clone()
+                                .unwrap_or_else(|| config.reachability_policy.clone()),
+                        score_weights: config.score_weights.clone(),
                         proxy_rotator: config.get_proxy_rotator(),
+                        proxy_pool_rotators: config.get_proxy_pool_rotators(),
+                        dns_resolver: config.get_dns_resolver(),
+                        catch_all_cache: config.get_catch_all_cache(),
+                        smtp_transcript: self.smtp_transcript.unwrap_or(config.smtp_transcript),
+                        misc_timeout: config.misc_timeout.map(Duration::from_secs),
                         sentry_dsn: config.sentry_dsn.clone(),
                         backend_name: config.backend_name.clone(),
                         webdriver_config: config.webdriver.clone(),
                         ..Default::default()
                 }
         }
+
+        /// Look up a recent result of the email in the result cache, unless
+        /// the request bypasses it.
+        pub async fn get_cached_result(&self, config: &BackendConfig) -> Option<CachedResult> {
+                if self.bypass_cache {
+                        return None;
+                }
+                config.get_result_cache()?.get(&self.to_email).await
+        }
 }
 
 /// The main endpoint handler that implements the logic of this route.
@@ -122,11 +155,13 @@ async fn http_handler(
                         ReacherResponseError::new(http::StatusCode::BAD_REQUEST, "to_email field is required.")
                                 .into(),
                 )
+        } else if let Some(cached) = body.get_cached_result(&config).await {
+                Ok(warp::reply::json(&cached))
         } else {
                 // Run the future to check an email.
-                Ok(warp::reply::json(
-                        &check_email(&body.to_check_email_input(Arc::clone(&config))).await,
-                ))
+                let result = check_email(&body.to_check_email_input(Arc::clone(&config))).await;
+                config.get_timing_stats().record(&result.debug);
+                Ok(warp::reply::json(&result))
         }
 }
 
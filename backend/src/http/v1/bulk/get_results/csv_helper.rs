@@ -28,6 +28,9 @@ pub struct CsvWrapper(pub serde_json::Value);
 pub struct CsvResponse {
 	input: String,
 	is_reachable: String,
+	score: Option<u64>,
+	sub_status: Option<String>,
+	reason_codes: String,
 	#[serde(rename = "misc.is_disposable")]
 	misc_is_disposable: bool,
 	#[serde(rename = "misc.is_role_account")]
@@ -46,6 +49,22 @@ pub struct CsvResponse {
 	smtp_is_deliverable: bool,
 	#[serde(rename = "smtp.is_disabled")]
 	smtp_is_disabled: bool,
+	#[serde(rename = "smtp.server.banner")]
+	smtp_server_banner: Option<String>,
+	#[serde(rename = "smtp.server.software")]
+	smtp_server_software: Option<String>,
+	#[serde(rename = "smtp.server.extensions.size")]
+	smtp_server_extensions_size: Option<u64>,
+	#[serde(rename = "smtp.server.extensions.8bitmime")]
+	smtp_server_extensions_8bitmime: Option<bool>,
+	#[serde(rename = "smtp.server.extensions.smtputf8")]
+	smtp_server_extensions_smtputf8: Option<bool>,
+	#[serde(rename = "smtp.server.extensions.starttls")]
+	smtp_server_extensions_starttls: Option<bool>,
+	#[serde(rename = "smtp.server.extensions.pipelining")]
+	smtp_server_extensions_pipelining: Option<bool>,
+	#[serde(rename = "smtp.server.extensions.chunking")]
+	smtp_server_extensions_chunking: Option<bool>,
 	#[serde(rename = "syntax.is_valid_syntax")]
 	syntax_is_valid_syntax: bool,
 	#[serde(rename = "syntax.domain")]
@@ -74,6 +93,24 @@ impl TryFrom<CsvWrapper> for CsvResponse {
 			.and_then(|v| v.as_str())
 			.ok_or("is_reachable should be a string")?
 			.to_string();
+		// The score is missing from results stored before it was computed.
+		let score = top_level.get("score").and_then(|v| v.as_u64());
+		// Same for the reason codes.
+		let sub_status = top_level
+			.get("sub_status")
+			.and_then(|v| v.as_str())
+			.map(|s| s.to_string());
+		let reason_codes = top_level
+			.get("reason_codes")
+			.and_then(|v| v.as_array())
+			.map(|codes| {
+				codes
+					.iter()
+					.filter_map(|v| v.as_str())
+					.collect::<Vec<_>>()
+					.join(",")
+			})
+			.unwrap_or_default();
 
 		let misc = top_level
 			.get("misc")
@@ -126,6 +163,34 @@ impl TryFrom<CsvWrapper> for CsvResponse {
 			.and_then(|v| v.as_bool())
 			.ok_or("is_disabled should be a boolean")?;
 
+		// The server details are missing from results stored before they
+		// were collected, so they are all optional.
+		let smtp_server = smtp.get("server").and_then(|v| v.as_object());
+		let smtp_server_banner = smtp_server
+			.and_then(|s| s.get("banner"))
+			.and_then(|v| v.as_str())
+			.map(|s| s.to_string());
+		let smtp_server_software = smtp_server
+			.and_then(|s| s.get("software"))
+			.and_then(|v| v.as_str())
+			.map(|s| s.to_string());
+		let extensions = smtp_server
+			.and_then(|s| s.get("extensions"))
+			.and_then(|v| v.as_object());
+		let extension = |name: &str| {
+			extensions
+				.and_then(|e| e.get(name))
+				.and_then(|v| v.as_bool())
+		};
+		let smtp_server_extensions_size = extensions
+			.and_then(|e| e.get("size"))
+			.and_then(|v| v.as_u64());
+		let smtp_server_extensions_8bitmime = extension("8bitmime");
+		let smtp_server_extensions_smtputf8 = extension("smtputf8");
+		let smtp_server_extensions_starttls = extension("starttls");
+		let smtp_server_extensions_pipelining = extension("pipelining");
+		let smtp_server_extensions_chunking = extension("chunking");
+
 		let syntax = top_level
 			.get("syntax")
 			.and_then(|v| v.as_object())
@@ -150,6 +215,9 @@ impl TryFrom<CsvWrapper> for CsvResponse {
 		Ok(CsvResponse {
 			input,
 			is_reachable,
+			score,
+			sub_status,
+			reason_codes,
 			misc_is_disposable,
 			misc_is_role_account,
 			misc_gravatar_url,
@@ -159,6 +227,14 @@ impl TryFrom<CsvWrapper> for CsvResponse {
 			smtp_is_catch_all,
 			smtp_is_deliverable,
 			smtp_is_disabled,
+			smtp_server_banner,
+			smtp_server_software,
+			smtp_server_extensions_size,
+			smtp_server_extensions_8bitmime,
+			smtp_server_extensions_smtputf8,
+			smtp_server_extensions_starttls,
+			smtp_server_extensions_pipelining,
+			smtp_server_extensions_chunking,
 			syntax_is_valid_syntax,
 			syntax_domain,
 			syntax_username,
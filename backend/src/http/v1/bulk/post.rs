@@ -45,6 +45,10 @@ use crate::worker::do_work::TaskWebhook;
 struct Request {
 	input: Vec<String>,
 	webhook: Option<TaskWebhook>,
+	/// Whether to verify the emails even if recent results are in the result
+	/// cache.
+	#[serde(default)]
+	bypass_cache: bool,
 }
 
 /// POST v1/bulk endpoint response body.
@@ -77,6 +81,7 @@ async fn http_handler(
 
 	let n = body.input.len();
 	let webhook = body.webhook.clone();
+	let bypass_cache = body.bypass_cache;
 	let stream = futures::stream::iter(body.input.into_iter());
 
 	let properties = BasicProperties::default()
@@ -97,6 +102,8 @@ async fn http_handler(
 				input,
 				job_id: CheckEmailJobId::Bulk(rec.id),
 				webhook: webhook.clone(),
+				deferrals: 0,
+				bypass_cache,
 			};
 
 			publish_task(
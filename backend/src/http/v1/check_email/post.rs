@@ -34,7 +34,7 @@ use crate::http::v1::bulk::post::publish_task;
 use crate::http::{check_header, ReacherResponseError};
 use crate::storage::commercial_license_trial::send_to_reacher;
 use crate::worker::consume::MAX_QUEUE_PRIORITY;
-use crate::worker::do_work::{CheckEmailJobId, CheckEmailTask};
+use crate::worker::do_work::{CheckEmailJobId, CheckEmailTask, TaskOutput};
 use crate::worker::single_shot::SingleShotReply;
 
 async fn handle_without_worker(
@@ -42,15 +42,25 @@ async fn handle_without_worker(
 	body: &CheckEmailRequest,
 	throttle_manager: &crate::throttle::ThrottleManager,
 ) -> Result<Vec<u8>, warp::Rejection> {
-	info!(target: LOG_TARGET, email=body.to_email, "Starting verification");
-	let input = body.to_check_email_input(Arc::clone(&config));
-	let result = check_email(&input).await;
+	let result = match body.get_cached_result(&config).await {
+		Some(cached) => {
+			info!(target: LOG_TARGET, email=body.to_email, cached_at=?cached.cached_at, "Found result in cache");
+			TaskOutput::Cached(cached)
+		}
+		None => {
+			info!(target: LOG_TARGET, email=body.to_email, "Starting verification");
+			let input = body.to_check_email_input(Arc::clone(&config));
+			let result = check_email(&input).await;
+			config.get_timing_stats().record(&result.debug);
+
+			// Increment counters after successful verification
+			throttle_manager.increment_counters().await;
+			TaskOutput::Verified(Box::new(result))
+		}
+	};
 	let result_ok = Ok(result);
 
-	// Increment counters after successful verification
-	throttle_manager.increment_counters().await;
-
-	// Store the result regardless of how we got it
+	// Store the result, unless it came from the result cache
 	let storage = Arc::clone(&config).get_storage_adapter();
 	storage
 		.store(
@@ -58,6 +68,8 @@ async fn handle_without_worker(
 				input: body.to_check_email_input(Arc::clone(&config)),
 				job_id: CheckEmailJobId::SingleShot,
 				webhook: None,
+				deferrals: 0,
+				bypass_cache: body.bypass_cache,
 			},
 			&result_ok,
 			storage.get_extra(),
@@ -72,7 +84,7 @@ async fn handle_without_worker(
 		.map_err(ReacherResponseError::from)?;
 
 	let result = result_ok.unwrap();
-	info!(target: LOG_TARGET, email=body.to_email, is_reachable=?result.is_reachable, "Done verification");
+	info!(target: LOG_TARGET, email=body.to_email, is_reachable=?result.is_reachable(), "Done verification");
 	Ok(serde_json::to_vec(&result).map_err(ReacherResponseError::from)?)
 }
 
@@ -114,6 +126,8 @@ async fn handle_with_worker(
 			input: body.to_check_email_input(config.clone()),
 			job_id: CheckEmailJobId::SingleShot,
 			webhook: None,
+			deferrals: 0,
+			bypass_cache: body.bypass_cache,
 		},
 		properties,
 	)
@@ -16,3 +16,5 @@
 
 pub mod bulk;
 pub mod check_email;
+pub mod proxies;
+pub mod timings;
@@ -0,0 +1,56 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! This file implements the `GET /v1/proxies/health` endpoint.
+
+use std::sync::Arc;
+
+use check_if_email_exists::smtp::proxy_rotator::ProxyHealth;
+use check_if_email_exists::LOG_TARGET;
+use warp::Filter;
+
+use crate::config::BackendConfig;
+use crate::http::check_header;
+
+/// Create the `GET /v1/proxies/health` endpoint, returning the health of
+/// each proxy of the global rotation, then of each named proxy pool, as seen
+/// by this backend process. The list is empty if no proxy pool is enabled.
+pub fn v1_get_proxies_health(
+	config: Arc<BackendConfig>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+	warp::path!("v1" / "proxies" / "health")
+		.and(warp::get())
+		.and(check_header(Arc::clone(&config)))
+		.map(move || {
+			let mut health: Vec<ProxyHealth> = config
+				.get_proxy_rotator()
+				.map(|rotator| rotator.health())
+				.unwrap_or_default();
+			if let Some(rotators) = config.get_proxy_pool_rotators() {
+				let mut pools: Vec<_> = rotators.iter().collect();
+				pools.sort_by_key(|(pool, _)| *pool);
+				for (pool, rotator) in pools {
+					health.extend(rotator.health().into_iter().map(|proxy| ProxyHealth {
+						pool: Some(pool.clone()),
+						..proxy
+					}));
+				}
+			}
+			warp::reply::json(&health)
+		})
+		// View access logs by setting `RUST_LOG=reacher`.
+		.with(warp::log(LOG_TARGET))
+}
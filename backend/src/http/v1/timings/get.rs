@@ -0,0 +1,39 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! This file implements the `GET /v1/timings` endpoint.
+
+use std::sync::Arc;
+
+use check_if_email_exists::LOG_TARGET;
+use warp::Filter;
+
+use crate::config::BackendConfig;
+use crate::http::check_header;
+
+/// Create the `GET /v1/timings` endpoint, returning the percentiles of the
+/// time spent in each verification stage by this backend process, see
+/// [TimingStats](crate::timings::TimingStats).
+pub fn v1_get_timings(
+	config: Arc<BackendConfig>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+	warp::path!("v1" / "timings")
+		.and(warp::get())
+		.and(check_header(Arc::clone(&config)))
+		.map(move || warp::reply::json(&config.get_timing_stats().percentiles()))
+		// View access logs by setting `RUST_LOG=reacher`.
+		.with(warp::log(LOG_TARGET))
+}
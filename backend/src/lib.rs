@@ -14,10 +14,15 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+// The combined warp filters of all the routes are deeply nested types.
+#![recursion_limit = "256"]
+
 pub mod config;
 pub mod http;
+pub mod proxy_list;
 pub mod storage;
 pub mod throttle;
+pub mod timings;
 pub mod worker;
 
 const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
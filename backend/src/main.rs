@@ -18,7 +18,7 @@
 //! functions, depending on whether the `bulk` feature is enabled or not.
 
 use check_if_email_exists::{setup_sentry, LOG_TARGET};
-use reacher_backend::config::load_config;
+use reacher_backend::config::{load_config, spawn_proxy_list_reloader};
 use reacher_backend::http::run_warp_server;
 use reacher_backend::worker::run_worker;
 use std::sync::Arc;
@@ -45,6 +45,7 @@ async fn main() -> Result<(), anyhow::Error> {
 	}
 
 	let config = Arc::new(config);
+	spawn_proxy_list_reloader(Arc::clone(&config));
 
 	let server_future = run_warp_server(Arc::clone(&config));
 	let worker_future = async {
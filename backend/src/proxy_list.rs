@@ -0,0 +1,223 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Lists of proxies loaded from a file, a URL or a single string, for pools
+//! too large to be configured one proxy at a time in `overrides.proxies`.
+//!
+//! A list is either JSON, i.e. an array of proxies or an object of proxies
+//! by ID, or one `host:port` or `host:port:username:password` entry per line.
+//! Empty lines and lines starting with `#` are ignored. Proxies without an
+//! explicit ID get `host:port`, or `host:port:username` if they have a
+//! username, as ID, so that they keep it when the list is loaded again.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use check_if_email_exists::CheckEmailInputProxy;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Timeout of the request fetching the proxy list URL.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Error when loading a proxy list.
+#[derive(Debug, Error)]
+pub enum ProxyListError {
+	#[error("Cannot read proxy list file {0}: {1}")]
+	Io(PathBuf, std::io::Error),
+	#[error("Cannot fetch proxy list {0}: {1}")]
+	Fetch(String, reqwest::Error),
+	#[error("Invalid JSON proxy list: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("Invalid proxy entry \"{0}\", expected host:port or host:port:username:password")]
+	InvalidEntry(String),
+}
+
+/// Where to load proxies from, in addition to `overrides.proxies`. The
+/// proxies of all the sources are merged.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ProxyListConfig {
+	/// Path to a proxy list file.
+	pub file: Option<PathBuf>,
+	/// HTTP URL of a proxy list.
+	pub url: Option<String>,
+	/// Proxy list whose entries may also be separated by commas, e.g.
+	/// "host1:1080,host2:1080:username:password", handy to set the whole
+	/// list in a single environment variable.
+	pub proxies: Option<String>,
+	/// If positive, how often to load the proxy list again, in seconds. On
+	/// Unix, the list is also loaded again on SIGHUP.
+	pub reload_interval_secs: u64,
+}
+
+impl ProxyListConfig {
+	/// Whether there's any source to load proxies from.
+	pub fn is_enabled(&self) -> bool {
+		self.file.is_some() || self.url.is_some() || self.proxies.is_some()
+	}
+}
+
+/// Load the proxies of all the sources of the configuration.
+pub async fn load_proxy_list(
+	config: &ProxyListConfig,
+) -> Result<HashMap<String, CheckEmailInputProxy>, ProxyListError> {
+	let mut proxies = HashMap::new();
+	if let Some(path) = &config.file {
+		let content =
+			std::fs::read_to_string(path).map_err(|e| ProxyListError::Io(path.clone(), e))?;
+		proxies.extend(parse_proxy_list(&content, &['\n'])?);
+	}
+	if let Some(url) = &config.url {
+		let content = fetch(url)
+			.await
+			.map_err(|e| ProxyListError::Fetch(url.clone(), e))?;
+		proxies.extend(parse_proxy_list(&content, &['\n'])?);
+	}
+	if let Some(list) = &config.proxies {
+		proxies.extend(parse_proxy_list(list, &['\n', ','])?);
+	}
+
+	Ok(proxies)
+}
+
+async fn fetch(url: &str) -> Result<String, reqwest::Error> {
+	reqwest::Client::new()
+		.get(url)
+		.timeout(FETCH_TIMEOUT)
+		.send()
+		.await?
+		.error_for_status()?
+		.text()
+		.await
+}
+
+/// Parse a proxy list, see the [module documentation](self). Unless the list
+/// is JSON, its entries are split on the `separators`.
+pub fn parse_proxy_list(
+	content: &str,
+	separators: &[char],
+) -> Result<HashMap<String, CheckEmailInputProxy>, ProxyListError> {
+	let content = content.trim();
+	let proxies: Vec<CheckEmailInputProxy> = if content.starts_with('{') {
+		return Ok(serde_json::from_str(content)?);
+	} else if content.starts_with('[') {
+		serde_json::from_str(content)?
+	} else {
+		content
+			.split(separators)
+			.map(str::trim)
+			.filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+			.map(parse_entry)
+			.collect::<Result<_, _>>()?
+	};
+
+	Ok(proxies
+		.into_iter()
+		.map(|proxy| (proxy_id(&proxy), proxy))
+		.collect())
+}
+
+/// The ID of a proxy listed without one, derived from its entry: unlike its
+/// position in the list, it doesn't change when other proxies are added or
+/// removed, so its health is kept across reloads.
+fn proxy_id(proxy: &CheckEmailInputProxy) -> String {
+	match &proxy.username {
+		Some(username) => format!("{}:{}:{}", proxy.host, proxy.port, username),
+		None => format!("{}:{}", proxy.host, proxy.port),
+	}
+}
+
+/// Parse a `host:port` or `host:port:username:password` entry. The password
+/// may contain colons.
+fn parse_entry(entry: &str) -> Result<CheckEmailInputProxy, ProxyListError> {
+	let invalid = || ProxyListError::InvalidEntry(entry.to_string());
+	let parts: Vec<&str> = entry.splitn(4, ':').collect();
+	let (host, port, credentials) = match parts.as_slice() {
+		[host, port] => (host, port, None),
+		[host, port, username, password] => (host, port, Some((username, password))),
+		_ => return Err(invalid()),
+	};
+	if host.is_empty() {
+		return Err(invalid());
+	}
+
+	Ok(CheckEmailInputProxy {
+		host: host.to_string(),
+		port: port.parse().map_err(|_| invalid())?,
+		username: credentials.map(|(username, _)| username.to_string()),
+		password: credentials.map(|(_, password)| password.to_string()),
+		..Default::default()
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_parse_lines() {
+		let proxies = parse_proxy_list(
+			"# Residential\nhost1:1080\n\nhost2:1081:user:pa,ss\n",
+			&['\n'],
+		)
+		.unwrap();
+		assert_eq!(proxies.len(), 2);
+		assert_eq!(proxies["host1:1080"].host, "host1");
+		assert_eq!(proxies["host1:1080"].username, None);
+		assert_eq!(proxies["host2:1081:user"].port, 1081);
+		assert_eq!(proxies["host2:1081:user"].username.as_deref(), Some("user"));
+		assert_eq!(
+			proxies["host2:1081:user"].password.as_deref(),
+			Some("pa,ss")
+		);
+
+		assert!(parse_proxy_list("host1:1080:user", &['\n']).is_err());
+		assert!(parse_proxy_list("host1:port", &['\n']).is_err());
+	}
+
+	#[test]
+	fn should_parse_commas() {
+		let proxies = parse_proxy_list("host1:1080,host2:1081:user:pa:ss", &['\n', ',']).unwrap();
+		assert_eq!(proxies.len(), 2);
+		assert_eq!(
+			proxies["host2:1081:user"].password.as_deref(),
+			Some("pa:ss")
+		);
+	}
+
+	#[test]
+	fn should_keep_ids_across_reloads() {
+		let proxies = parse_proxy_list("host1:1080\nhost2:1081", &['\n']).unwrap();
+		let reloaded = parse_proxy_list("host0:1079\nhost2:1081", &['\n']).unwrap();
+		assert_eq!(proxies["host2:1081"], reloaded["host2:1081"]);
+	}
+
+	#[test]
+	fn should_parse_json() {
+		let proxies =
+			parse_proxy_list(r#"[{"host": "host1", "port": 1080, "weight": 2}]"#, &['\n']).unwrap();
+		assert_eq!(proxies["host1:1080"].weight, Some(2));
+
+		let proxies = parse_proxy_list(
+			r#"{"residential": {"host": "host1", "port": 1080}}"#,
+			&['\n', ','],
+		)
+		.unwrap();
+		assert_eq!(proxies["residential"].port, 1080);
+	}
+}
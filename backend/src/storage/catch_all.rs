@@ -0,0 +1,77 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use async_trait::async_trait;
+use check_if_email_exists::smtp::catch_all::CatchAllCache;
+use check_if_email_exists::LOG_TARGET;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::warn;
+
+/// [CatchAllCache] stored in the `catch_all_cache` table, so that it's
+/// shared by all the backends using the same database, and survives
+/// restarts.
+#[derive(Debug)]
+pub struct PostgresCatchAllCache {
+	pg_pool: PgPool,
+	ttl: Duration,
+}
+
+impl PostgresCatchAllCache {
+	pub fn new(pg_pool: PgPool, ttl: Duration) -> Self {
+		Self { pg_pool, ttl }
+	}
+}
+
+#[async_trait]
+impl CatchAllCache for PostgresCatchAllCache {
+	async fn get(&self, domain: &str) -> Option<bool> {
+		let result = sqlx::query_scalar::<_, bool>(
+			r#"
+			SELECT is_catch_all FROM catch_all_cache
+			WHERE domain = $1 AND probed_at > NOW() - make_interval(secs => $2)
+			"#,
+		)
+		.bind(domain)
+		.bind(self.ttl.as_secs_f64())
+		.fetch_optional(&self.pg_pool)
+		.await;
+
+		result.unwrap_or_else(|e| {
+			warn!(target: LOG_TARGET, domain=domain, error=?e, "Failed to read catch-all cache");
+			None
+		})
+	}
+
+	async fn insert(&self, domain: &str, is_catch_all: bool) {
+		let result = sqlx::query(
+			r#"
+			INSERT INTO catch_all_cache (domain, is_catch_all, probed_at)
+			VALUES ($1, $2, NOW())
+			ON CONFLICT (domain) DO UPDATE
+			SET is_catch_all = EXCLUDED.is_catch_all, probed_at = EXCLUDED.probed_at
+			"#,
+		)
+		.bind(domain)
+		.bind(is_catch_all)
+		.execute(&self.pg_pool)
+		.await;
+
+		if let Err(e) = result {
+			warn!(target: LOG_TARGET, domain=domain, error=?e, "Failed to write catch-all cache");
+		}
+	}
+}
@@ -16,19 +16,23 @@
 
 use crate::config::{BackendConfig, CommercialLicenseTrialConfig};
 use crate::http::ReacherResponseError;
-use crate::worker::do_work::TaskError;
-use check_if_email_exists::{CheckEmailOutput, LOG_TARGET};
+use crate::worker::do_work::{TaskError, TaskOutput};
+use check_if_email_exists::LOG_TARGET;
 use std::sync::Arc;
 use tracing::debug;
 use warp::http::StatusCode;
 
 /// If we're in the Commercial License Trial, we also store the
-/// result by sending it to back to Reacher.
+/// result by sending it to back to Reacher. Results found in the result cache
+/// were already sent when the email was verified.
 pub async fn send_to_reacher(
 	config: Arc<BackendConfig>,
 	email: &str,
-	worker_output: &Result<CheckEmailOutput, TaskError>,
+	worker_output: &Result<TaskOutput, TaskError>,
 ) -> Result<(), ReacherResponseError> {
+	if let Ok(TaskOutput::Cached(_)) = worker_output {
+		return Ok(());
+	}
 	if let Some(CommercialLicenseTrialConfig { api_token, url }) = &config.commercial_license_trial
 	{
 		let res = reqwest::Client::new()
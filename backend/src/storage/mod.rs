@@ -14,12 +14,13 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod catch_all;
 pub mod commercial_license_trial;
 pub mod error;
 pub mod postgres;
+pub mod result_cache;
 
-use crate::worker::do_work::{CheckEmailTask, TaskError};
-use check_if_email_exists::CheckEmailOutput;
+use crate::worker::do_work::{CheckEmailJobId, CheckEmailTask, TaskError, TaskOutput};
 use error::StorageError;
 use postgres::PostgresStorage;
 use std::fmt::Debug;
@@ -32,12 +33,20 @@ pub enum StorageAdapter {
 }
 
 impl StorageAdapter {
+	/// Store the result of the task. A result found in the result cache was
+	/// already stored when the email was verified, so it's only stored again
+	/// for bulk jobs, which need the results of all their emails.
 	pub async fn store(
 		&self,
 		task: &CheckEmailTask,
-		worker_output: &Result<CheckEmailOutput, TaskError>,
+		worker_output: &Result<TaskOutput, TaskError>,
 		extra: Option<serde_json::Value>,
 	) -> Result<(), StorageError> {
+		if let (Ok(TaskOutput::Cached(_)), CheckEmailJobId::SingleShot) =
+			(worker_output, &task.job_id)
+		{
+			return Ok(());
+		}
 		match self {
 			StorageAdapter::Postgres(storage) => storage.store(task, worker_output, extra).await,
 			StorageAdapter::Noop => Ok(()),
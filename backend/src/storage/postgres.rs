@@ -15,8 +15,9 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::error::StorageError;
-use crate::worker::do_work::{CheckEmailJobId, CheckEmailTask, TaskError};
-use check_if_email_exists::{CheckEmailOutput, LOG_TARGET};
+use super::result_cache::normalize_email;
+use crate::worker::do_work::{CheckEmailJobId, CheckEmailTask, TaskError, TaskOutput};
+use check_if_email_exists::LOG_TARGET;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use tracing::{debug, info};
@@ -45,10 +46,12 @@ impl PostgresStorage {
 	pub async fn store(
 		&self,
 		task: &CheckEmailTask,
-		worker_output: &Result<CheckEmailOutput, TaskError>,
+		worker_output: &Result<TaskOutput, TaskError>,
 		extra: Option<serde_json::Value>,
 	) -> Result<(), StorageError> {
-		let payload_json = serde_json::to_value(task)?;
+		// The result cache looks up the results by normalized email.
+		let mut payload_json = serde_json::to_value(task)?;
+		payload_json["input"]["to_email"] = normalize_email(&task.input.to_email).into();
 
 		match worker_output {
 			Ok(output) => {
@@ -0,0 +1,221 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Cache of the verification results, so that an email verified recently
+//! isn't verified again.
+
+use async_trait::async_trait;
+use check_if_email_exists::{Reachable, LOG_TARGET};
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::fmt::Debug;
+use std::time::Duration;
+use tracing::warn;
+
+/// Configuration of the result cache. A result is reused for a duration
+/// depending on its `is_reachable` field, a zero duration meaning that it's
+/// never reused.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ResultCacheConfig {
+	/// Whether to look up the recent results before verifying an email.
+	///
+	/// Defaults to false.
+	pub enabled: bool,
+	/// How long a "safe" result is reused, in seconds.
+	///
+	/// Defaults to 604800, i.e. 7 days.
+	pub safe_secs: u64,
+	/// How long a "risky" result is reused, in seconds.
+	///
+	/// Defaults to 604800, i.e. 7 days.
+	pub risky_secs: u64,
+	/// How long an "invalid" result is reused, in seconds.
+	///
+	/// Defaults to 2592000, i.e. 30 days.
+	pub invalid_secs: u64,
+	/// How long an "unknown" result is reused, in seconds.
+	///
+	/// Defaults to 0, i.e. never.
+	pub unknown_secs: u64,
+}
+
+impl Default for ResultCacheConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			safe_secs: 604800,
+			risky_secs: 604800,
+			invalid_secs: 2592000,
+			unknown_secs: 0,
+		}
+	}
+}
+
+impl ResultCacheConfig {
+	/// How long a result with the given `is_reachable` is reused.
+	pub fn freshness(&self, is_reachable: &Reachable) -> Duration {
+		Duration::from_secs(match is_reachable {
+			Reachable::Safe => self.safe_secs,
+			Reachable::Risky => self.risky_secs,
+			Reachable::Invalid => self.invalid_secs,
+			Reachable::Unknown => self.unknown_secs,
+		})
+	}
+}
+
+/// A verification result read from the cache. It serializes as the original
+/// result, with an additional `cached_at` field.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CachedResult {
+	/// JSON serialization of the original CheckEmailOutput.
+	#[serde(flatten)]
+	pub result: serde_json::Map<String, serde_json::Value>,
+	/// When the email was actually verified.
+	pub cached_at: DateTime<Utc>,
+}
+
+impl CachedResult {
+	/// Build a CachedResult from a stored result. If the stored result itself
+	/// came from the cache, its original `cached_at` is kept.
+	pub fn from_stored(result: serde_json::Value, stored_at: DateTime<Utc>) -> Option<Self> {
+		let mut result = match result {
+			serde_json::Value::Object(result) => result,
+			_ => return None,
+		};
+		let cached_at = result
+			.remove("cached_at")
+			.and_then(|cached_at| serde_json::from_value(cached_at).ok())
+			.unwrap_or(stored_at);
+
+		Some(Self { result, cached_at })
+	}
+
+	pub fn is_reachable(&self) -> Reachable {
+		self.result
+			.get("is_reachable")
+			.and_then(|is_reachable| serde_json::from_value(is_reachable.clone()).ok())
+			.unwrap_or(Reachable::Unknown)
+	}
+
+	/// Whether the result can still be reused.
+	pub fn is_fresh(&self, config: &ResultCacheConfig) -> bool {
+		let age = (Utc::now() - self.cached_at).to_std().unwrap_or_default();
+		age < config.freshness(&self.is_reachable())
+	}
+}
+
+/// The email as stored and looked up in the result cache: trimmed, and with
+/// its domain lowercased. The local part may be case-sensitive, so it's kept
+/// as is.
+pub fn normalize_email(to_email: &str) -> String {
+	let to_email = to_email.trim();
+	match to_email.rsplit_once('@') {
+		Some((local_part, domain)) => format!("{}@{}", local_part, domain.to_lowercase()),
+		None => to_email.to_string(),
+	}
+}
+
+/// Recent verification results by email. Implementations handle their own
+/// errors: a failing cache should behave as an empty one.
+#[async_trait]
+pub trait ResultCache: Debug + Send + Sync {
+	/// The most recent result of the email, if it's still fresh.
+	async fn get(&self, to_email: &str) -> Option<CachedResult>;
+}
+
+/// [ResultCache] reading the results stored in the `v1_task_result` table.
+/// The results which themselves came from the cache, e.g. those stored for
+/// bulk jobs, are ignored.
+#[derive(Debug)]
+pub struct PostgresResultCache {
+	pg_pool: PgPool,
+	config: ResultCacheConfig,
+}
+
+impl PostgresResultCache {
+	pub fn new(pg_pool: PgPool, config: ResultCacheConfig) -> Self {
+		Self { pg_pool, config }
+	}
+}
+
+#[async_trait]
+impl ResultCache for PostgresResultCache {
+	async fn get(&self, to_email: &str) -> Option<CachedResult> {
+		let row = sqlx::query_as::<_, (serde_json::Value, DateTime<Utc>)>(
+			r#"
+			SELECT result, created_at FROM v1_task_result
+			WHERE payload->'input'->>'to_email' = $1
+				AND result IS NOT NULL
+				AND result->'cached_at' IS NULL
+			ORDER BY created_at DESC
+			LIMIT 1
+			"#,
+		)
+		.bind(normalize_email(to_email))
+		.fetch_optional(&self.pg_pool)
+		.await
+		.unwrap_or_else(|e| {
+			warn!(target: LOG_TARGET, email=to_email, error=?e, "Failed to read result cache");
+			None
+		})?;
+
+		CachedResult::from_stored(row.0, row.1).filter(|cached| cached.is_fresh(&self.config))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn should_keep_results_fresh_by_reachable() {
+		let config = ResultCacheConfig::default();
+		let verified_at = Utc::now() - Duration::from_secs(10 * 86400);
+
+		let invalid =
+			CachedResult::from_stored(json!({ "is_reachable": "invalid" }), verified_at).unwrap();
+		assert!(invalid.is_fresh(&config));
+
+		let safe =
+			CachedResult::from_stored(json!({ "is_reachable": "safe" }), verified_at).unwrap();
+		assert!(!safe.is_fresh(&config));
+
+		let unknown =
+			CachedResult::from_stored(json!({ "is_reachable": "unknown" }), Utc::now()).unwrap();
+		assert!(!unknown.is_fresh(&config));
+	}
+
+	#[test]
+	fn should_only_lowercase_domain() {
+		assert_eq!(normalize_email(" Foo@Example.COM\n"), "Foo@example.com");
+		assert_eq!(normalize_email("foo"), "foo");
+	}
+
+	#[test]
+	fn should_keep_original_cached_at() {
+		let verified_at = Utc::now() - Duration::from_secs(10 * 86400);
+		let cached =
+			CachedResult::from_stored(json!({ "is_reachable": "invalid" }), verified_at).unwrap();
+
+		// Storing a cached result again must not make it fresher.
+		let stored = serde_json::to_value(&cached).unwrap();
+		let cached_again = CachedResult::from_stored(stored, Utc::now()).unwrap();
+		assert_eq!(cached_again, cached);
+	}
+}
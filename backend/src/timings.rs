@@ -0,0 +1,134 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Percentiles of the time spent in each stage of the recent email
+//! verifications, to find out which stage slows them down. The samples are
+//! kept in memory, so the statistics are per backend process.
+
+use check_if_email_exists::DebugDetails;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::iter;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of recent samples kept by stage.
+const WINDOW_SIZE: usize = 1000;
+
+/// Name of the stage covering the whole verification.
+const TOTAL_STAGE: &str = "total";
+
+/// Percentiles of the time spent in a stage, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StagePercentiles {
+	/// Number of samples the percentiles are computed on.
+	pub count: usize,
+	pub p50_ms: f64,
+	pub p90_ms: f64,
+	pub p99_ms: f64,
+}
+
+/// The most recent timings of each stage, see [WINDOW_SIZE].
+#[derive(Debug, Default)]
+pub struct TimingStats {
+	samples: Mutex<HashMap<&'static str, VecDeque<Duration>>>,
+}
+
+impl TimingStats {
+	/// Record the timings of a verification. Stages that didn't run are
+	/// skipped.
+	pub fn record(&self, debug: &DebugDetails) {
+		// The samples stay consistent even if a thread panicked while
+		// holding the lock.
+		let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+		let stages = debug.timings.stages();
+		let stages = stages
+			.iter()
+			.copied()
+			.chain(iter::once((TOTAL_STAGE, Some(debug.duration))));
+		for (stage, duration) in stages {
+			if let Some(duration) = duration {
+				let window = samples.entry(stage).or_default();
+				if window.len() == WINDOW_SIZE {
+					window.pop_front();
+				}
+				window.push_back(duration);
+			}
+		}
+	}
+
+	/// The percentiles of each stage with at least one sample.
+	pub fn percentiles(&self) -> BTreeMap<&'static str, StagePercentiles> {
+		let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+		samples
+			.iter()
+			.map(|(stage, window)| {
+				let mut sorted: Vec<Duration> = window.iter().copied().collect();
+				sorted.sort_unstable();
+				let percentiles = StagePercentiles {
+					count: sorted.len(),
+					p50_ms: percentile(&sorted, 50),
+					p90_ms: percentile(&sorted, 90),
+					p99_ms: percentile(&sorted, 99),
+				};
+				(*stage, percentiles)
+			})
+			.collect()
+	}
+}
+
+/// Nearest-rank percentile of sorted, non-empty samples, in milliseconds.
+fn percentile(sorted: &[Duration], p: usize) -> f64 {
+	let rank = (sorted.len() * p).div_ceil(100).max(1);
+	sorted[rank - 1].as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use check_if_email_exists::StageTimings;
+
+	#[test]
+	fn should_compute_percentiles_by_stage() {
+		let stats = TimingStats::default();
+		for ms in 1..=100 {
+			stats.record(&DebugDetails {
+				duration: Duration::from_millis(ms),
+				timings: StageTimings {
+					rcpt: Some(Duration::from_millis(2 * ms)),
+					..Default::default()
+				},
+				..Default::default()
+			});
+		}
+
+		let percentiles = stats.percentiles();
+		assert_eq!(
+			percentiles.keys().copied().collect::<Vec<_>>(),
+			vec!["rcpt", "total"]
+		);
+		assert_eq!(
+			percentiles["total"],
+			StagePercentiles {
+				count: 100,
+				p50_ms: 50.0,
+				p90_ms: 90.0,
+				p99_ms: 99.0,
+			}
+		);
+		assert_eq!(percentiles["rcpt"].p90_ms, 180.0);
+	}
+}
@@ -0,0 +1,90 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::consume::CHECK_EMAIL_QUEUE;
+use super::do_work::CheckEmailTask;
+use check_if_email_exists::LOG_TARGET;
+use lapin::options::{BasicPublishOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{BasicProperties, Channel};
+use std::convert::TryFrom;
+use std::time::Duration;
+use tracing::debug;
+
+/// Name of the queue holding the tasks deferred by `ttl_ms`. RabbitMQ only
+/// expires the messages at the head of a queue, so each delay has its own
+/// queue, in which all messages expire in order.
+fn delay_queue_name(ttl_ms: u32) -> String {
+	format!("{}.delay.{}ms", CHECK_EMAIL_QUEUE, ttl_ms)
+}
+
+/// Publish a task to be consumed again from the "check_email" queue after
+/// `delay`, e.g. when the SMTP server greylisted us. The task waits in a
+/// delay queue without consumers, from which RabbitMQ dead-letters it back
+/// to the "check_email" queue once it expires.
+pub async fn publish_deferred_task(
+	channel: &Channel,
+	task: &CheckEmailTask,
+	delay: Duration,
+	properties: BasicProperties,
+) -> Result<(), anyhow::Error> {
+	let ttl_ms = u32::try_from(delay.as_millis()).unwrap_or(u32::MAX);
+	let queue = delay_queue_name(ttl_ms);
+
+	let mut queue_args = FieldTable::default();
+	queue_args.insert("x-message-ttl".into(), AMQPValue::LongUInt(ttl_ms));
+	queue_args.insert(
+		"x-dead-letter-exchange".into(),
+		AMQPValue::LongString("".into()),
+	);
+	queue_args.insert(
+		"x-dead-letter-routing-key".into(),
+		AMQPValue::LongString(CHECK_EMAIL_QUEUE.into()),
+	);
+	// Delete the queue once it's unused, i.e. not declared again, for long
+	// enough for its last message to have expired.
+	queue_args.insert(
+		"x-expires".into(),
+		AMQPValue::LongUInt(ttl_ms.saturating_mul(2).saturating_add(60_000)),
+	);
+
+	channel
+		.queue_declare(
+			&queue,
+			QueueDeclareOptions {
+				durable: true,
+				..Default::default()
+			},
+			queue_args,
+		)
+		.await?;
+
+	let task_json = serde_json::to_vec(task)?;
+	channel
+		.basic_publish(
+			"",
+			&queue,
+			BasicPublishOptions::default(),
+			&task_json,
+			properties,
+		)
+		.await?
+		.await?;
+
+	debug!(target: LOG_TARGET, email=?task.input.to_email, queue=?queue, "Published deferred task");
+
+	Ok(())
+}
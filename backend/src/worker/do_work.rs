@@ -16,7 +16,9 @@
 
 use crate::config::BackendConfig;
 use crate::storage::commercial_license_trial::send_to_reacher;
+use crate::storage::result_cache::CachedResult;
 use crate::throttle::ThrottleResult;
+use crate::worker::defer::publish_deferred_task;
 use crate::worker::single_shot::send_single_shot_reply;
 use check_if_email_exists::{
         check_email, CheckEmailInput, CheckEmailOutput, Reachable, LOG_TARGET,
@@ -33,14 +35,22 @@ use thiserror::Error;
 use tracing::{debug, info};
 use warp::http::StatusCode;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CheckEmailTask {
         pub input: CheckEmailInput,
         pub job_id: CheckEmailJobId,
         pub webhook: Option<TaskWebhook>,
+        /// Number of times the task was deferred because the SMTP server
+        /// greylisted us, see `GreylistingConfig.max_deferrals`.
+        #[serde(default)]
+        pub deferrals: usize,
+        /// Whether to verify the email even if a recent result is in the
+        /// result cache.
+        #[serde(default)]
+        pub bypass_cache: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum CheckEmailJobId {
         /// Single-shot email verification, they won't have an actual job id.
@@ -90,6 +100,26 @@ impl From<reqwest::Error> for TaskError {
         }
 }
 
+/// The output of a task.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum TaskOutput {
+        /// The email was just verified.
+        Verified(Box<CheckEmailOutput>),
+        /// The email was verified recently, and its result was found in the
+        /// result cache.
+        Cached(CachedResult),
+}
+
+impl TaskOutput {
+        pub fn is_reachable(&self) -> Reachable {
+                match self {
+                        Self::Verified(output) => output.is_reachable.clone(),
+                        Self::Cached(cached) => cached.is_reachable(),
+                }
+        }
+}
+
 impl Serialize for TaskError {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
@@ -113,7 +143,7 @@ pub struct Webhook {
 
 #[derive(Debug, Serialize)]
 struct WebhookOutput<'a> {
-        result: &'a CheckEmailOutput,
+        result: &'a TaskOutput,
         extra: &'a Option<serde_json::Value>,
 }
 
@@ -124,10 +154,35 @@ pub(crate) async fn do_check_email_work(
         channel: Arc<Channel>,
         config: Arc<BackendConfig>,
 ) -> Result<(), anyhow::Error> {
-        let worker_output = check_email_and_send_result(task, config.clone()).await;
+        let output = match cached_result(task, &config).await {
+                Some(cached) => TaskOutput::Cached(cached),
+                None => check_email_task(task, config.clone()).await,
+        };
+
+        if let TaskOutput::Verified(output) = &output {
+                if should_defer(task, output) {
+                        // Don't block the worker while waiting for the greylisting to
+                        // expire: publish the task again, to be consumed after the delay.
+                        let delay = task
+                                .input
+                                .greylisting
+                                .retry_delay(task.deferrals + output.debug.smtp.retries.waits.len());
+                        let deferred_task = CheckEmailTask {
+                                deferrals: task.deferrals + 1,
+                                ..task.clone()
+                        };
+                        publish_deferred_task(&channel, &deferred_task, delay, delivery.properties.clone())
+                                .await?;
+                        delivery.ack(BasicAckOptions::default()).await?;
+                        info!(target: LOG_TARGET, email=task.input.to_email, job_id=?task.job_id, delay=?delay, deferrals=deferred_task.deferrals, "Deferred greylisted task");
+                        return Ok(());
+                }
+        }
+
+        let worker_output = send_result(task, output).await;
 
         match (&worker_output, delivery.redelivered) {
-                (Ok(output), false) if output.is_reachable == Reachable::Unknown => {
+                (Ok(output), false) if output.is_reachable() == Reachable::Unknown => {
                         // If is_reachable is unknown, then we requeue the message, but only once.
                         // We might want to add a requeue counter in the future, see:
                         // https://stackoverflow.com/questions/25226080/rabbitmq-how-to-requeue-message-with-counter
@@ -146,7 +201,7 @@ pub(crate) async fn do_check_email_work(
                 _ => {
                         // This is the happy path. We acknowledge the message and:
                         // - If it's a single-shot email verification, we send a reply to the client.
-                        // - In any case, we store the result.
+                        // - We store the result, see StorageAdapter::store.
                         delivery.ack(BasicAckOptions::default()).await?;
 
                         if let CheckEmailJobId::SingleShot = task.job_id {
@@ -165,7 +220,7 @@ pub(crate) async fn do_check_email_work(
 
                         info!(target: LOG_TARGET,
                                 email=task.input.to_email,
-                                worker_output=?worker_output.map(|o| o.is_reachable),
+                                worker_output=?worker_output.map(|o| o.is_reachable()),
                                 job_id=?task.job_id,
                                 "Done check",
                         );
@@ -175,15 +230,61 @@ pub(crate) async fn do_check_email_work(
         Ok(())
 }
 
+/// Whether the task should be deferred instead of storing its result,
+/// because the SMTP server greylisted us. Single-shot tasks are never
+/// deferred, as the client is waiting for the result.
+fn should_defer(task: &CheckEmailTask, output: &CheckEmailOutput) -> bool {
+        matches!(task.job_id, CheckEmailJobId::Bulk(_))
+                && output.debug.smtp.retries.greylisted
+                && task.deferrals < task.input.greylisting.max_deferrals
+}
+
 /// Checks the email and sends the result to the webhook.
 pub async fn check_email_and_send_result(
         task: &CheckEmailTask,
         config: Arc<BackendConfig>,
-) -> Result<CheckEmailOutput, TaskError> {
+) -> Result<TaskOutput, TaskError> {
+        let output = match cached_result(task, &config).await {
+                Some(cached) => TaskOutput::Cached(cached),
+                None => check_email_task(task, config).await,
+        };
+        send_result(task, output).await
+}
+
+/// Looks up a recent result of the email of the task in the result cache,
+/// unless the task bypasses it.
+async fn cached_result(task: &CheckEmailTask, config: &BackendConfig) -> Option<CachedResult> {
+        if task.bypass_cache {
+                return None;
+        }
+        let cached = config.get_result_cache()?.get(&task.input.to_email).await?;
+        debug!(target: LOG_TARGET, email=task.input.to_email, cached_at=?cached.cached_at, "Found result in cache");
+        Some(cached)
+}
+
+/// Checks the email of the task, using the shared resources of the backend.
+async fn check_email_task(task: &CheckEmailTask, config: Arc<BackendConfig>) -> TaskOutput {
         let mut input = task.input.clone();
         input.proxy_rotator = config.get_proxy_rotator();
-        let output = check_email(&input).await;
+        input.proxy_pool_rotators = config.get_proxy_pool_rotators();
+        input.dns_resolver = config.get_dns_resolver();
+        input.catch_all_cache = config.get_catch_all_cache();
+        // Bulk jobs usually verify many emails at the same domains, so they
+        // reuse the SMTP sessions. Single-shot verifications stay isolated.
+        if let CheckEmailJobId::Bulk(_) = task.job_id {
+                input.smtp_session_pool = config.get_smtp_session_pool();
+        }
+        let mut output = check_email(&input).await;
+        config.get_timing_stats().record(&output.debug);
+        output.debug.smtp.retries.deferrals = task.deferrals;
+        TaskOutput::Verified(Box::new(output))
+}
 
+/// Sends the result of the task to its webhook, if any.
+async fn send_result(
+        task: &CheckEmailTask,
+        output: TaskOutput,
+) -> Result<TaskOutput, TaskError> {
         // Check if we have a webhook to send the output to.
         if let Some(TaskWebhook {
                 on_each_email: Some(webhook),
@@ -205,7 +306,7 @@ pub async fn check_email_and_send_result(
                         .await?
                         .text()
                         .await?;
-                debug!(target: LOG_TARGET, email=?webhook_output.result.input,res=?res, "Received webhook response");
+                debug!(target: LOG_TARGET, email=?task.input.to_email,res=?res, "Received webhook response");
         }
 
         Ok(output)
@@ -18,9 +18,11 @@
 // is defined as:
 // - consume from RabbitMQ
 // - do the work (i.e. check the email)
+// - if the SMTP server greylisted us, defer the task to a delay queue
 // - send response (either to the reply_to queue or save to the database)
 
 pub mod consume;
+pub mod defer;
 pub mod do_work;
 pub mod single_shot;
 
@@ -14,9 +14,9 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use super::do_work::TaskError;
+use super::do_work::{TaskError, TaskOutput};
 use anyhow::bail;
-use check_if_email_exists::{CheckEmailOutput, LOG_TARGET};
+use check_if_email_exists::LOG_TARGET;
 use lapin::message::Delivery;
 use lapin::options::BasicPublishOptions;
 use lapin::{BasicProperties, Channel};
@@ -27,12 +27,12 @@ use tracing::debug;
 use warp::http::StatusCode;
 
 /// For single-shot email verifications, the worker will send a reply to the
-/// client with the result of the verification. Since both CheckEmailOutput and
+/// client with the result of the verification. Since both TaskOutput and
 /// TaskError are not Deserialize, we need to create a new struct that can be
 /// serialized and deserialized.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum SingleShotReply {
-	/// JSON serialization of CheckEmailOutput
+	/// JSON serialization of TaskOutput
 	Ok(Vec<u8>),
 	/// String representation of TaskError with its status code.
 	/// Unfortunately, we cannot use StatusCode directly, as it is not
@@ -40,10 +40,10 @@ pub enum SingleShotReply {
 	Err((String, u16)),
 }
 
-impl TryFrom<&Result<CheckEmailOutput, TaskError>> for SingleShotReply {
+impl TryFrom<&Result<TaskOutput, TaskError>> for SingleShotReply {
 	type Error = serde_json::Error;
 
-	fn try_from(result: &Result<CheckEmailOutput, TaskError>) -> Result<Self, Self::Error> {
+	fn try_from(result: &Result<TaskOutput, TaskError>) -> Result<Self, Self::Error> {
 		match result {
 			Ok(output) => Ok(Self::Ok(serde_json::to_vec(output)?)),
 			Err(TaskError::Throttle(e)) => Ok(Self::Err((
@@ -67,7 +67,7 @@ impl TryFrom<&Result<CheckEmailOutput, TaskError>> for SingleShotReply {
 pub async fn send_single_shot_reply(
 	channel: Arc<Channel>,
 	delivery: &Delivery,
-	worker_output: &Result<CheckEmailOutput, TaskError>,
+	worker_output: &Result<TaskOutput, TaskError>,
 ) -> Result<(), anyhow::Error> {
 	if let (Some(reply_to), Some(correlation_id)) = (
 		delivery.properties.reply_to(),
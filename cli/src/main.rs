@@ -15,10 +15,15 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use check_if_email_exists::{
-	check_email, smtp::verif_method::VerifMethod, CheckEmailInputBuilder, CheckEmailInputProxy,
+	check_email,
+	mx::resolver::{build_resolver, DnsConfig, DnsProtocol},
+	smtp::verif_method::VerifMethod,
+	CheckEmailInputBuilder, CheckEmailInputProxy,
 };
 use clap::Parser;
 use once_cell::sync::Lazy;
+use std::net::IpAddr;
+use std::sync::Arc;
 
 /// CLI options of this binary.
 #[derive(Parser, Debug)]
@@ -63,10 +68,56 @@ pub struct Cli {
 	#[clap(long, env, parse(try_from_str))]
 	pub haveibeenpwned_api_key: Option<String>,
 
+	/// Comma-separated IP addresses of the nameservers to use for the MX
+	/// lookup. If not set, the system's nameservers are used.
+	#[clap(long, env, use_value_delimiter = true)]
+	pub dns_nameservers: Vec<IpAddr>,
+
+	/// Protocol to reach the nameservers: "udp", "tls" or "https".
+	#[clap(long, env, default_value = "udp", parse(try_from_str = parse_dns_protocol))]
+	pub dns_protocol: DnsProtocol,
+
+	/// Name to validate the nameservers' TLS certificate against. Required
+	/// when `--dns-protocol` is "tls" or "https".
+	#[clap(long, env)]
+	pub dns_tls_name: Option<String>,
+
+	/// Timeout of each DNS request, in milliseconds.
+	#[clap(long, env, default_value = "5000")]
+	pub dns_timeout_ms: u64,
+
+	/// Number of attempts before giving up on a DNS request.
+	#[clap(long, env, default_value = "2")]
+	pub dns_attempts: usize,
+
+	/// Maximum number of DNS answers kept in the cache. Set to 0 to disable
+	/// the cache.
+	#[clap(long, env, default_value = "1024")]
+	pub dns_cache_size: usize,
+
+	/// Minimum time to keep a DNS answer in the cache, in seconds, overriding
+	/// its TTL if lower.
+	#[clap(long, env)]
+	pub dns_min_ttl_secs: Option<u64>,
+
+	/// Maximum time to keep a DNS answer in the cache, in seconds, overriding
+	/// its TTL if higher.
+	#[clap(long, env)]
+	pub dns_max_ttl_secs: Option<u64>,
+
 	/// The email to check.
 	pub to_email: String,
 }
 
+fn parse_dns_protocol(s: &str) -> Result<DnsProtocol, String> {
+	match s {
+		"udp" => Ok(DnsProtocol::Udp),
+		"tls" => Ok(DnsProtocol::Tls),
+		"https" => Ok(DnsProtocol::Https),
+		_ => Err(format!("unknown DNS protocol: {s}")),
+	}
+}
+
 /// Global config of this application.
 pub(crate) static CONF: Lazy<Cli> = Lazy::new(Cli::parse);
 
@@ -85,6 +136,7 @@ async fn main() -> Result<(), anyhow::Error> {
 			username: CONF.proxy_username.clone(),
 			password: CONF.proxy_password.clone(),
 			timeout_ms: None,
+			weight: None,
 		});
 	let verif_method = VerifMethod::new_with_same_config_for_all(
 		proxy,
@@ -95,10 +147,23 @@ async fn main() -> Result<(), anyhow::Error> {
 		1,
 	);
 
+	let dns_resolver = build_resolver(&DnsConfig {
+		nameservers: CONF.dns_nameservers.clone(),
+		protocol: CONF.dns_protocol,
+		tls_dns_name: CONF.dns_tls_name.clone(),
+		timeout_ms: CONF.dns_timeout_ms,
+		attempts: CONF.dns_attempts,
+		cache_size: CONF.dns_cache_size,
+		min_ttl_secs: CONF.dns_min_ttl_secs,
+		max_ttl_secs: CONF.dns_max_ttl_secs,
+		..Default::default()
+	})?;
+
 	let mut input = CheckEmailInputBuilder::default();
 	let input = input
 		.to_email(to_email.clone())
 		.verif_method(verif_method)
+		.dns_resolver(Some(Arc::new(dns_resolver)))
 		.check_gravatar(CONF.check_gravatar)
 		.haveibeenpwned_api_key(CONF.haveibeenpwned_api_key.clone())
 		.backend_name("reacher-cli".to_string())
@@ -0,0 +1,279 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Verification of many emails at once, see [check_emails].
+
+use crate::smtp::catch_all::{CatchAllCache, InMemoryCatchAllCache};
+use crate::{
+	check_email_with_shared_domain, CheckEmailInput, CheckEmailOutput, SharedDomainChecks,
+	LOG_TARGET,
+};
+use futures::future::BoxFuture;
+use futures::stream::{BoxStream, FuturesUnordered, Stream, StreamExt};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+/// Concurrency limits of [check_emails].
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct CheckEmailsConfig {
+	/// Maximum number of verifications running at the same time.
+	///
+	/// Defaults to 10.
+	pub concurrency: usize,
+	/// Maximum number of verifications running at the same time at a single
+	/// domain. The first verification at a domain always runs alone, so that
+	/// the next ones reuse its MX lookup and catch-all probe.
+	///
+	/// Defaults to 2.
+	pub per_domain_concurrency: usize,
+	/// Maximum number of emails read from the input stream, but not being
+	/// verified yet. Reading the input stream is paused while it's reached.
+	///
+	/// Defaults to 1000.
+	pub max_pending: usize,
+}
+
+impl Default for CheckEmailsConfig {
+	fn default() -> Self {
+		Self {
+			concurrency: 10,
+			per_domain_concurrency: 2,
+			max_pending: 1000,
+		}
+	}
+}
+
+/// The verifications at one domain.
+#[derive(Default)]
+struct DomainQueue {
+	pending: VecDeque<CheckEmailInput>,
+	running: usize,
+	/// Whether a verification at this domain completed, so that its MX
+	/// lookup, misc checks and catch-all probe can be reused.
+	warmed_up: bool,
+	shared: Arc<SharedDomainChecks>,
+}
+
+/// Schedules the verifications of [check_emails], domain by domain.
+struct Scheduler {
+	inputs: BoxStream<'static, CheckEmailInput>,
+	inputs_done: bool,
+	config: CheckEmailsConfig,
+	/// The emails already read from the input stream.
+	seen: HashSet<String>,
+	domains: HashMap<String, DomainQueue>,
+	/// Number of emails waiting in the domain queues.
+	pending: usize,
+	running: FuturesUnordered<BoxFuture<'static, (String, CheckEmailOutput)>>,
+	catch_all_cache: Arc<dyn CatchAllCache>,
+}
+
+/// The normalized email and domain of an input, used to deduplicate and
+/// group the verifications. Only the domain is case-insensitive, the local
+/// part may not be.
+fn email_and_domain(input: &CheckEmailInput) -> (String, String) {
+	let email = input.to_email.trim();
+	match email.rsplit_once('@') {
+		Some((local_part, domain)) => {
+			let domain = domain.to_lowercase();
+			(format!("{}@{}", local_part, domain), domain)
+		}
+		None => (email.to_string(), String::new()),
+	}
+}
+
+impl Scheduler {
+	fn new<S>(inputs: S, config: CheckEmailsConfig) -> Self
+	where
+		S: Stream<Item = CheckEmailInput> + Send + 'static,
+	{
+		Self {
+			inputs: inputs.boxed(),
+			inputs_done: false,
+			config,
+			seen: HashSet::new(),
+			domains: HashMap::new(),
+			pending: 0,
+			running: FuturesUnordered::new(),
+			catch_all_cache: Arc::new(InMemoryCatchAllCache::default()),
+		}
+	}
+
+	/// Queue an input read from the input stream, unless its email was
+	/// already seen.
+	fn push(&mut self, mut input: CheckEmailInput) {
+		let (email, domain) = email_and_domain(&input);
+		if !self.seen.insert(email) {
+			tracing::debug!(
+				target: LOG_TARGET,
+				email=input.to_email,
+				"Skipping duplicate email"
+			);
+			return;
+		}
+
+		if input.catch_all_cache.is_none() {
+			input.catch_all_cache = Some(Arc::clone(&self.catch_all_cache));
+		}
+		self.domains
+			.entry(domain)
+			.or_default()
+			.pending
+			.push_back(input);
+		self.pending += 1;
+	}
+
+	/// Start the queued verifications allowed by the concurrency limits.
+	fn dispatch(&mut self) {
+		let concurrency = self.config.concurrency.max(1);
+		let per_domain_concurrency = self.config.per_domain_concurrency.max(1);
+
+		for (domain, queue) in self.domains.iter_mut() {
+			let limit = if queue.warmed_up {
+				per_domain_concurrency
+			} else {
+				1
+			};
+			while self.running.len() < concurrency && queue.running < limit {
+				let input = match queue.pending.pop_front() {
+					Some(input) => input,
+					None => break,
+				};
+				queue.running += 1;
+				self.pending -= 1;
+
+				let domain = domain.clone();
+				let shared = Arc::clone(&queue.shared);
+				self.running.push(
+					async move {
+						let output = check_email_with_shared_domain(&input, Some(&shared)).await;
+						(domain, output)
+					}
+					.boxed(),
+				);
+			}
+		}
+	}
+
+	/// Return the next completed verification, or None once all the inputs
+	/// are verified.
+	async fn next(&mut self) -> Option<CheckEmailOutput> {
+		loop {
+			self.dispatch();
+			let can_read = !self.inputs_done && self.pending < self.config.max_pending.max(1);
+
+			tokio::select! {
+				biased;
+				Some((domain, output)) = self.running.next(), if !self.running.is_empty() => {
+					if let Some(queue) = self.domains.get_mut(&domain) {
+						queue.running -= 1;
+						queue.warmed_up = true;
+					}
+					return Some(output);
+				}
+				input = self.inputs.next(), if can_read => match input {
+					Some(input) => self.push(input),
+					None => self.inputs_done = true,
+				},
+				else => return None,
+			}
+		}
+	}
+}
+
+/// Verify a stream of emails, and yield their outputs as the verifications
+/// complete, i.e. not necessarily in the input order.
+///
+/// Compared to calling [check_email](crate::check_email) on each email:
+/// - duplicate emails (ignoring the case of the domain) are only verified,
+///   and yielded, once,
+/// - the verifications are grouped by domain: the MX lookup, the misc checks
+///   which only depend on the domain, and the catch-all probe of a domain are
+///   made by its first verification, and reused by the next ones,
+/// - the number of verifications running at the same time, overall and per
+///   domain, is bounded, see [CheckEmailsConfig].
+///
+/// The emails and domains seen are remembered until the stream ends.
+pub fn check_emails<S>(
+	inputs: S,
+	config: CheckEmailsConfig,
+) -> impl Stream<Item = CheckEmailOutput> + Send
+where
+	S: Stream<Item = CheckEmailInput> + Send + 'static,
+{
+	futures::stream::unfold(Scheduler::new(inputs, config), |mut scheduler| async move {
+		scheduler.next().await.map(|output| (output, scheduler))
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::Reachable;
+
+	fn input(to_email: &str) -> CheckEmailInput {
+		CheckEmailInput {
+			to_email: to_email.to_string(),
+			..Default::default()
+		}
+	}
+
+	#[tokio::test]
+	async fn should_deduplicate_emails() {
+		let inputs = vec![input("foo"), input(" foo"), input("bar")];
+		let outputs: Vec<CheckEmailOutput> =
+			check_emails(futures::stream::iter(inputs), CheckEmailsConfig::default())
+				.collect()
+				.await;
+
+		assert_eq!(outputs.len(), 2);
+		assert!(outputs.iter().all(|o| o.is_reachable == Reachable::Invalid));
+	}
+
+	#[test]
+	fn should_only_ignore_case_of_domain() {
+		assert_eq!(
+			email_and_domain(&input(" Foo@Example.COM ")),
+			("Foo@example.com".to_string(), "example.com".to_string())
+		);
+		assert_eq!(
+			email_and_domain(&input("foo")),
+			("foo".to_string(), String::new())
+		);
+	}
+
+	#[test]
+	fn should_run_first_verification_of_domain_alone() {
+		let mut scheduler = Scheduler::new(futures::stream::empty(), CheckEmailsConfig::default());
+		for email in ["a@example.com", "b@example.com", "c@example.org"] {
+			scheduler.push(input(email));
+		}
+
+		// The verifications aren't polled, so nothing is actually verified.
+		scheduler.dispatch();
+		assert_eq!(scheduler.running.len(), 2);
+		assert_eq!(scheduler.pending, 1);
+
+		scheduler.domains.get_mut("example.com").unwrap().running = 0;
+		scheduler.domains.get_mut("example.com").unwrap().warmed_up = true;
+		scheduler.dispatch();
+		assert_eq!(scheduler.running.len(), 3);
+		assert_eq!(scheduler.pending, 0);
+	}
+}
@@ -51,6 +51,7 @@
 //!         username: None,                              // You can also set it non-empty
 //!         password: None,
 //!         timeout_ms: None,
+//!         weight: None,
 //!     });
 //!     let verif_method = VerifMethod {
 //!         proxies,
@@ -79,21 +80,27 @@
 //! }
 //! ```
 
+mod batch;
 mod haveibeenpwned;
 pub mod misc;
 pub mod mx;
-mod rules;
+pub mod reachability;
+pub mod rules;
+pub mod score;
 pub mod smtp;
 pub mod syntax;
 mod util;
 
-use misc::{check_misc, MiscDetails};
-use mx::check_mx;
+pub use batch::{check_emails, CheckEmailsConfig};
+use misc::{check_misc_with_domain, DomainMiscDetails, MiscDetails};
+use mx::{check_mx, MxDetails, MxError, MxKind};
+use reachability::{ReachabilityPolicy, ReasonCode};
 use rustls::crypto::ring;
-use smtp::{check_smtp, SmtpDetails, SmtpError};
+use score::calculate_score;
+use smtp::{check_smtp_with_mx_fallback, SmtpDetails, SmtpError};
 pub use smtp::{is_gmail, is_hotmail, is_hotmail_b2b, is_hotmail_b2c, is_yahoo};
 use std::sync::Once;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use syntax::{check_syntax, get_similar_mail_provider};
 pub use util::input_output::*;
 #[cfg(feature = "sentry")]
@@ -115,57 +122,23 @@ pub fn initialize_crypto_provider() {
 /// Given an email's misc and smtp details, calculate an estimate of our
 /// confidence on how reachable the email is, along with a human-readable reason.
 ///
-/// Returns a tuple of (Reachable, String) where the String is the reason.
+/// Returns a tuple of (Reachable, String, Vec<ReasonCode>) where the String
+/// is the reason, and the codes its machine-readable counterpart.
 ///
 /// Maybe we can switch to a points-based system?
 /// ref: https://github.com/reacherhq/check-if-email-exists/issues/935
 fn calculate_reachable_with_reason(
         misc: &MiscDetails,
         smtp: &Result<SmtpDetails, SmtpError>,
-) -> (Reachable, String) {
-        if let Ok(smtp_details) = smtp {
-                let mut risky_reasons: Vec<&str> = Vec::new();
-
-                if misc.is_disposable {
-                        risky_reasons.push("disposable email address");
-                }
-                if misc.is_role_account {
-                        risky_reasons.push("role-based account (e.g., admin@, support@)");
-                }
-                if smtp_details.is_catch_all {
-                        risky_reasons.push("catch-all address (accepts all emails)");
-                }
-                if smtp_details.has_full_inbox {
-                        risky_reasons.push("inbox is full");
-                }
-
-                if !risky_reasons.is_empty() {
-                        let reason = format!("Risky: {}", risky_reasons.join(", "));
-                        return (Reachable::Risky, reason);
-                }
-
-                let mut invalid_reasons: Vec<&str> = Vec::new();
-
-                if !smtp_details.can_connect_smtp {
-                        invalid_reasons.push("cannot connect to SMTP server");
-                }
-                if smtp_details.is_disabled {
-                        invalid_reasons.push("email account is disabled");
-                }
-                if !smtp_details.is_deliverable {
-                        invalid_reasons.push("email is not deliverable");
-                }
-
-                if !invalid_reasons.is_empty() {
-                        let reason = format!("Invalid: {}", invalid_reasons.join(", "));
-                        return (Reachable::Invalid, reason);
-                }
-
-                (Reachable::Safe, "Email verification passed all checks".to_string())
-        } else {
-                let smtp_error = smtp.as_ref().err().unwrap();
-                let reason = format_smtp_error_reason(smtp_error);
-                (Reachable::Unknown, reason)
+        policy: &ReachabilityPolicy,
+) -> (Reachable, String, Vec<ReasonCode>) {
+        match smtp {
+                Ok(smtp_details) => policy.evaluate(misc, smtp_details),
+                Err(smtp_error) => (
+                        Reachable::Unknown,
+                        format_smtp_error_reason(smtp_error),
+                        vec![ReasonCode::from(smtp_error)],
+                ),
         }
 }
 
@@ -191,6 +164,12 @@ fn format_smtp_error_reason(error: &SmtpError) -> String {
                                 format!("Unknown: SOCKS5 proxy connection failed - {}", error)
                         }
                 }
+                SmtpError::TlsError(e) => format!("Unknown: TLS negotiation failed - {}", e),
+                SmtpError::EaiUnsupported => {
+                        "Unknown: SMTP server does not support internationalized email addresses (SMTPUTF8)"
+                                .to_string()
+                }
+                SmtpError::SkippedByRule => "Unknown: SMTP verification skipped by rule".to_string(),
                 SmtpError::AnyhowError(e) => format!("Unknown: Unexpected error - {}", e),
         }
 }
@@ -204,8 +183,37 @@ fn format_smtp_error_reason(error: &SmtpError) -> String {
 /// - misc checks: metadata about the email provider.
 ///
 /// Returns a `CheckEmailOutput` output, whose `is_reachable` field is one of
-/// `Safe`, `Invalid`, `Risky` or `Unknown`.
+/// `Safe`, `Invalid`, `Risky` or `Unknown`, and whose `score` field is a finer
+/// estimate between 0 and 100, see [score].
 pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
+        check_email_with_shared_domain(input, None).await
+}
+
+/// The results of the checks which only depend on the domain, shared by the
+/// verifications at the same domain.
+#[derive(Default)]
+pub(crate) struct SharedDomainChecks {
+        mx: tokio::sync::OnceCell<Result<MxDetails, MxError>>,
+        misc: once_cell::sync::OnceCell<DomainMiscDetails>,
+}
+
+/// Same as [check_email], but the MX lookup and the misc checks of the
+/// domain are only made once across all the verifications sharing `shared`,
+/// which must be at the same domain.
+pub(crate) async fn check_email_with_shared_domain(
+        input: &CheckEmailInput,
+        shared: Option<&SharedDomainChecks>,
+) -> CheckEmailOutput {
+        let mut output = verify_email(input, shared).await;
+        output.score = calculate_score(&output, &input.score_weights);
+        output
+}
+
+/// Run the verifications of [check_email], without the score.
+async fn verify_email(
+        input: &CheckEmailInput,
+        shared: Option<&SharedDomainChecks>,
+) -> CheckEmailOutput {
         initialize_crypto_provider();
         let start_time = SystemTime::now();
         let to_email = &input.to_email;
@@ -215,13 +223,21 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
                 email=%to_email,
                 "Checking email"
         );
+        let mut timings = StageTimings::default();
+        let stage_start = Instant::now();
         let mut my_syntax = check_syntax(to_email.as_ref());
+        StageTimings::record(&mut timings.syntax, stage_start);
         if !my_syntax.is_valid_syntax {
                 return CheckEmailOutput {
                         input: to_email.to_string(),
                         is_reachable: Reachable::Invalid,
                         reason: "Invalid: email syntax is invalid".to_string(),
+                        reason_codes: vec![ReasonCode::InvalidSyntax],
                         syntax: my_syntax,
+                        debug: DebugDetails {
+                                timings,
+                                ..Default::default()
+                        },
                         ..Default::default()
                 };
         }
@@ -233,7 +249,17 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
                 "Found syntax validation"
         );
 
-        let my_mx = match check_mx(&my_syntax).await {
+        let stage_start = Instant::now();
+        let my_mx = match shared {
+                Some(shared) => shared
+                        .mx
+                        .get_or_init(|| check_mx(&my_syntax, input.dns_resolver.as_deref()))
+                        .await
+                        .clone(),
+                None => check_mx(&my_syntax, input.dns_resolver.as_deref()).await,
+        };
+        StageTimings::record(&mut timings.mx_lookup, stage_start);
+        let my_mx = match my_mx {
                 Ok(m) => m,
                 e => {
                         get_similar_mail_provider(&mut my_syntax);
@@ -250,34 +276,48 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
                                 input: to_email.to_string(),
                                 is_reachable: Reachable::Unknown,
                                 reason,
+                                reason_codes: vec![ReasonCode::MxLookupFailed],
                                 mx: e,
                                 syntax: my_syntax,
+                                debug: DebugDetails {
+                                        timings,
+                                        ..Default::default()
+                                },
                                 ..Default::default()
                         };
                 }
         };
 
-        // Return if we didn't find any MX records.
-        if my_mx.lookup.is_err() {
+        // Return if the domain doesn't accept emails.
+        if !my_mx.accepts_mail() {
                 get_similar_mail_provider(&mut my_syntax);
 
+                let (reason, reason_code) = match my_mx.kind {
+                        MxKind::NullMx => (
+                                "Invalid: domain does not accept email (null MX record)",
+                                ReasonCode::NullMx,
+                        ),
+                        _ => ("Invalid: no MX records found for domain", ReasonCode::NoMx),
+                };
                 return CheckEmailOutput {
                         input: to_email.to_string(),
                         is_reachable: Reachable::Invalid,
-                        reason: "Invalid: no MX records found for domain".to_string(),
+                        reason: reason.to_string(),
+                        reason_codes: vec![reason_code],
                         mx: Ok(my_mx),
                         syntax: my_syntax,
+                        debug: DebugDetails {
+                                timings,
+                                ..Default::default()
+                        },
                         ..Default::default()
                 };
         }
 
-        let mx_hosts: Vec<String> = my_mx
-                .lookup
-                .as_ref()
-                .expect("If lookup is error, we already returned. qed.")
-                .iter()
-                .map(|host| host.to_string())
-                .collect();
+        // The MX hosts by order of preference. For an implicit MX, this is the
+        // domain itself.
+        let hosts = my_mx.hosts_by_preference();
+        let mx_hosts: Vec<String> = hosts.iter().map(|host| host.to_string()).collect();
 
         tracing::debug!(
                 target: LOG_TARGET,
@@ -286,40 +326,53 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
                 "Found MX hosts"
         );
 
-        let my_misc = check_misc(
-                &my_syntax,
-                input.check_gravatar,
-                input.haveibeenpwned_api_key.clone(),
-        )
-        .await;
-
-        tracing::debug!(
-                target: LOG_TARGET,
-                email=%to_email,
-                misc=?my_misc,
-                "Found misc details"
-        );
+        // The misc checks don't depend on the SMTP verification, so they run
+        // concurrently with it. Their timings are recorded separately, and
+        // merged afterwards.
+        let mut misc_timings = StageTimings::default();
+        let domain_misc = match shared {
+                Some(shared) => *shared.misc.get_or_init(|| DomainMiscDetails::new(&my_syntax)),
+                None => DomainMiscDetails::new(&my_syntax),
+        };
+        let misc_future = async {
+                let stage_start = Instant::now();
+                let my_misc = check_misc_with_domain(
+                        &my_syntax,
+                        domain_misc,
+                        input.check_gravatar,
+                        input.haveibeenpwned_api_key.clone(),
+                        input.misc_timeout,
+                        &mut misc_timings,
+                )
+                .await;
+                StageTimings::record(&mut misc_timings.misc, stage_start);
+
+                tracing::debug!(
+                        target: LOG_TARGET,
+                        email=%to_email,
+                        misc=?my_misc,
+                        "Found misc details"
+                );
+                my_misc
+        };
 
-        // From the list of MX records, we choose the one with the lowest priority.
-        let mx_records = my_mx
-                .lookup
-                .as_ref()
-                .expect("If lookup is error, we already returned. qed.")
-                .iter()
-                .min_by_key(|a| a.preference())
-                .expect("There should be at least one MX record after filtering.");
-        let host = mx_records;
-
-        let (my_smtp, smtp_debug) = check_smtp(
+        // Try the MX hosts by order of preference, falling back to the next
+        // host if one is unreachable.
+        let smtp_future = check_smtp_with_mx_fallback(
                 my_syntax
                         .address
                         .as_ref()
                         .expect("We already checked that the email has valid format. qed."),
-                host.exchange(),
+                &hosts,
                 my_syntax.domain.as_ref(),
                 input,
-        )
-        .await;
+                &mut timings,
+        );
+
+        let (my_misc, (my_smtp, smtp_debug)) = tokio::join!(misc_future, smtp_future);
+        timings.misc = misc_timings.misc;
+        timings.gravatar = misc_timings.gravatar;
+        timings.haveibeenpwned = misc_timings.haveibeenpwned;
 
         if my_smtp.is_err() {
                 get_similar_mail_provider(&mut my_syntax);
@@ -327,12 +380,14 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 
         let end_time = SystemTime::now();
 
-        let (is_reachable, reason) = calculate_reachable_with_reason(&my_misc, &my_smtp);
+        let (is_reachable, reason, reason_codes) = calculate_reachable_with_reason(&my_misc, &my_smtp, &input.reachability_policy);
 
         let output = CheckEmailOutput {
                 input: to_email.to_string(),
                 is_reachable,
                 reason,
+                reason_codes,
+                score: 0,
                 misc: Ok(my_misc),
                 mx: Ok(my_mx),
                 smtp: my_smtp,
@@ -344,6 +399,7 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
                                 .duration_since(start_time)
                                 .unwrap_or(Duration::from_secs(0)),
                         smtp: smtp_debug,
+                        timings,
                         backend_name: input.backend_name.clone(),
                 },
         };
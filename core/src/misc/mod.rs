@@ -17,9 +17,12 @@
 mod gravatar;
 use crate::haveibeenpwned::check_haveibeenpwned;
 use crate::syntax::SyntaxDetails;
+use crate::{StageTimings, LOG_TARGET};
 use gravatar::check_gravatar;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::{Duration, Instant};
 use std::{collections::HashSet, default::Default};
 use thiserror::Error;
 
@@ -62,11 +65,93 @@ pub struct MiscDetails {
 #[serde(tag = "type", content = "message")]
 pub enum MiscError {}
 
+/// The misc details which only depend on the domain of the email address,
+/// so that the verifications at the same domain can share them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DomainMiscDetails {
+	is_disposable: bool,
+	is_b2c: bool,
+}
+
+impl DomainMiscDetails {
+	pub(crate) fn new(syntax: &SyntaxDetails) -> Self {
+		let address = syntax
+			.address
+			.as_ref()
+			.expect("We already checked that the syntax was valid. qed.")
+			.to_string();
+
+		Self {
+			// mailchecker::is_valid checks also if the syntax is valid. But if
+			// we're here, it means we're sure the syntax is valid, so is_valid
+			// actually will only check if it's disposable.
+			is_disposable: !mailchecker::is_valid(address.as_ref()),
+			is_b2c: FREE_EMAIL_PROVIDERS_SET.contains(&syntax.domain.to_lowercase()),
+		}
+	}
+}
+
+/// Run a misc lookup if it's enabled, giving up after `timeout`. Returns its
+/// result, and how long it took if it ran.
+async fn lookup<T>(
+	name: &str,
+	enabled: bool,
+	timeout: Option<Duration>,
+	lookup: impl Future<Output = Option<T>>,
+) -> (Option<T>, Option<Duration>) {
+	if !enabled {
+		return (None, None);
+	}
+
+	let start = Instant::now();
+	let result = match timeout {
+		Some(timeout) => tokio::time::timeout(timeout, lookup)
+			.await
+			.unwrap_or_else(|_| {
+				tracing::debug!(
+					target: LOG_TARGET,
+					lookup=name,
+					timeout=?timeout,
+					"Misc lookup timed out"
+				);
+				None
+			}),
+		None => lookup.await,
+	};
+	(result, Some(start.elapsed()))
+}
+
 /// Fetch misc details about the email address, such as whether it's disposable.
+/// The Gravatar and HaveIBeenPwned lookups run concurrently, each giving up
+/// after `timeout`, and the time they took is recorded in `timings`.
+///
+/// Breaking change in 0.12: this now takes the `timeout` and `timings` arguments.
 pub async fn check_misc(
 	syntax: &SyntaxDetails,
 	cfg_check_gravatar: bool,
 	haveibeenpwned_api_key: Option<String>,
+	timeout: Option<Duration>,
+	timings: &mut StageTimings,
+) -> MiscDetails {
+	check_misc_with_domain(
+		syntax,
+		DomainMiscDetails::new(syntax),
+		cfg_check_gravatar,
+		haveibeenpwned_api_key,
+		timeout,
+		timings,
+	)
+	.await
+}
+
+/// Same as [check_misc], reusing the misc details of the domain.
+pub(crate) async fn check_misc_with_domain(
+	syntax: &SyntaxDetails,
+	domain: DomainMiscDetails,
+	cfg_check_gravatar: bool,
+	haveibeenpwned_api_key: Option<String>,
+	timeout: Option<Duration>,
+	timings: &mut StageTimings,
 ) -> MiscDetails {
 	let address = syntax
 		.address
@@ -74,25 +159,27 @@ pub async fn check_misc(
 		.expect("We already checked that the syntax was valid. qed.")
 		.to_string();
 
-	let mut gravatar_url: Option<String> = None;
-
-	if cfg_check_gravatar {
-		gravatar_url = check_gravatar(address.as_ref()).await;
-	}
-
-	let mut haveibeenpwned: Option<bool> = None;
-
-	if haveibeenpwned_api_key.is_some() {
-		haveibeenpwned = check_haveibeenpwned(address.as_ref(), haveibeenpwned_api_key).await;
-	}
+	let ((gravatar_url, gravatar_time), (haveibeenpwned, haveibeenpwned_time)) = tokio::join!(
+		lookup(
+			"gravatar",
+			cfg_check_gravatar,
+			timeout,
+			check_gravatar(address.as_ref())
+		),
+		lookup(
+			"haveibeenpwned",
+			haveibeenpwned_api_key.is_some(),
+			timeout,
+			check_haveibeenpwned(address.as_ref(), haveibeenpwned_api_key)
+		),
+	);
+	timings.gravatar = gravatar_time;
+	timings.haveibeenpwned = haveibeenpwned_time;
 
 	MiscDetails {
-		// mailchecker::is_valid checks also if the syntax is valid. But if
-		// we're here, it means we're sure the syntax is valid, so is_valid
-		// actually will only check if it's disposable.
-		is_disposable: !mailchecker::is_valid(address.as_ref()),
+		is_disposable: domain.is_disposable,
 		is_role_account: ROLE_ACCOUNTS_SET.contains(&syntax.username.to_lowercase()),
-		is_b2c: FREE_EMAIL_PROVIDERS_SET.contains(&syntax.domain.to_lowercase()),
+		is_b2c: domain.is_b2c,
 		gravatar_url,
 		haveibeenpwned,
 	}
@@ -113,12 +200,31 @@ mod tests {
 			domain: "gmail.com".to_string(),
 			normalized_email: None,
 			suggestion: None,
+			..Default::default()
 		};
 
-		let misc_details = check_misc(&syntax, true, None).await;
+		let misc_details =
+			check_misc(&syntax, true, None, None, &mut StageTimings::default()).await;
 
 		assert!(!misc_details.is_disposable); // gmail.com is not in mailchecker
 		assert!(misc_details.is_role_account); // test is in roles.txt
 		assert!(misc_details.is_b2c); // gmail.com is in b2c.txt
 	}
+
+	#[tokio::test]
+	async fn should_give_up_lookup_after_timeout() {
+		let timeout = Some(Duration::from_millis(10));
+		let (result, elapsed) = lookup(
+			"test",
+			true,
+			timeout,
+			std::future::pending::<Option<bool>>(),
+		)
+		.await;
+		assert_eq!(result, None);
+		assert!(elapsed.is_some());
+
+		let (result, elapsed) = lookup("test", false, timeout, async { Some(true) }).await;
+		assert_eq!((result, elapsed), (None, None));
+	}
 }
@@ -14,34 +14,145 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::syntax::SyntaxDetails;
+pub mod resolver;
+
+use crate::syntax::{domain_to_ascii, SyntaxDetails};
 use crate::util::ser_with_display::ser_with_display;
+use hickory_proto::rr::rdata::MX;
+use hickory_proto::rr::Name;
 use hickory_resolver::error::{ResolveError, ResolveErrorKind};
 use hickory_resolver::lookup::MxLookup;
 use hickory_resolver::system_conf::read_system_conf;
 use hickory_resolver::TokioAsyncResolver;
-use serde::{ser::SerializeMap, Serialize, Serializer};
+use rand::seq::SliceRandom;
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 use std::io;
 use thiserror::Error;
 
+/// How the domain designates the hosts that receive its emails.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MxKind {
+	/// The domain publishes MX records.
+	Explicit,
+	/// The domain has no MX records, but has A/AAAA records. As per RFC 5321
+	/// section 5.1, the domain itself is then the mail host (implicit MX).
+	Implicit(Name),
+	/// The domain publishes a null MX record (`MX 0 .`), i.e. it explicitly
+	/// doesn't accept any email (RFC 7505).
+	NullMx,
+	/// The domain has neither MX nor A/AAAA records.
+	NoRecords,
+}
+
+impl MxKind {
+	fn as_str(&self) -> &'static str {
+		match self {
+			MxKind::Explicit => "explicit",
+			MxKind::Implicit(_) => "implicit",
+			MxKind::NullMx => "null_mx",
+			MxKind::NoRecords => "no_records",
+		}
+	}
+}
+
 /// Details about the MX lookup.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MxDetails {
 	/// MX lookup of this DNS.
 	pub lookup: Result<MxLookup, ResolveError>,
+	/// How the domain designates its mail hosts.
+	pub kind: MxKind,
 }
 
 impl Default for MxDetails {
 	fn default() -> Self {
 		MxDetails {
 			lookup: Err(ResolveError::from("Skipped")),
+			kind: MxKind::NoRecords,
+		}
+	}
+}
+
+impl MxDetails {
+	/// Whether the domain accepts emails, i.e. it has an explicit or an
+	/// implicit MX.
+	pub fn accepts_mail(&self) -> bool {
+		matches!(self.kind, MxKind::Explicit | MxKind::Implicit(_))
+	}
+
+	/// The MX hosts to try for the SMTP verification, ordered by preference
+	/// (lowest first). For an implicit MX, this is the domain itself. Returns
+	/// an empty list if the domain doesn't accept emails.
+	pub fn hosts_by_preference(&self) -> Vec<Name> {
+		match (&self.kind, &self.lookup) {
+			(MxKind::Explicit, Ok(lookup)) => order_by_preference(lookup.iter().cloned().collect()),
+			(MxKind::Implicit(host), _) => vec![host.clone()],
+			_ => Vec::new(),
+		}
+	}
+}
+
+/// Check if the MX lookup is a null MX as defined in RFC 7505, i.e. a single
+/// MX record whose exchange is the root domain ".".
+fn is_null_mx(lookup: &MxLookup) -> bool {
+	let mut records = lookup.iter();
+	match (records.next(), records.next()) {
+		(Some(record), None) => record.exchange().is_root(),
+		_ => false,
+	}
+}
+
+/// Sort MX records by preference, lowest first. As per RFC 5321 section 5.1,
+/// records sharing the same preference are randomized, so that we spread the
+/// load across them.
+fn order_by_preference(mut records: Vec<MX>) -> Vec<Name> {
+	records.shuffle(&mut rand::thread_rng());
+	// `sort_by_key` is stable, so the shuffled order is kept among equal
+	// preferences.
+	records.sort_by_key(|r| r.preference());
+	records.into_iter().map(|r| r.exchange().clone()).collect()
+}
+
+/// Strategy to choose which MX hosts to connect to during the SMTP
+/// verification.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MxFallbackStrategy {
+	/// Only connect to the MX host with the lowest preference.
+	LowestPreference,
+	/// Connect to the MX hosts in order of preference, and move on to the
+	/// next one if a host is unreachable (timeout, connection refused, proxy
+	/// error...). At most `max_hosts` hosts are tried.
+	ByPreference { max_hosts: usize },
+}
+
+impl Default for MxFallbackStrategy {
+	fn default() -> Self {
+		MxFallbackStrategy::ByPreference { max_hosts: 3 }
+	}
+}
+
+impl MxFallbackStrategy {
+	/// Maximum number of MX hosts to try. Always at least 1.
+	pub fn max_hosts(&self) -> usize {
+		match self {
+			MxFallbackStrategy::LowestPreference => 1,
+			MxFallbackStrategy::ByPreference { max_hosts } => (*max_hosts).max(1),
 		}
 	}
 }
 
 impl From<MxLookup> for MxDetails {
 	fn from(lookup: MxLookup) -> Self {
-		MxDetails { lookup: Ok(lookup) }
+		let kind = if is_null_mx(&lookup) {
+			MxKind::NullMx
+		} else {
+			MxKind::Explicit
+		};
+		MxDetails {
+			lookup: Ok(lookup),
+			kind,
+		}
 	}
 }
 
@@ -50,20 +161,24 @@ impl Serialize for MxDetails {
 	where
 		S: Serializer,
 	{
-		let records: Vec<String> = self
-			.lookup
-			.as_ref()
-			.map(|lookup| {
-				lookup
-					.iter()
-					.map(|host| host.exchange().to_string())
-					.collect::<Vec<_>>()
-			})
-			.unwrap_or_else(|_| Vec::new()); // In case of a resolve error, we don't serialize the error.
-
-		let mut map = serializer.serialize_map(Some(2))?;
-		map.serialize_entry("accepts_mail", &!records.is_empty())?;
+		let records: Vec<String> = match &self.kind {
+			MxKind::Implicit(host) => vec![host.to_string()],
+			_ => self
+				.lookup
+				.as_ref()
+				.map(|lookup| {
+					lookup
+						.iter()
+						.map(|host| host.exchange().to_string())
+						.collect::<Vec<_>>()
+				})
+				.unwrap_or_else(|_| Vec::new()), // In case of a resolve error, we don't serialize the error.
+		};
+
+		let mut map = serializer.serialize_map(Some(3))?;
+		map.serialize_entry("accepts_mail", &self.accepts_mail())?;
 		map.serialize_entry("records", &records)?;
+		map.serialize_entry("kind", self.kind.as_str())?;
 		map.end()
 	}
 }
@@ -82,6 +197,16 @@ pub enum MxError {
 	ResolveError(Box<ResolveError>),
 }
 
+impl Clone for MxError {
+	fn clone(&self) -> Self {
+		match self {
+			// io::Error isn't Clone, only its kind and message are kept.
+			MxError::IoError(e) => MxError::IoError(io::Error::new(e.kind(), e.to_string())),
+			MxError::ResolveError(e) => MxError::ResolveError(e.clone()),
+		}
+	}
+}
+
 impl From<io::Error> for MxError {
 	fn from(e: io::Error) -> Self {
 		MxError::IoError(e)
@@ -94,17 +219,51 @@ impl From<ResolveError> for MxError {
 	}
 }
 
-/// Make a MX lookup.
-pub async fn check_mx(syntax: &SyntaxDetails) -> Result<MxDetails, MxError> {
-	// Construct a new Resolver with default configuration options
-	let (config, opts) = read_system_conf()?;
-	let resolver = TokioAsyncResolver::tokio(config, opts);
+/// Make a MX lookup, using the given shared resolver. If no resolver is
+/// given, a new one is built from the system configuration, without any
+/// cache shared across calls.
+pub async fn check_mx(
+	syntax: &SyntaxDetails,
+	resolver: Option<&TokioAsyncResolver>,
+) -> Result<MxDetails, MxError> {
+	let system_resolver;
+	let resolver = match resolver {
+		Some(resolver) => resolver,
+		None => {
+			let (config, opts) = read_system_conf()?;
+			system_resolver = TokioAsyncResolver::tokio(config, opts);
+			&system_resolver
+		}
+	};
+
+	// Internationalized domain names are looked up in their punycode form.
+	let domain = domain_to_ascii(&syntax.domain)
+		.ok_or_else(|| ResolveError::from(format!("Invalid domain name: {}", syntax.domain)))?;
 
-	match resolver.mx_lookup(&syntax.domain).await {
+	match resolver.mx_lookup(domain.as_str()).await {
 		Ok(lookup) => Ok(MxDetails::from(lookup)),
 		Err(err) => match err.kind() {
-			// Prefer to return an empty MX lookup if there are no records.
-			ResolveErrorKind::NoRecordsFound { .. } => Ok(MxDetails { lookup: Err(err) }),
+			// If there are no MX records, fall back to the A/AAAA records of the
+			// domain (implicit MX, RFC 5321 section 5.1).
+			ResolveErrorKind::NoRecordsFound { .. } => {
+				let kind = match resolver.lookup_ip(domain.as_str()).await {
+					Ok(ips) if ips.iter().next().is_some() => {
+						let mut host = Name::from_ascii(&domain).map_err(ResolveError::from)?;
+						host.set_fqdn(true);
+						MxKind::Implicit(host)
+					}
+					Ok(_) => MxKind::NoRecords,
+					Err(ip_err) => match ip_err.kind() {
+						ResolveErrorKind::NoRecordsFound { .. } => MxKind::NoRecords,
+						_ => return Err(ip_err.into()),
+					},
+				};
+				// Prefer to return an empty MX lookup if there are no records.
+				Ok(MxDetails {
+					lookup: Err(err),
+					kind,
+				})
+			}
 			_ => Err(err.into()),
 		},
 	}
@@ -163,3 +322,100 @@ pub fn is_proofpoint(mx_host: &str) -> bool {
 pub fn is_yahoo(mx_host: &str) -> bool {
 	mx_host.to_lowercase().ends_with(".yahoodns.net.")
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use hickory_proto::op::Query;
+	use hickory_proto::rr::{RData, Record, RecordType};
+	use hickory_resolver::lookup::Lookup;
+	use std::str::FromStr;
+	use std::sync::Arc;
+
+	fn mx(preference: u16, host: &str) -> MX {
+		MX::new(preference, Name::from_str(host).unwrap())
+	}
+
+	#[test]
+	fn should_order_by_preference() {
+		let hosts = order_by_preference(vec![
+			mx(30, "c.example.com."),
+			mx(10, "a.example.com."),
+			mx(20, "b.example.com."),
+		]);
+
+		assert_eq!(
+			hosts,
+			vec![
+				Name::from_str("a.example.com.").unwrap(),
+				Name::from_str("b.example.com.").unwrap(),
+				Name::from_str("c.example.com.").unwrap(),
+			]
+		);
+	}
+
+	#[test]
+	fn should_randomize_equal_preferences() {
+		let records = vec![
+			mx(10, "a.example.com."),
+			mx(10, "b.example.com."),
+			mx(20, "c.example.com."),
+		];
+
+		let mut first_hosts = std::collections::HashSet::new();
+		for _ in 0..100 {
+			let hosts = order_by_preference(records.clone());
+			assert_eq!(hosts[2], Name::from_str("c.example.com.").unwrap());
+			first_hosts.insert(hosts[0].clone());
+		}
+		assert_eq!(first_hosts.len(), 2);
+	}
+
+	fn mx_lookup(records: Vec<MX>) -> MxLookup {
+		let domain = Name::from_str("example.com.").unwrap();
+		let records: Vec<Record> = records
+			.into_iter()
+			.map(|r| Record::from_rdata(domain.clone(), 3600, RData::MX(r)))
+			.collect();
+		Lookup::new_with_max_ttl(Query::query(domain, RecordType::MX), Arc::from(records)).into()
+	}
+
+	#[test]
+	fn should_detect_null_mx() {
+		let details = MxDetails::from(mx_lookup(vec![mx(0, ".")]));
+		assert_eq!(details.kind, MxKind::NullMx);
+		assert!(!details.accepts_mail());
+		assert!(details.hosts_by_preference().is_empty());
+
+		let details = MxDetails::from(mx_lookup(vec![mx(10, "a.example.com.")]));
+		assert_eq!(details.kind, MxKind::Explicit);
+		assert!(details.accepts_mail());
+	}
+
+	#[test]
+	fn should_use_domain_as_implicit_mx() {
+		let host = Name::from_str("example.com.").unwrap();
+		let details = MxDetails {
+			lookup: Err(ResolveError::from("No MX records")),
+			kind: MxKind::Implicit(host.clone()),
+		};
+		assert!(details.accepts_mail());
+		assert_eq!(details.hosts_by_preference(), vec![host]);
+
+		let actual = serde_json::to_string(&details).unwrap();
+		assert_eq!(
+			actual,
+			r#"{"accepts_mail":true,"records":["example.com."],"kind":"implicit"}"#
+		);
+	}
+
+	#[test]
+	fn should_try_at_least_one_host() {
+		assert_eq!(MxFallbackStrategy::LowestPreference.max_hosts(), 1);
+		assert_eq!(
+			MxFallbackStrategy::ByPreference { max_hosts: 0 }.max_hosts(),
+			1
+		);
+		assert_eq!(MxFallbackStrategy::default().max_hosts(), 3);
+	}
+}
@@ -0,0 +1,183 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Configurable DNS resolver used for the MX lookups. The resolver keeps a
+//! cache of the DNS answers (respecting their TTL), so it should be built
+//! once with [build_resolver] and shared across all email verifications.
+
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveError;
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The DNS resolver used for the MX lookups.
+pub type DnsResolver = TokioAsyncResolver;
+
+/// Protocol used to reach the upstream nameservers.
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsProtocol {
+	/// Plain DNS over UDP, falling back to TCP for truncated answers.
+	#[default]
+	Udp,
+	/// DNS-over-TLS (RFC 7858).
+	Tls,
+	/// DNS-over-HTTPS (RFC 8484).
+	Https,
+}
+
+impl DnsProtocol {
+	/// The default port of the protocol.
+	pub fn default_port(&self) -> u16 {
+		match self {
+			DnsProtocol::Udp => 53,
+			DnsProtocol::Tls => 853,
+			DnsProtocol::Https => 443,
+		}
+	}
+}
+
+/// Configuration of the DNS resolver.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct DnsConfig {
+	/// IP addresses of the upstream nameservers. If empty, the nameservers
+	/// of the system configuration (e.g. /etc/resolv.conf) are used.
+	pub nameservers: Vec<IpAddr>,
+	/// Port of the upstream nameservers. Defaults to the protocol's port.
+	pub port: Option<u16>,
+	/// Protocol used to reach the upstream nameservers.
+	pub protocol: DnsProtocol,
+	/// Name of the nameservers to validate their TLS certificate against.
+	/// Required for the "tls" and "https" protocols, e.g. "cloudflare-dns.com".
+	pub tls_dns_name: Option<String>,
+	/// Timeout of each DNS request, in milliseconds.
+	pub timeout_ms: u64,
+	/// Number of attempts before giving up on a DNS request.
+	pub attempts: usize,
+	/// Maximum number of DNS answers kept in the cache. Set to 0 to disable
+	/// the cache.
+	pub cache_size: usize,
+	/// Minimum time to keep a DNS answer in the cache, in seconds, overriding
+	/// its TTL if lower.
+	pub min_ttl_secs: Option<u64>,
+	/// Maximum time to keep a DNS answer in the cache, in seconds, overriding
+	/// its TTL if higher.
+	pub max_ttl_secs: Option<u64>,
+}
+
+impl Default for DnsConfig {
+	fn default() -> Self {
+		DnsConfig {
+			nameservers: vec![],
+			port: None,
+			protocol: DnsProtocol::default(),
+			tls_dns_name: None,
+			timeout_ms: 5000,
+			attempts: 2,
+			cache_size: 1024,
+			min_ttl_secs: None,
+			max_ttl_secs: None,
+		}
+	}
+}
+
+/// Build a DNS resolver from the given configuration. This should be called
+/// once, and the resolver shared (e.g. via `CheckEmailInput.dns_resolver`),
+/// so that its cache is reused across email verifications.
+pub fn build_resolver(config: &DnsConfig) -> Result<DnsResolver, ResolveError> {
+	let (resolver_config, mut opts) = if config.nameservers.is_empty() {
+		if config.protocol != DnsProtocol::Udp {
+			return Err(ResolveError::from(
+				"DNS nameservers must be set when using the tls or https protocol",
+			));
+		}
+		read_system_conf()?
+	} else {
+		let port = config
+			.port
+			.unwrap_or_else(|| config.protocol.default_port());
+		let group = match config.protocol {
+			DnsProtocol::Udp => {
+				NameServerConfigGroup::from_ips_clear(&config.nameservers, port, true)
+			}
+			DnsProtocol::Tls => NameServerConfigGroup::from_ips_tls(
+				&config.nameservers,
+				port,
+				tls_dns_name(config)?,
+				true,
+			),
+			DnsProtocol::Https => NameServerConfigGroup::from_ips_https(
+				&config.nameservers,
+				port,
+				tls_dns_name(config)?,
+				true,
+			),
+		};
+		(
+			ResolverConfig::from_parts(None, vec![], group),
+			ResolverOpts::default(),
+		)
+	};
+
+	opts.timeout = Duration::from_millis(config.timeout_ms);
+	opts.attempts = config.attempts;
+	opts.cache_size = config.cache_size;
+	opts.positive_min_ttl = config.min_ttl_secs.map(Duration::from_secs);
+	opts.positive_max_ttl = config.max_ttl_secs.map(Duration::from_secs);
+
+	Ok(TokioAsyncResolver::tokio(resolver_config, opts))
+}
+
+fn tls_dns_name(config: &DnsConfig) -> Result<String, ResolveError> {
+	config.tls_dns_name.clone().ok_or_else(|| {
+		ResolveError::from("DNS tls_dns_name must be set when using the tls or https protocol")
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_require_tls_dns_name() {
+		let config = DnsConfig {
+			nameservers: vec!["1.1.1.1".parse().unwrap()],
+			protocol: DnsProtocol::Tls,
+			..Default::default()
+		};
+		assert!(build_resolver(&config).is_err());
+
+		let config = DnsConfig {
+			tls_dns_name: Some("cloudflare-dns.com".into()),
+			..config
+		};
+		assert!(build_resolver(&config).is_ok());
+	}
+
+	#[test]
+	fn should_require_nameservers_for_encrypted_dns() {
+		let config = DnsConfig {
+			protocol: DnsProtocol::Https,
+			tls_dns_name: Some("cloudflare-dns.com".into()),
+			..Default::default()
+		};
+		assert!(build_resolver(&config).is_err());
+	}
+}
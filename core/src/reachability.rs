@@ -0,0 +1,339 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! How the signals of a verification map to its `is_reachable` field, see
+//! [ReachabilityPolicy].
+
+use crate::misc::MiscDetails;
+use crate::smtp::{SmtpDetails, SmtpError, SmtpErrorDesc};
+use crate::Reachable;
+use serde::{Deserialize, Serialize};
+
+/// Machine-readable counterpart of the `reason` field of the output: why the
+/// email is risky, invalid, or unknown.
+#[derive(Debug, Clone, Copy, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonCode {
+	// Signals of a successful SMTP verification, see [ReachabilityPolicy].
+	Disposable,
+	RoleAccount,
+	CatchAll,
+	MailboxFull,
+	CannotConnectSmtp,
+	AccountDisabled,
+	NotDeliverable,
+	// Syntax and MX checks.
+	InvalidSyntax,
+	NoMx,
+	NullMx,
+	MxLookupFailed,
+	// Failures of the SMTP verification.
+	SmtpTimeout,
+	ProxyFailure,
+	IpBlacklisted,
+	NeedsRdns,
+	Greylisted,
+	TlsFailure,
+	EaiUnsupported,
+	SkippedByRule,
+	ProviderApiFailure,
+	SmtpError,
+	IoError,
+	InternalError,
+}
+
+impl From<&SmtpError> for ReasonCode {
+	fn from(error: &SmtpError) -> Self {
+		match error {
+			SmtpError::YahooError(_)
+			| SmtpError::GmailError(_)
+			| SmtpError::HeadlessError(_)
+			| SmtpError::Microsoft365Error(_) => ReasonCode::ProviderApiFailure,
+			SmtpError::AsyncSmtpError(_) if error.is_greylisted() => ReasonCode::Greylisted,
+			SmtpError::AsyncSmtpError(_) => match error.get_description() {
+				Some(SmtpErrorDesc::IpBlacklisted) => ReasonCode::IpBlacklisted,
+				Some(SmtpErrorDesc::NeedsRDNS) => ReasonCode::NeedsRdns,
+				_ => ReasonCode::SmtpError,
+			},
+			SmtpError::IOError(_) => ReasonCode::IoError,
+			SmtpError::Timeout(_) => ReasonCode::SmtpTimeout,
+			SmtpError::Socks5(_) => ReasonCode::ProxyFailure,
+			SmtpError::TlsError(_) => ReasonCode::TlsFailure,
+			SmtpError::EaiUnsupported => ReasonCode::EaiUnsupported,
+			SmtpError::SkippedByRule => ReasonCode::SkippedByRule,
+			SmtpError::AnyhowError(_) => ReasonCode::InternalError,
+		}
+	}
+}
+
+/// What a signal makes of the email, when it fires.
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignalOutcome {
+	/// The email is still safe, but the signal is mentioned in the reason and
+	/// the reason codes.
+	Safe,
+	Risky,
+	Invalid,
+	/// The signal is not taken into account.
+	Ignored,
+}
+
+/// Maps each signal of a successful SMTP verification to an outcome. The
+/// email is risky if a risky signal fired, else invalid if an invalid signal
+/// fired, else safe. A full inbox or a disabled account also makes the email
+/// undeliverable, so `not_deliverable` only fires if neither `full_inbox` nor
+/// `disabled` did.
+///
+/// The default policy treats disposable emails, role accounts, catch-all
+/// domains and full inboxes as risky, and unreachable SMTP servers, disabled
+/// accounts and undeliverable emails as invalid.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ReachabilityPolicy {
+	pub disposable: SignalOutcome,
+	pub role_account: SignalOutcome,
+	pub catch_all: SignalOutcome,
+	pub full_inbox: SignalOutcome,
+	pub cannot_connect_smtp: SignalOutcome,
+	pub disabled: SignalOutcome,
+	pub not_deliverable: SignalOutcome,
+}
+
+impl Default for ReachabilityPolicy {
+	fn default() -> Self {
+		Self {
+			disposable: SignalOutcome::Risky,
+			role_account: SignalOutcome::Risky,
+			catch_all: SignalOutcome::Risky,
+			full_inbox: SignalOutcome::Risky,
+			cannot_connect_smtp: SignalOutcome::Invalid,
+			disabled: SignalOutcome::Invalid,
+			not_deliverable: SignalOutcome::Invalid,
+		}
+	}
+}
+
+impl ReachabilityPolicy {
+	/// Calculate the reachability of an email whose SMTP verification
+	/// succeeded, along with a human-readable reason and the codes of the
+	/// signals it mentions. The reason and codes of a safe email are those of
+	/// the signals whose outcome is [SignalOutcome::Safe], if any fired.
+	pub fn evaluate(
+		&self,
+		misc: &MiscDetails,
+		smtp: &SmtpDetails,
+	) -> (Reachable, String, Vec<ReasonCode>) {
+		// A more specific signal already explains why the email is
+		// undeliverable.
+		let fired =
+			|signal: bool, outcome: SignalOutcome| signal && outcome != SignalOutcome::Ignored;
+		let explained_undeliverable =
+			fired(smtp.has_full_inbox, self.full_inbox) || fired(smtp.is_disabled, self.disabled);
+		let signals = [
+			(
+				misc.is_disposable,
+				self.disposable,
+				ReasonCode::Disposable,
+				"disposable email address",
+			),
+			(
+				misc.is_role_account,
+				self.role_account,
+				ReasonCode::RoleAccount,
+				"role-based account (e.g., admin@, support@)",
+			),
+			(
+				smtp.is_catch_all,
+				self.catch_all,
+				ReasonCode::CatchAll,
+				"catch-all address (accepts all emails)",
+			),
+			(
+				smtp.has_full_inbox,
+				self.full_inbox,
+				ReasonCode::MailboxFull,
+				"inbox is full",
+			),
+			(
+				!smtp.can_connect_smtp,
+				self.cannot_connect_smtp,
+				ReasonCode::CannotConnectSmtp,
+				"cannot connect to SMTP server",
+			),
+			(
+				smtp.is_disabled,
+				self.disabled,
+				ReasonCode::AccountDisabled,
+				"email account is disabled",
+			),
+			(
+				!smtp.is_deliverable && !explained_undeliverable,
+				self.not_deliverable,
+				ReasonCode::NotDeliverable,
+				"email is not deliverable",
+			),
+		];
+
+		for (is_reachable, outcome, prefix) in [
+			(Reachable::Risky, SignalOutcome::Risky, "Risky"),
+			(Reachable::Invalid, SignalOutcome::Invalid, "Invalid"),
+			(Reachable::Safe, SignalOutcome::Safe, "Safe"),
+		] {
+			let (codes, reasons): (Vec<ReasonCode>, Vec<&str>) = signals
+				.iter()
+				.filter(|(fired, o, _, _)| *fired && *o == outcome)
+				.map(|(_, _, code, reason)| (*code, *reason))
+				.unzip();
+			if !codes.is_empty() {
+				let reason = format!("{}: {}", prefix, reasons.join(", "));
+				return (is_reachable, reason, codes);
+			}
+		}
+
+		(
+			Reachable::Safe,
+			"Email verification passed all checks".to_string(),
+			Vec::new(),
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn full_inbox_role_account() -> (MiscDetails, SmtpDetails) {
+		let misc = MiscDetails {
+			is_role_account: true,
+			..Default::default()
+		};
+		let smtp = SmtpDetails {
+			can_connect_smtp: true,
+			has_full_inbox: true,
+			..Default::default()
+		};
+		(misc, smtp)
+	}
+
+	#[test]
+	fn should_keep_default_behavior() {
+		let (misc, smtp) = full_inbox_role_account();
+		assert_eq!(
+			ReachabilityPolicy::default().evaluate(&misc, &smtp),
+			(
+				Reachable::Risky,
+				"Risky: role-based account (e.g., admin@, support@), inbox is full".to_string(),
+				vec![ReasonCode::RoleAccount, ReasonCode::MailboxFull]
+			)
+		);
+	}
+
+	#[test]
+	fn should_apply_custom_policy() {
+		let (misc, smtp) = full_inbox_role_account();
+		let policy = ReachabilityPolicy {
+			role_account: SignalOutcome::Safe,
+			full_inbox: SignalOutcome::Invalid,
+			..Default::default()
+		};
+		assert_eq!(
+			policy.evaluate(&misc, &smtp),
+			(
+				Reachable::Invalid,
+				"Invalid: inbox is full".to_string(),
+				vec![ReasonCode::MailboxFull]
+			)
+		);
+
+		let smtp = SmtpDetails {
+			is_deliverable: true,
+			has_full_inbox: false,
+			..smtp
+		};
+		assert_eq!(
+			policy.evaluate(&misc, &smtp),
+			(
+				Reachable::Safe,
+				"Safe: role-based account (e.g., admin@, support@)".to_string(),
+				vec![ReasonCode::RoleAccount]
+			)
+		);
+	}
+
+	#[test]
+	fn should_only_fire_not_deliverable_without_specific_signal() {
+		let smtp = SmtpDetails {
+			can_connect_smtp: true,
+			is_disabled: true,
+			..Default::default()
+		};
+		let policy = ReachabilityPolicy {
+			disabled: SignalOutcome::Risky,
+			..Default::default()
+		};
+		assert_eq!(
+			policy.evaluate(&MiscDetails::default(), &smtp),
+			(
+				Reachable::Risky,
+				"Risky: email account is disabled".to_string(),
+				vec![ReasonCode::AccountDisabled]
+			)
+		);
+
+		// An ignored signal doesn't explain anything.
+		let policy = ReachabilityPolicy {
+			disabled: SignalOutcome::Ignored,
+			..Default::default()
+		};
+		assert_eq!(
+			policy.evaluate(&MiscDetails::default(), &smtp),
+			(
+				Reachable::Invalid,
+				"Invalid: email is not deliverable".to_string(),
+				vec![ReasonCode::NotDeliverable]
+			)
+		);
+	}
+
+	#[test]
+	fn should_not_give_reason_codes_when_safe() {
+		let smtp = SmtpDetails {
+			can_connect_smtp: true,
+			is_deliverable: true,
+			..Default::default()
+		};
+		assert_eq!(
+			ReachabilityPolicy::default().evaluate(&MiscDetails::default(), &smtp),
+			(
+				Reachable::Safe,
+				"Email verification passed all checks".to_string(),
+				vec![]
+			)
+		);
+	}
+
+	#[test]
+	fn should_map_smtp_errors_to_reason_codes() {
+		let code = ReasonCode::from(&SmtpError::Timeout(std::time::Duration::from_secs(10)));
+		assert_eq!(code, ReasonCode::SmtpTimeout);
+		assert_eq!(serde_json::to_string(&code).unwrap(), r#""smtp_timeout""#);
+		assert_eq!(
+			ReasonCode::from(&SmtpError::SkippedByRule),
+			ReasonCode::SkippedByRule
+		);
+	}
+}
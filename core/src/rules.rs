@@ -18,88 +18,388 @@
 //! email verification to the domain/provider, and translate those rules into
 //! code.
 //!
+//! The built-in rules are read from `rules.json`. A user-supplied file with
+//! the same format can extend (or replace) them, see [init_rules]. Rules are
+//! applied by domain name, by exact MX host, or by MX host suffix. When
+//! several matching entries set the same valued rule (e.g. the SMTP
+//! timeout), the most specific one wins: domain first, then exact MX host,
+//! then the longest MX host suffix.
+//!
 //! IMPORTANT: This is still a beta feature, and probably needs refining.
 
+use crate::smtp::verif_method::VerifMethodSmtpConfig;
+use crate::EmailAddress;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
 
-#[derive(Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[derive(Debug, Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum Rule {
 	/// Don't perform catch-all check.
 	SkipCatchAll,
-	/// Set the SMTP timeout to 45s.
+	/// Set the SMTP timeout to at least 45s.
 	SmtpTimeout45s,
+	/// Set the SMTP timeout to this number of seconds.
+	SmtpTimeoutSecs(u64),
+	/// Connect through this proxy, which must exist in the `proxies` field of
+	/// the verification method.
+	ForceProxy(String),
+	/// Use this verification method, regardless of the one configured for
+	/// the email provider.
+	ForceVerifMethod(ForcedVerifMethod),
+	/// Don't perform the SMTP verification at all.
+	SkipSmtp,
+	/// Consider the domain as catch-all, without checking it.
+	TreatAsCatchAll,
+	/// Use this email in the `MAIL FROM:` SMTP command.
+	FromEmail(String),
+	/// Use this name in the `EHLO` SMTP command.
+	HelloName(String),
+}
+
+/// A verification method forced by the [Rule::ForceVerifMethod] rule.
+#[derive(Debug, Clone, Copy, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForcedVerifMethod {
+	/// Connect to the SMTP server. Email providers without an SMTP
+	/// configuration use the one of "everything else".
+	Smtp,
+	/// Use the provider's HTTP API, only available for Yahoo.
+	Api,
+	/// Use a headless browser, only available for Yahoo and Hotmail B2C.
+	Headless,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 struct RulesByDomain {
 	rules: Vec<Rule>,
+	#[serde(default, rename = "_comment", skip_serializing_if = "Option::is_none")]
+	comment: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 struct AllRules {
+	/// If set in a user-supplied file, its rules replace the built-in ones
+	/// instead of extending them.
+	#[serde(default)]
+	replace_defaults: bool,
 	/// Apply rules by domain name, i.e. after the @ symbol.
+	#[serde(default)]
 	by_domain: HashMap<String, RulesByDomain>,
 	/// Apply rules by the MX host.
+	#[serde(default)]
 	by_mx: HashMap<String, RulesByDomain>,
 	/// Apply rules by the MX host. Since each domain potentially has multiple
 	/// MX records, we match by their suffix.
+	#[serde(default)]
 	by_mx_suffix: HashMap<String, RulesByDomain>,
 }
 
-static ALL_RULES: Lazy<AllRules> =
-	Lazy::new(|| serde_json::from_str::<AllRules>(include_str!("rules.json")).unwrap());
+impl AllRules {
+	/// Check that the valued rules are usable.
+	fn validate(&self) -> Result<(), RulesError> {
+		let entries = self
+			.by_domain
+			.iter()
+			.chain(self.by_mx.iter())
+			.chain(self.by_mx_suffix.iter());
+		for (key, entry) in entries {
+			for rule in &entry.rules {
+				let error = match rule {
+					Rule::SmtpTimeoutSecs(0) => Some("the SMTP timeout must be positive"),
+					Rule::ForceProxy(id) if id.is_empty() => Some("the proxy ID must not be empty"),
+					Rule::HelloName(name) if name.is_empty() => {
+						Some("the hello name must not be empty")
+					}
+					Rule::FromEmail(email) if EmailAddress::new(email.clone()).is_err() => {
+						Some("the from email is invalid")
+					}
+					_ => None,
+				};
+				if let Some(error) = error {
+					return Err(RulesError::InvalidRule(key.clone(), error));
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Merge the entries of another file into these rules. Entries for the
+	/// same key are replaced.
+	fn extend(&mut self, other: AllRules) {
+		self.by_domain.extend(other.by_domain);
+		self.by_mx.extend(other.by_mx);
+		self.by_mx_suffix.extend(other.by_mx_suffix);
+	}
+}
+
+/// Error when loading a rules file.
+#[derive(Debug, Error)]
+pub enum RulesError {
+	#[error("Cannot read rules file {0}: {1}")]
+	Io(PathBuf, std::io::Error),
+	#[error("Invalid rules file: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("Invalid rule for \"{0}\": {1}")]
+	InvalidRule(String, &'static str),
+}
+
+/// Configuration of the rules.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RulesConfig {
+	/// Path to a JSON file with additional rules, in the same format as the
+	/// built-in `rules.json`.
+	pub file: Option<PathBuf>,
+	/// If positive, how often to check the file for changes, in seconds.
+	/// Changes are then applied without restarting. This is left to the
+	/// caller, e.g. the backend, the library never reloads the file itself.
+	pub reload_interval_secs: u64,
+}
+
+/// The rules applying to one verification, merged from all the matching
+/// entries.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatchedRules {
+	pub skip_catch_all: bool,
+	pub treat_as_catch_all: bool,
+	pub skip_smtp: bool,
+	/// Lower bound of the SMTP timeout, if one is set.
+	pub min_smtp_timeout: Option<Duration>,
+	pub smtp_timeout: Option<Duration>,
+	pub proxy: Option<String>,
+	pub verif_method: Option<ForcedVerifMethod>,
+	pub from_email: Option<String>,
+	pub hello_name: Option<String>,
+}
 
-fn does_domain_have_rule(domain: &str, rule: &Rule) -> bool {
-	if let Some(v) = ALL_RULES.by_domain.get(domain) {
-		return v.rules.contains(rule);
+impl MatchedRules {
+	/// Add the rules of a matching entry. Valued rules already set by a more
+	/// specific entry are kept.
+	fn add(&mut self, rules: &[Rule]) {
+		for rule in rules {
+			match rule {
+				Rule::SkipCatchAll => self.skip_catch_all = true,
+				Rule::TreatAsCatchAll => self.treat_as_catch_all = true,
+				Rule::SkipSmtp => self.skip_smtp = true,
+				Rule::SmtpTimeout45s => {
+					self.min_smtp_timeout = self.min_smtp_timeout.or(Some(Duration::from_secs(45)))
+				}
+				Rule::SmtpTimeoutSecs(secs) => {
+					self.smtp_timeout = self.smtp_timeout.or(Some(Duration::from_secs(*secs)))
+				}
+				Rule::ForceProxy(id) => {
+					self.proxy.get_or_insert_with(|| id.clone());
+				}
+				Rule::ForceVerifMethod(method) => {
+					self.verif_method.get_or_insert(*method);
+				}
+				Rule::FromEmail(email) => {
+					self.from_email.get_or_insert_with(|| email.clone());
+				}
+				Rule::HelloName(name) => {
+					self.hello_name.get_or_insert_with(|| name.clone());
+				}
+			}
+		}
 	}
 
-	false
+	/// Apply the rules to the configuration of the SMTP verification. The
+	/// forced proxy isn't applied, as it must be looked up in the proxies of
+	/// the verification method.
+	pub fn apply_to_smtp_config(&self, config: &mut VerifMethodSmtpConfig) {
+		if let Some(timeout) = self.smtp_timeout {
+			config.smtp_timeout = Some(timeout);
+		}
+		// No timeout at all is already longer than the minimum.
+		if let Some(min) = self.min_smtp_timeout {
+			config.smtp_timeout = config.smtp_timeout.map(|t| t.max(min));
+		}
+		if let Some(from_email) = &self.from_email {
+			config.from_email = from_email.clone();
+		}
+		if let Some(hello_name) = &self.hello_name {
+			config.hello_name = hello_name.clone();
+		}
+	}
 }
 
-fn does_mx_have_rule(host: &str, rule: &Rule) -> bool {
-	if let Some(v) = ALL_RULES.by_mx.get(host) {
-		return v.rules.contains(rule);
+/// The rules currently in use.
+#[derive(Debug, Default)]
+pub struct Rules(AllRules);
+
+impl Rules {
+	/// Get the rules applying to the given domain and MX host.
+	pub fn matching(&self, domain: &str, host: &str) -> MatchedRules {
+		let mut matched = MatchedRules::default();
+		if let Some(v) = self.0.by_domain.get(domain) {
+			matched.add(&v.rules);
+		}
+		if let Some(v) = self.0.by_mx.get(host) {
+			matched.add(&v.rules);
+		}
+		let mut suffixes: Vec<_> = self
+			.0
+			.by_mx_suffix
+			.iter()
+			.filter(|(suffix, _)| host.ends_with(suffix.as_str()))
+			.collect();
+		suffixes.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+		for (_, v) in suffixes {
+			matched.add(&v.rules);
+		}
+
+		matched
 	}
+}
 
-	false
+fn parse_rules(content: &str) -> Result<AllRules, RulesError> {
+	let rules = serde_json::from_str::<AllRules>(content)?;
+	rules.validate()?;
+	Ok(rules)
 }
 
-fn does_mx_suffix_have_rule(host: &str, rule: &Rule) -> bool {
-	for (k, v) in ALL_RULES.by_mx_suffix.iter() {
-		if host.ends_with(k) {
-			return v.rules.contains(rule);
+static RULES: Lazy<RwLock<Arc<Rules>>> = Lazy::new(|| {
+	RwLock::new(Arc::new(Rules(
+		parse_rules(include_str!("rules.json")).expect("Built-in rules are valid. qed."),
+	)))
+});
+
+/// The rules currently used by the email verifications.
+pub fn current_rules() -> Arc<Rules> {
+	RULES
+		.read()
+		.map(|r| r.clone())
+		.unwrap_or_else(|e| e.into_inner().clone())
+}
+
+/// Load the rules of the given configuration, validating the user-supplied
+/// file if any. This doesn't change the rules in use, see [init_rules].
+pub fn load_rules(config: &RulesConfig) -> Result<Rules, RulesError> {
+	let mut rules =
+		parse_rules(include_str!("rules.json")).expect("Built-in rules are valid. qed.");
+	if let Some(path) = &config.file {
+		let content = std::fs::read_to_string(path).map_err(|e| RulesError::Io(path.clone(), e))?;
+		let user_rules = parse_rules(&content)?;
+		if user_rules.replace_defaults {
+			rules = user_rules;
+		} else {
+			rules.extend(user_rules);
 		}
 	}
 
-	false
+	Ok(Rules(rules))
 }
 
-/// Check if either the domain or the MX host has any given rule.
-pub fn has_rule(domain: &str, host: &str, rule: &Rule) -> bool {
-	does_domain_have_rule(domain, rule)
-		|| does_mx_have_rule(host, rule)
-		|| does_mx_suffix_have_rule(host, rule)
+/// Load the rules of the given configuration, and use them for all
+/// subsequent email verifications. This should be called at startup, so
+/// that an invalid rules file is reported early, and again whenever the file
+/// changes.
+pub fn init_rules(config: &RulesConfig) -> Result<(), RulesError> {
+	let rules = Arc::new(load_rules(config)?);
+	match RULES.write() {
+		Ok(mut r) => *r = rules,
+		Err(e) => *e.into_inner() = rules,
+	}
+	Ok(())
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::util::temp_file;
 
 	#[test]
 	fn should_skip_catch_all() {
-		assert!(has_rule(
-			"gmail.com",
-			"alt4.aspmx.l.google.com.",
-			&Rule::SkipCatchAll
-		));
-
-		assert!(has_rule(
-			"domain.com",
-			".antispamcloud.com.",
-			&Rule::SkipCatchAll
-		))
+		let rules = current_rules();
+		assert!(
+			rules
+				.matching("gmail.com", "alt4.aspmx.l.google.com.")
+				.skip_catch_all
+		);
+
+		let matched = rules.matching("domain.com", "mx1.antispamcloud.com.");
+		assert!(matched.skip_catch_all);
+		assert_eq!(matched.min_smtp_timeout, Some(Duration::from_secs(45)));
+	}
+
+	#[test]
+	fn should_prefer_most_specific_rules() {
+		let file = temp_file(
+			r#"{
+				"by_domain": {"example.com": {"rules": [{"SmtpTimeoutSecs": 10}, {"HelloName": "a.com"}]}},
+				"by_mx": {"mx.example.com.": {"rules": [{"SmtpTimeoutSecs": 20}, {"ForceProxy": "proxy1"}]}},
+				"by_mx_suffix": {
+					"example.com.": {"rules": [{"FromEmail": "b@b.com"}, {"ForceProxy": "proxy2"}]},
+					".example.com.": {"rules": [{"FromEmail": "a@a.com"}, "TreatAsCatchAll"]}
+				}
+			}"#,
+		);
+		let rules = load_rules(&RulesConfig {
+			file: Some(file.path().to_path_buf()),
+			..Default::default()
+		})
+		.unwrap();
+		let matched = rules.matching("example.com", "mx.example.com.");
+		assert_eq!(
+			matched,
+			MatchedRules {
+				treat_as_catch_all: true,
+				smtp_timeout: Some(Duration::from_secs(10)),
+				proxy: Some("proxy1".into()),
+				from_email: Some("a@a.com".into()),
+				hello_name: Some("a.com".into()),
+				..Default::default()
+			}
+		);
+
+		let mut config = VerifMethodSmtpConfig::default();
+		matched.apply_to_smtp_config(&mut config);
+		assert_eq!(config.smtp_timeout, Some(Duration::from_secs(10)));
+		assert_eq!(config.from_email, "a@a.com");
+		assert_eq!(config.hello_name, "a.com");
+
+		// The built-in rules are kept.
+		assert!(rules.matching("gmail.com", "").skip_catch_all);
+	}
+
+	#[test]
+	fn should_replace_defaults() {
+		let file = temp_file(
+			r#"{"replace_defaults": true, "by_domain": {"example.com": {"rules": ["SkipSmtp", {"ForceVerifMethod": "smtp"}]}}}"#,
+		);
+		let rules = load_rules(&RulesConfig {
+			file: Some(file.path().to_path_buf()),
+			..Default::default()
+		})
+		.unwrap();
+		let matched = rules.matching("example.com", "");
+		assert!(matched.skip_smtp);
+		assert_eq!(matched.verif_method, Some(ForcedVerifMethod::Smtp));
+		assert!(!rules.matching("gmail.com", "").skip_catch_all);
+	}
+
+	#[test]
+	fn should_reject_invalid_files() {
+		for content in [
+			r#"{"by_domain": {"a.com": {"rules": ["UnknownRule"]}}}"#,
+			r#"{"by_domain": {"a.com": {"rules": [{"SmtpTimeoutSecs": 0}]}}}"#,
+			r#"{"by_mx": {"mx.a.com.": {"rules": [{"FromEmail": "a b@c.com"}]}}}"#,
+			r#"{"by_mx_suffix": {".a.com.": {"rules": [{"ForceVerifMethod": "carrier_pigeon"}]}}}"#,
+		]
+		.iter()
+		{
+			let file = temp_file(content);
+			let config = RulesConfig {
+				file: Some(file.path().to_path_buf()),
+				..Default::default()
+			};
+			assert!(load_rules(&config).is_err(), "{}", content);
+		}
 	}
 }
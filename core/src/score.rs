@@ -0,0 +1,287 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Numeric deliverability score, finer-grained than `is_reachable`.
+//!
+//! Each positive signal of the verification earns points, and each risk
+//! signal costs points. The score is the net number of points, as a
+//! percentage of the maximum number of points, between 0 and 100.
+
+use crate::CheckEmailOutput;
+use serde::{Deserialize, Serialize};
+
+/// Weights of the signals in the deliverability score, in points.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ScoreWeights {
+	/// Points earned if the email is syntactically valid.
+	///
+	/// Defaults to 10.
+	pub valid_syntax: u32,
+	/// Points earned if the domain accepts emails.
+	///
+	/// Defaults to 15.
+	pub accepts_mail: u32,
+	/// Points earned if we could connect to the SMTP server.
+	///
+	/// Defaults to 15.
+	pub can_connect_smtp: u32,
+	/// Points earned if the SMTP server accepts the email.
+	///
+	/// Defaults to 60.
+	pub is_deliverable: u32,
+	/// Points earned instead of `can_connect_smtp` and `is_deliverable` when
+	/// the SMTP verification failed, i.e. the deliverability is unknown.
+	///
+	/// Defaults to 20.
+	pub unknown_deliverability: u32,
+	/// Points lost if the domain is catch-all.
+	///
+	/// Defaults to 30.
+	pub catch_all: u32,
+	/// Points lost if the email is disposable.
+	///
+	/// Defaults to 50.
+	pub disposable: u32,
+	/// Points lost if the email is a role-based account.
+	///
+	/// Defaults to 20.
+	pub role_account: u32,
+	/// Points lost if the inbox is full.
+	///
+	/// Defaults to 40.
+	pub full_inbox: u32,
+	/// Points lost if the email account is disabled.
+	///
+	/// Defaults to 60.
+	pub disabled: u32,
+	/// Points lost if the email is at a free provider, e.g. gmail.com.
+	///
+	/// Defaults to 0.
+	pub b2c: u32,
+	/// Points lost if the SMTP server rejects the email for another reason
+	/// than a full inbox or a disabled account, which have their own weights,
+	/// so that invalid emails score well below the ones whose deliverability
+	/// is unknown.
+	///
+	/// Defaults to 30.
+	pub undeliverable: u32,
+}
+
+impl Default for ScoreWeights {
+	fn default() -> Self {
+		Self {
+			valid_syntax: 10,
+			accepts_mail: 15,
+			can_connect_smtp: 15,
+			is_deliverable: 60,
+			unknown_deliverability: 20,
+			catch_all: 30,
+			disposable: 50,
+			role_account: 20,
+			full_inbox: 40,
+			disabled: 60,
+			b2c: 0,
+			undeliverable: 30,
+		}
+	}
+}
+
+/// Calculate the deliverability score of a verification, between 0 and 100.
+pub fn calculate_score(output: &CheckEmailOutput, weights: &ScoreWeights) -> u8 {
+	let max_points = weights.valid_syntax
+		+ weights.accepts_mail
+		+ (weights.can_connect_smtp + weights.is_deliverable).max(weights.unknown_deliverability);
+	if max_points == 0 {
+		return 0;
+	}
+
+	let mut points = 0;
+	let mut penalty = 0;
+	if output.syntax.is_valid_syntax {
+		points += weights.valid_syntax;
+	}
+	if matches!(&output.mx, Ok(mx) if mx.accepts_mail()) {
+		points += weights.accepts_mail;
+	}
+	if let Ok(misc) = &output.misc {
+		if misc.is_disposable {
+			penalty += weights.disposable;
+		}
+		if misc.is_role_account {
+			penalty += weights.role_account;
+		}
+		if misc.is_b2c {
+			penalty += weights.b2c;
+		}
+	}
+	match &output.smtp {
+		Ok(smtp) => {
+			if smtp.can_connect_smtp {
+				points += weights.can_connect_smtp;
+			}
+			if smtp.is_deliverable {
+				points += weights.is_deliverable;
+			}
+			if smtp.is_catch_all {
+				penalty += weights.catch_all;
+			}
+			if smtp.has_full_inbox {
+				penalty += weights.full_inbox;
+			} else if smtp.is_disabled {
+				penalty += weights.disabled;
+			} else if !smtp.is_deliverable {
+				penalty += weights.undeliverable;
+			}
+		}
+		Err(_) => points += weights.unknown_deliverability,
+	}
+
+	let score = 100 * points.saturating_sub(penalty) / max_points;
+	score.min(100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::misc::MiscDetails;
+	use crate::mx::{MxDetails, MxKind};
+	use crate::smtp::{SmtpDetails, SmtpError};
+	use crate::syntax::SyntaxDetails;
+
+	fn output(misc: MiscDetails, smtp: Result<SmtpDetails, SmtpError>) -> CheckEmailOutput {
+		CheckEmailOutput {
+			misc: Ok(misc),
+			mx: Ok(MxDetails {
+				kind: MxKind::Explicit,
+				..Default::default()
+			}),
+			smtp,
+			syntax: SyntaxDetails {
+				is_valid_syntax: true,
+				..Default::default()
+			},
+			..Default::default()
+		}
+	}
+
+	fn deliverable() -> SmtpDetails {
+		SmtpDetails {
+			can_connect_smtp: true,
+			is_deliverable: true,
+			..Default::default()
+		}
+	}
+
+	fn rejected() -> SmtpDetails {
+		SmtpDetails {
+			can_connect_smtp: true,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn should_score_risky_signals_differently() {
+		let weights = ScoreWeights::default();
+
+		let safe = output(MiscDetails::default(), Ok(deliverable()));
+		assert_eq!(calculate_score(&safe, &weights), 100);
+
+		let catch_all = output(
+			MiscDetails::default(),
+			Ok(SmtpDetails {
+				is_catch_all: true,
+				..deliverable()
+			}),
+		);
+		assert_eq!(calculate_score(&catch_all, &weights), 70);
+
+		let disposable = output(
+			MiscDetails {
+				is_disposable: true,
+				..Default::default()
+			},
+			Ok(deliverable()),
+		);
+		assert_eq!(calculate_score(&disposable, &weights), 50);
+
+		let unknown = output(MiscDetails::default(), Err(SmtpError::SkippedByRule));
+		assert_eq!(calculate_score(&unknown, &weights), 45);
+
+		assert_eq!(calculate_score(&CheckEmailOutput::default(), &weights), 0);
+	}
+
+	#[test]
+	fn should_score_invalid_below_unknown() {
+		let weights = ScoreWeights::default();
+		let score = |smtp| calculate_score(&output(MiscDetails::default(), smtp), &weights);
+
+		let safe = score(Ok(deliverable()));
+		let catch_all = score(Ok(SmtpDetails {
+			is_catch_all: true,
+			..deliverable()
+		}));
+		let unknown = score(Err(SmtpError::SkippedByRule));
+		let invalid: Vec<u8> = vec![
+			SmtpDetails {
+				has_full_inbox: true,
+				..rejected()
+			},
+			SmtpDetails {
+				is_disabled: true,
+				..rejected()
+			},
+			rejected(),
+		]
+		.into_iter()
+		.map(|smtp| score(Ok(smtp)))
+		.collect();
+		assert!(
+			invalid.iter().all(|invalid| *invalid < unknown),
+			"{:?}",
+			invalid
+		);
+		assert!(unknown < catch_all);
+		assert!(catch_all < safe);
+	}
+
+	#[test]
+	fn should_not_stack_undeliverable_weight() {
+		let weights = ScoreWeights {
+			full_inbox: 10,
+			disabled: 20,
+			..Default::default()
+		};
+		let score = |smtp| calculate_score(&output(MiscDetails::default(), smtp), &weights);
+
+		// 40 points, minus the most specific weight only.
+		assert_eq!(score(Ok(rejected())), 10);
+		assert_eq!(
+			score(Ok(SmtpDetails {
+				has_full_inbox: true,
+				..rejected()
+			})),
+			30
+		);
+		assert_eq!(
+			score(Ok(SmtpDetails {
+				is_disabled: true,
+				..rejected()
+			})),
+			20
+		);
+	}
+}
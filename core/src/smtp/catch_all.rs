@@ -0,0 +1,135 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Cache of the catch-all probes by domain.
+//!
+//! To find out whether a domain is catch-all, we probe a random address at
+//! this domain, which doubles the `RCPT TO` traffic and can tip off
+//! anti-abuse systems. As a domain rarely changes its catch-all setup, the
+//! result of the probe is cached for a while, and the next verifications at
+//! the same domain skip the probe.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Configuration of the catch-all cache.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct CatchAllCacheConfig {
+	/// Whether to cache the catch-all probes.
+	///
+	/// Defaults to false.
+	pub enabled: bool,
+	/// How long the result of a probe is reused, in seconds.
+	///
+	/// Defaults to 86400, i.e. one day.
+	pub ttl_secs: u64,
+}
+
+impl Default for CatchAllCacheConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			ttl_secs: 86400,
+		}
+	}
+}
+
+impl CatchAllCacheConfig {
+	pub fn ttl(&self) -> Duration {
+		Duration::from_secs(self.ttl_secs)
+	}
+}
+
+/// Results of the catch-all probes by domain, shared across verifications.
+/// Implementations handle their own errors: a failing cache should behave
+/// as an empty one.
+#[async_trait]
+pub trait CatchAllCache: Debug + Send + Sync {
+	/// Whether the domain is catch-all, if it was probed recently enough.
+	async fn get(&self, domain: &str) -> Option<bool>;
+
+	/// Record the result of the catch-all probe of the domain.
+	async fn insert(&self, domain: &str, is_catch_all: bool);
+}
+
+/// In-memory [CatchAllCache].
+#[derive(Debug)]
+pub struct InMemoryCatchAllCache {
+	ttl: Duration,
+	domains: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl InMemoryCatchAllCache {
+	pub fn new(ttl: Duration) -> Self {
+		Self {
+			ttl,
+			domains: Mutex::new(HashMap::new()),
+		}
+	}
+}
+
+impl Default for InMemoryCatchAllCache {
+	fn default() -> Self {
+		Self::new(CatchAllCacheConfig::default().ttl())
+	}
+}
+
+#[async_trait]
+impl CatchAllCache for InMemoryCatchAllCache {
+	async fn get(&self, domain: &str) -> Option<bool> {
+		let mut domains = self.domains.lock().ok()?;
+		match domains.get(domain) {
+			Some((is_catch_all, probed_at)) if probed_at.elapsed() < self.ttl => {
+				Some(*is_catch_all)
+			}
+			Some(_) => {
+				domains.remove(domain);
+				None
+			}
+			None => None,
+		}
+	}
+
+	async fn insert(&self, domain: &str, is_catch_all: bool) {
+		if let Ok(mut domains) = self.domains.lock() {
+			domains.insert(domain.to_string(), (is_catch_all, Instant::now()));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn should_expire_entries() {
+		let cache = InMemoryCatchAllCache::new(Duration::from_secs(60));
+		assert_eq!(cache.get("example.com").await, None);
+		cache.insert("example.com", true).await;
+		cache.insert("example.org", false).await;
+		assert_eq!(cache.get("example.com").await, Some(true));
+		assert_eq!(cache.get("example.org").await, Some(false));
+
+		let cache = InMemoryCatchAllCache::new(Duration::ZERO);
+		cache.insert("example.com", true).await;
+		assert_eq!(cache.get("example.com").await, None);
+	}
+}
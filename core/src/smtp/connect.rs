@@ -14,29 +14,53 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use async_recursion::async_recursion;
-use async_smtp::commands::{MailCommand, RcptCommand};
-use async_smtp::extension::ClientId;
-use async_smtp::{SmtpClient, SmtpTransport};
+use async_smtp::commands::{MailCommand, QuitCommand, RsetCommand, StarttlsCommand};
+use async_smtp::extension::{ClientId, MailParameter};
+use async_smtp::EmailAddress as AsyncSmtpEmailAddress;
 use fast_socks5::client::Config;
 use fast_socks5::{client::Socks5Stream, Result};
 use rand::rngs::SmallRng;
 use rand::{distributions::Alphanumeric, Rng, SeedableRng};
+use std::fmt::{self, Display};
 use std::iter;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufStream};
 use tokio::net::TcpStream;
 
-use super::parser;
-use super::verif_method::VerifMethodSmtp;
-use super::{SmtpDetails, SmtpError};
-use crate::rules::{has_rule, Rule};
-use crate::{EmailAddress, LOG_TARGET};
+use super::catch_all::CatchAllCache;
+use super::greylisting::{GreylistingConfig, SmtpDebugRetries};
+use super::parser::{self, SmtpReplyOutcome};
+use super::session::SmtpSession;
+use super::session_pool::{OpenSession, SessionKey, SmtpSessionPool};
+use super::tls;
+use super::transcript::{SmtpTranscript, SmtpTranscriptEvent, TranscriptStream};
+use super::verif_method::{StartTlsMode, VerifMethodSmtp};
+use super::{SmtpDetails, SmtpError, SmtpServerDetails, SmtpTlsDetails};
+use crate::rules::current_rules;
+use crate::{EmailAddress, StageTimings, LOG_TARGET};
 
 // Define a new trait that combines AsyncRead, AsyncWrite, and Unpin
-trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+pub(super) trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
 
+/// The SMTP stream we use to talk to the server, possibly recording the
+/// conversation into a transcript.
+pub(super) type SmtpConnection = SmtpSession<BufStream<TranscriptStream<Box<dyn AsyncReadWrite>>>>;
+
+/// State shared with other verifications, e.g. those of a bulk job or of a
+/// [check_emails](crate::check_emails) call.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct SmtpShared<'a> {
+	/// Idle SMTP sessions to reuse.
+	pub(super) session_pool: Option<&'a SmtpSessionPool>,
+	/// Where to record the results of the catch-all probes.
+	pub(super) catch_all_cache: Option<&'a dyn CatchAllCache>,
+	/// Whether the domain is catch-all, if already known, e.g. from the
+	/// catch-all cache. The probe is then skipped.
+	pub(super) known_catch_all: Option<bool>,
+}
+
 /// Try to send an smtp command, close and return Err if fails.
 macro_rules! try_smtp (
     ($res: expr, $client: ident, $to_email: expr, $host: expr, $port: expr) => ({
@@ -50,29 +74,42 @@ macro_rules! try_smtp (
 				"Closing connection due to error"
 			);
 			// Try to close the connection, but ignore if there's an error.
-			let _ = $client.quit().await;
+			let _ = $client.command(QuitCommand).await;
 
 			return Err(SmtpError::AsyncSmtpError(err));
 		}
     })
 );
 
-/// Connect to an SMTP host and return the configured client transport.
+/// Connect to an SMTP host, read its greeting, introduce ourselves with
+/// EHLO, optionally upgrade the session to TLS with STARTTLS, and return the
+/// connection along with what we learnt about the server. If a transcript is
+/// given, the whole SMTP conversation is recorded into it. The time spent
+/// connecting and in the handshake is added to `timings`.
+///
+/// No mail transaction is started yet, see [start_transaction].
 async fn connect_to_smtp_host(
 	to_email: &EmailAddress,
 	mx_host: &str,
 	verif_method: &VerifMethodSmtp,
-) -> Result<SmtpTransport<BufStream<Box<dyn AsyncReadWrite>>>, SmtpError> {
+	transcript: Option<&SmtpTranscript>,
+	starttls: StartTlsMode,
+	timings: &mut StageTimings,
+) -> Result<(SmtpConnection, SmtpServerDetails, SmtpTlsDetails), SmtpError> {
 	// hostname verification fails if it ends with '.', for example, using
 	// SOCKS5 proxies we can `io: incomplete` error.
 	let clean_host = mx_host.trim_end_matches('.').to_string();
-	let smtp_client = SmtpClient::new()
-		.hello_name(ClientId::Domain(verif_method.config.hello_name.to_string()))
-		// Sometimes, using socks5 proxy, we get an `io: incomplete` error
-		// when using pipelining and sending two consecutive RCPT TO commands.
-		.pipelining(false);
+	let hello_name = ClientId::Domain(verif_method.config.hello_name.to_string());
+
+	if let Some(transcript) = transcript {
+		transcript.push(SmtpTranscriptEvent::Connect {
+			host: clean_host.clone(),
+			port: verif_method.config.smtp_port,
+		});
+	}
 
-	let stream: BufStream<Box<dyn AsyncReadWrite>> = match &verif_method.proxy {
+	let connect_timer = StageTimer::start(&mut timings.proxy_connect);
+	let stream: Box<dyn AsyncReadWrite> = match &verif_method.proxy {
 		Some(proxy) => {
 			let mut config = Config::default();
 			if let Some(timeout_ms) = proxy.timeout_ms {
@@ -99,39 +136,289 @@ async fn connect_to_smtp_host(
 					)
 					.await?
 				};
-			BufStream::new(Box::new(socks_stream) as Box<dyn AsyncReadWrite>)
+			Box::new(socks_stream)
 		}
 		None => {
 			let tcp_stream =
 				TcpStream::connect(format!("{}:{}", clean_host, verif_method.config.smtp_port))
 					.await?;
-			BufStream::new(Box::new(tcp_stream) as Box<dyn AsyncReadWrite>)
+			Box::new(tcp_stream)
 		}
 	};
+	drop(connect_timer);
+	let handshake_timer = StageTimer::start(&mut timings.smtp_handshake);
+	let mut smtp_stream = SmtpSession::new(BufStream::new(TranscriptStream::new(
+		stream,
+		transcript.cloned(),
+	)));
+
+	// Read the server's greeting, and introduce ourselves.
+	let greeting = smtp_stream.read_response().await?;
+	let ehlo_response = smtp_stream.ehlo(hello_name.clone()).await?;
+	let mut server = SmtpServerDetails::new(&greeting, &ehlo_response);
 
-	let mut smtp_transport = SmtpTransport::new(smtp_client, stream).await?;
+	let mut tls = SmtpTlsDetails {
+		starttls_offered: server.extensions.starttls,
+		..Default::default()
+	};
+
+	match starttls {
+		StartTlsMode::Disabled => {}
+		StartTlsMode::Required if !tls.starttls_offered => {
+			let _ = smtp_stream.command(QuitCommand).await;
+			return Err(SmtpError::TlsError(
+				"Server does not offer STARTTLS".to_string(),
+			));
+		}
+		StartTlsMode::Opportunistic | StartTlsMode::Required if tls.starttls_offered => {
+			let res = smtp_stream.command(StarttlsCommand).await;
+			if let (Err(err), StartTlsMode::Required) = (&res, starttls) {
+				let err = SmtpError::TlsError(format!("Server rejected STARTTLS: {}", err));
+				let _ = smtp_stream.command(QuitCommand).await;
+				return Err(err);
+			}
+			try_smtp!(
+				res,
+				smtp_stream,
+				to_email,
+				clean_host,
+				verif_method.config.smtp_port
+			);
+
+			// Anything buffered after the STARTTLS reply is discarded, as
+			// required by RFC 3207.
+			let (stream, transcript) = smtp_stream.into_inner().into_inner().into_parts();
+			match tls::upgrade(stream, &clean_host).await {
+				Ok((tls_stream, info)) => {
+					tls.negotiated = true;
+					tls.protocol_version = info.protocol_version;
+					tls.cert_valid = Some(info.cert_valid);
+					tls.error = info.cert_error;
+
+					smtp_stream = SmtpSession::new(BufStream::new(TranscriptStream::new(
+						Box::new(tls_stream) as Box<dyn AsyncReadWrite>,
+						transcript,
+					)));
+					// As per RFC 3207, we must introduce ourselves again. The
+					// server may advertise different extensions over TLS.
+					let ehlo_response = smtp_stream.ehlo(hello_name).await?;
+					server = SmtpServerDetails::new(&greeting, &ehlo_response);
+					server.extensions.starttls = true;
+				}
+				Err(err) if starttls == StartTlsMode::Required => {
+					return Err(SmtpError::TlsError(err.to_string()));
+				}
+				Err(err) => {
+					tracing::debug!(
+						target: LOG_TARGET,
+						email=to_email.to_string(),
+						mx_host=clean_host,
+						error=?err,
+						"TLS handshake failed, reconnecting without STARTTLS"
+					);
+					drop(handshake_timer);
+					let (smtp_stream, server, _) = Box::pin(connect_to_smtp_host(
+						to_email,
+						mx_host,
+						verif_method,
+						transcript.as_ref(),
+						StartTlsMode::Disabled,
+						timings,
+					))
+					.await?;
+					return Ok((
+						smtp_stream,
+						server,
+						SmtpTlsDetails {
+							error: Some(err.to_string()),
+							..tls
+						},
+					));
+				}
+			}
+		}
+		StartTlsMode::Opportunistic | StartTlsMode::Required => {}
+	}
+	drop(handshake_timer);
+
+	Ok((smtp_stream, server, tls))
+}
+
+/// Start a mail transaction with the MAIL FROM command, to verify
+/// `to_email` next.
+async fn start_transaction(
+	smtp_stream: &mut SmtpConnection,
+	server: &SmtpServerDetails,
+	to_email: &EmailAddress,
+	mx_host: &str,
+	verif_method: &VerifMethodSmtp,
+) -> Result<(), SmtpError> {
+	let clean_host = mx_host.trim_end_matches('.');
+
+	// An internationalized address can only be sent to a server supporting
+	// SMTPUTF8, and the MAIL command must then carry the SMTPUTF8 parameter
+	// (RFC 6531 section 3.4).
+	let mail_parameters = if to_email.is_international() {
+		if !server.extensions.smtputf8 {
+			let _ = smtp_stream.command(QuitCommand).await;
+			return Err(SmtpError::EaiUnsupported);
+		}
+		vec![MailParameter::SmtpUtfEight]
+	} else {
+		vec![]
+	};
 
 	// Set "MAIL FROM"
-	let from_email = EmailAddress::from_str(&verif_method.config.from_email).unwrap_or_else(|_| {
-		tracing::warn!(
-			target: LOG_TARGET,
-			from_email=verif_method.config.from_email,
-			"Invalid 'from_email' provided, using default: 'user@example.org'"
-		);
-		EmailAddress::from_str("user@example.org").expect("Default email is valid")
-	});
+	let from_email = AsyncSmtpEmailAddress::from_str(&verif_method.config.from_email)
+		.unwrap_or_else(|_| {
+			tracing::warn!(
+				target: LOG_TARGET,
+				from_email=verif_method.config.from_email,
+				"Invalid 'from_email' provided, using default: 'user@example.org'"
+			);
+			AsyncSmtpEmailAddress::from_str("user@example.org").expect("Default email is valid")
+		});
 	try_smtp!(
-		smtp_transport
-			.get_mut()
-			.command(MailCommand::new(Some(from_email.into_inner()), vec![]))
+		smtp_stream
+			.command(MailCommand::new(Some(from_email), mail_parameters))
 			.await,
-		smtp_transport,
+		smtp_stream,
 		to_email,
 		clean_host,
 		verif_method.config.smtp_port
 	);
 
-	Ok(smtp_transport)
+	Ok(())
+}
+
+/// Connect to the SMTP host, and start a mail transaction.
+async fn open_session(
+	to_email: &EmailAddress,
+	mx_host: &str,
+	verif_method: &VerifMethodSmtp,
+	transcript: Option<&SmtpTranscript>,
+	timings: &mut StageTimings,
+) -> Result<OpenSession, SmtpError> {
+	let (smtp_stream, server, tls) = connect_to_smtp_host(
+		to_email,
+		mx_host,
+		verif_method,
+		transcript,
+		verif_method.config.starttls,
+		timings,
+	)
+	.await?;
+	let mut session = OpenSession::new(smtp_stream, server, tls);
+	start_transaction(
+		&mut session.conn,
+		&session.server,
+		to_email,
+		mx_host,
+		verif_method,
+	)
+	.await?;
+
+	Ok(session)
+}
+
+/// Take an idle session from the pool, and start a mail transaction on it.
+/// Returns None if there's no idle session for this MX host, or if the
+/// server doesn't accept a new transaction on it, e.g. because it closed the
+/// connection in the meantime.
+async fn reuse_session(
+	pool: &SmtpSessionPool,
+	key: &SessionKey,
+	to_email: &EmailAddress,
+	mx_host: &str,
+	verif_method: &VerifMethodSmtp,
+	transcript: Option<&SmtpTranscript>,
+) -> Option<OpenSession> {
+	let mut session = pool.checkout(key)?;
+	session
+		.conn
+		.get_mut()
+		.get_mut()
+		.set_transcript(transcript.cloned());
+	if let Some(transcript) = transcript {
+		transcript.push(SmtpTranscriptEvent::Reuse {
+			host: mx_host.trim_end_matches('.').to_string(),
+			port: verif_method.config.smtp_port,
+		});
+	}
+
+	match start_transaction(
+		&mut session.conn,
+		&session.server,
+		to_email,
+		mx_host,
+		verif_method,
+	)
+	.await
+	{
+		Ok(()) => Some(session),
+		Err(err) => {
+			tracing::debug!(
+				target: LOG_TARGET,
+				email=to_email.to_string(),
+				mx_host=mx_host,
+				error=?err,
+				"Could not reuse SMTP session, opening a new one"
+			);
+			None
+		}
+	}
+}
+
+/// End the session after a verification: either reset it with RSET and give
+/// it back to the pool for the next verification, or close it. The sessions
+/// of the pool idle for too long are closed in the background.
+async fn release_session(
+	mut session: OpenSession,
+	pool: Option<(&SmtpSessionPool, SessionKey)>,
+) -> Result<(), SmtpError> {
+	session.recipients += 1;
+
+	if let Some((pool, _)) = pool {
+		for mut expired in pool.take_expired() {
+			tokio::spawn(async move {
+				let _ = tokio::time::timeout(
+					Duration::from_secs(10),
+					expired.conn.command(QuitCommand),
+				)
+				.await;
+			});
+		}
+	}
+
+	if let Some((pool, key)) = pool.filter(|(pool, _)| pool.accepts(&session)) {
+		if session.conn.command(RsetCommand).await.is_err() {
+			// The session can't be reused, and is probably closed already.
+			return Ok(());
+		}
+		session.conn.get_mut().get_mut().set_transcript(None);
+		match pool.checkin(key, session) {
+			None => return Ok(()),
+			Some(rejected) => session = rejected,
+		}
+	}
+
+	session
+		.conn
+		.command(QuitCommand)
+		.await
+		.map_err(SmtpError::AsyncSmtpError)?;
+
+	Ok(())
+}
+
+/// The RCPT command. Unlike async-smtp's `RcptCommand`, it accepts
+/// internationalized email addresses.
+struct RcptCommand<'a>(&'a EmailAddress);
+
+impl Display for RcptCommand<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "RCPT TO:<{}>\r\n", self.0)
+	}
 }
 
 /// Description of the deliverability information we can gather from
@@ -145,16 +432,12 @@ struct Deliverability {
 	is_disabled: bool,
 }
 
-/// Checks deliverability of a target email address using the provided SMTP transport.
+/// Checks deliverability of a target email address using the provided SMTP stream.
 async fn check_email_deliverability<S: AsyncBufRead + AsyncWrite + Unpin + Send>(
-	smtp_transport: &mut SmtpTransport<S>,
+	smtp_stream: &mut SmtpSession<S>,
 	to_email: &EmailAddress,
 ) -> Result<Deliverability, SmtpError> {
-	match smtp_transport
-		.get_mut()
-		.command(RcptCommand::new(to_email.clone().into_inner(), vec![]))
-		.await
-	{
+	match smtp_stream.command(RcptCommand(to_email)).await {
 		// According to RFC 5321, `RCPT TO` command succeeds with 250 and
 		// 251 codes only (no 3xx codes at all):
 		// https://tools.ietf.org/html/rfc5321#page-56
@@ -171,65 +454,53 @@ async fn check_email_deliverability<S: AsyncBufRead + AsyncWrite + Unpin + Send>
 			is_deliverable: true, // response.is_positive()
 			is_disabled: false,
 		}),
-		Err(err) => {
-			// We cast to lowercase, because our matched strings below are all
-			// lowercase.
-			let err_string = err.to_string().to_lowercase();
-
-			// Check if the email account has been disabled or blocked.
-			if parser::is_disabled_account(&err_string) {
-				return Ok(Deliverability {
-					has_full_inbox: false,
-					is_deliverable: false,
-					is_disabled: true,
-				});
-			}
-
-			// Check if the email account has a full inbox.
-			if parser::is_full_inbox(&err_string) {
-				return Ok(Deliverability {
-					has_full_inbox: true,
-					is_deliverable: false,
-					is_disabled: false,
-				});
-			}
-
-			// Check error messages that say that user can actually receive
-			// emails.
-			// 4.2.1 The user you are trying to contact is receiving mail at a rate that
-			if err_string
-				.contains("the user you are trying to contact is receiving mail at a rate that")
-			{
-				return Ok(Deliverability {
-					has_full_inbox: false,
-					is_deliverable: true,
-					is_disabled: false,
-				});
-			}
-
-			// Check that the mailbox doesn't exist.
-			if parser::is_invalid(&err_string, to_email) {
-				return Ok(Deliverability {
-					has_full_inbox: false,
-					is_deliverable: false,
-					is_disabled: false,
-				});
-			}
-
-			// Return all unparsable errors,.
-			Err(SmtpError::AsyncSmtpError(err))
-		}
+		Err(err) => match parser::classify_error(&err) {
+			Some(SmtpReplyOutcome::MailboxDisabled) => Ok(Deliverability {
+				has_full_inbox: false,
+				is_deliverable: false,
+				is_disabled: true,
+			}),
+			Some(SmtpReplyOutcome::MailboxFull) => Ok(Deliverability {
+				has_full_inbox: true,
+				is_deliverable: false,
+				is_disabled: false,
+			}),
+			// The mailbox exists, it's just receiving too many emails.
+			Some(SmtpReplyOutcome::RecipientRateLimited) => Ok(Deliverability {
+				has_full_inbox: false,
+				is_deliverable: true,
+				is_disabled: false,
+			}),
+			Some(SmtpReplyOutcome::MailboxUnknown) => Ok(Deliverability {
+				has_full_inbox: false,
+				is_deliverable: false,
+				is_disabled: false,
+			}),
+			// Return all the other errors, they don't tell us anything about
+			// the mailbox.
+			_ => Err(SmtpError::AsyncSmtpError(err)),
+		},
 	}
 }
 
 /// Checks if the domain has a catch-all email setup.
 async fn smtp_is_catch_all<S: AsyncBufRead + AsyncWrite + Unpin + Send>(
-	smtp_transport: &mut SmtpTransport<S>,
+	smtp_stream: &mut SmtpSession<S>,
 	domain: &str,
 	host: &str,
 	to_email: &EmailAddress,
 ) -> Result<bool, SmtpError> {
-	if has_rule(domain, host, &Rule::SkipCatchAll) {
+	let rules = current_rules().matching(domain, host);
+	if rules.treat_as_catch_all {
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=to_email.to_string(),
+			domain=domain,
+			"Treating domain as catch-all by rule"
+		);
+		return Ok(true);
+	}
+	if rules.skip_catch_all {
 		tracing::debug!(
 			target: LOG_TARGET,
 			email=to_email.to_string(),
@@ -247,25 +518,90 @@ async fn smtp_is_catch_all<S: AsyncBufRead + AsyncWrite + Unpin + Send>(
 		.collect();
 	let random_email = EmailAddress::new(format!("{}@{}", random_email, domain))?;
 
-	check_email_deliverability(smtp_transport, &random_email)
+	check_email_deliverability(smtp_stream, &random_email)
 		.await
 		.map(|result| result.is_deliverable)
 }
 
-/// Creates an SMTP future for email verification.
+/// Adds the time elapsed since it started to a stage of `timings` when
+/// dropped, so that a stage interrupted by the SMTP timeout is recorded too.
+struct StageTimer<'a> {
+	stage: &'a mut Option<Duration>,
+	start: Instant,
+}
+
+impl<'a> StageTimer<'a> {
+	fn start(stage: &'a mut Option<Duration>) -> Self {
+		Self {
+			stage,
+			start: Instant::now(),
+		}
+	}
+}
+
+impl Drop for StageTimer<'_> {
+	fn drop(&mut self) {
+		StageTimings::record(self.stage, self.start);
+	}
+}
+
+/// Await `fut`, adding the time it took to a stage of `timings`, even if
+/// `fut` is dropped before it completes.
+async fn timed<T>(stage: &mut Option<Duration>, fut: impl std::future::Future<Output = T>) -> T {
+	let _timer = StageTimer::start(stage);
+	fut.await
+}
+
+/// Creates an SMTP future for email verification. If a session pool is
+/// shared, an idle session to the MX host is reused if possible, and the
+/// session is given back to the pool afterwards.
 async fn create_smtp_future(
 	to_email: &EmailAddress,
 	mx_host: &str,
 	domain: &str,
 	verif_method: &VerifMethodSmtp,
-) -> Result<(bool, Deliverability), SmtpError> {
+	transcript: Option<&SmtpTranscript>,
+	shared: SmtpShared<'_>,
+	timings: &mut StageTimings,
+) -> Result<SmtpDetails, SmtpError> {
+	let key = SessionKey::new(mx_host, verif_method);
+	let reused = match shared.session_pool {
+		Some(pool) => reuse_session(pool, &key, to_email, mx_host, verif_method, transcript).await,
+		None => None,
+	};
 	// FIXME If the SMTP is not connectable, we should actually return an
 	// Ok(SmtpDetails { can_connect_smtp: false, ... }).
-	let mut smtp_transport = connect_to_smtp_host(to_email, mx_host, verif_method).await?;
+	let mut session = match reused {
+		Some(session) => session,
+		None => open_session(to_email, mx_host, verif_method, transcript, timings).await?,
+	};
 
-	let is_catch_all = smtp_is_catch_all(&mut smtp_transport, domain, mx_host, to_email)
+	// The catch-all probe is only made once per domain and pool, and skipped
+	// if the result is already known.
+	let known_catch_all = shared
+		.session_pool
+		.and_then(|pool| pool.get_catch_all(domain))
+		.or(shared.known_catch_all);
+	let is_catch_all = match known_catch_all {
+		Some(is_catch_all) => is_catch_all,
+		None => match timed(
+			&mut timings.catch_all_rcpt,
+			smtp_is_catch_all(&mut session.conn, domain, mx_host, to_email),
+		)
 		.await
-		.unwrap_or(false);
+		{
+			Ok(is_catch_all) => {
+				if let Some(pool) = shared.session_pool {
+					pool.set_catch_all(domain, is_catch_all);
+				}
+				if let Some(cache) = shared.catch_all_cache {
+					cache.insert(domain, is_catch_all).await;
+				}
+				is_catch_all
+			}
+			Err(_) => false,
+		},
+	};
 	let deliverability = if is_catch_all {
 		Deliverability {
 			has_full_inbox: false,
@@ -273,13 +609,17 @@ async fn create_smtp_future(
 			is_disabled: false,
 		}
 	} else {
-		let mut result = check_email_deliverability(&mut smtp_transport, to_email).await;
+		let mut result = timed(
+			&mut timings.rcpt,
+			check_email_deliverability(&mut session.conn, to_email),
+		)
+		.await;
 
 		// Some SMTP servers automatically close the connection after an error,
 		// so we should reconnect to perform a next command.
 		//
-		// Unfortunately `smtp_transport.is_connected()` doesn't report about this,
-		// so we can only check for "io: incomplete" SMTP error being returned.
+		// Unfortunately we can't detect that the connection was closed, so we
+		// can only check for "io: incomplete" SMTP error being returned.
 		// https://github.com/async-email/async-smtp/issues/37
 		if let Err(e) = &result {
 			if parser::is_err_io_errors(e) {
@@ -290,21 +630,32 @@ async fn create_smtp_future(
 					"Got `io: incomplete` error, reconnecting"
 				);
 
-				let _ = smtp_transport.quit().await;
-				smtp_transport = connect_to_smtp_host(to_email, mx_host, verif_method).await?;
-				result = check_email_deliverability(&mut smtp_transport, to_email).await;
+				let _ = session.conn.command(QuitCommand).await;
+				session =
+					open_session(to_email, mx_host, verif_method, transcript, timings).await?;
+				result = timed(
+					&mut timings.rcpt,
+					check_email_deliverability(&mut session.conn, to_email),
+				)
+				.await;
 			}
 		}
 
 		result?
 	};
 
-	smtp_transport
-		.quit()
-		.await
-		.map_err(SmtpError::AsyncSmtpError)?;
+	let details = SmtpDetails {
+		can_connect_smtp: true,
+		has_full_inbox: deliverability.has_full_inbox,
+		is_catch_all,
+		is_deliverable: deliverability.is_deliverable,
+		is_disabled: deliverability.is_disabled,
+		tls: session.tls.clone(),
+		server: session.server.clone(),
+	};
+	release_session(session, shared.session_pool.map(|pool| (pool, key))).await?;
 
-	Ok((is_catch_all, deliverability))
+	Ok(details)
 }
 
 /// Get all email details we can from one single `EmailAddress`, without
@@ -314,83 +665,788 @@ async fn check_smtp_without_retry(
 	mx_host: &str,
 	domain: &str,
 	verif_method: &VerifMethodSmtp,
+	transcript: Option<&SmtpTranscript>,
+	shared: SmtpShared<'_>,
+	timings: &mut StageTimings,
 ) -> Result<SmtpDetails, SmtpError> {
-	let fut = create_smtp_future(to_email, mx_host, domain, verif_method);
+	let fut = create_smtp_future(
+		to_email,
+		mx_host,
+		domain,
+		verif_method,
+		transcript,
+		shared,
+		timings,
+	);
 
-	let (is_catch_all, deliverability) = match verif_method.config.smtp_timeout {
+	match verif_method.config.smtp_timeout {
 		Some(smtp_timeout) => {
 			let timeout = tokio::time::timeout(smtp_timeout, fut);
 
 			match timeout.await {
-				Ok(result) => result?,
-				Err(_) => return Err(SmtpError::Timeout(smtp_timeout)),
+				Ok(result) => result,
+				Err(_) => Err(SmtpError::Timeout(smtp_timeout)),
 			}
 		}
-		None => fut.await?,
-	};
-
-	Ok(SmtpDetails {
-		can_connect_smtp: true,
-		has_full_inbox: deliverability.has_full_inbox,
-		is_catch_all,
-		is_deliverable: deliverability.is_deliverable,
-		is_disabled: deliverability.is_disabled,
-	})
+		None => fut.await,
+	}
 }
 
 /// Get all email details we can from one single `EmailAddress`.
-/// Retry the SMTP connection on error, in particular to avoid greylisting.
-#[async_recursion]
+/// Retry the SMTP connection on unknown errors, and after waiting if the
+/// server greylisted us, see [GreylistingConfig].
+#[allow(clippy::too_many_arguments)]
 pub async fn check_smtp_with_retry(
 	to_email: &EmailAddress,
 	mx_host: &str,
 	domain: &str,
 	verif_method: &VerifMethodSmtp,
-	// Number of remaining retries.
-	count: usize,
-) -> Result<SmtpDetails, SmtpError> {
-	tracing::debug!(
-		target: LOG_TARGET,
-		email=to_email.to_string(),
-		attempt=verif_method.config.retries - count + 1,
-		mx_host=mx_host,
-		port=verif_method.config.smtp_port,
-		using_proxy=verif_method.proxy.is_some(),
-		"Check SMTP"
-	);
+	transcript: Option<&SmtpTranscript>,
+	greylisting: &GreylistingConfig,
+	shared: SmtpShared<'_>,
+	timings: &mut StageTimings,
+) -> (Result<SmtpDetails, SmtpError>, SmtpDebugRetries) {
+	// Total number of SMTP connections to make.
+	let max_attempts = verif_method.config.retries.max(1);
+	let mut retries = SmtpDebugRetries::default();
 
-	let result = check_smtp_without_retry(to_email, mx_host, domain, verif_method).await;
+	loop {
+		retries.attempts += 1;
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=to_email.to_string(),
+			attempt=retries.attempts,
+			mx_host=mx_host,
+			port=verif_method.config.smtp_port,
+			using_proxy=verif_method.proxy.is_some(),
+			"Check SMTP"
+		);
 
-	tracing::debug!(
-		target: LOG_TARGET,
-		email=to_email.to_string(),
-		attempt=verif_method.config.retries - count + 1,
-		mx_host=mx_host,
-		port=verif_method.config.smtp_port,
-		result=?result,
-		"Got SMTP check result"
-	);
+		let result = check_smtp_without_retry(
+			to_email,
+			mx_host,
+			domain,
+			verif_method,
+			transcript,
+			shared,
+			timings,
+		)
+		.await;
+
+		tracing::debug!(
+			target: LOG_TARGET,
+			email=to_email.to_string(),
+			attempt=retries.attempts,
+			mx_host=mx_host,
+			port=verif_method.config.smtp_port,
+			result=?result,
+			"Got SMTP check result"
+		);
+
+		retries.greylisted = matches!(&result, Err(err) if err.is_greylisted());
+		if retries.attempts >= max_attempts {
+			return (result, retries);
+		}
 
-	match &result {
-		// Don't retry if we used Hotmail or Yahoo API. This two options should
-		// be non-callable, as this function only deals with actual SMTP
-		// connection errors.
-		Err(SmtpError::HeadlessError(_)) => result,
-		Err(SmtpError::YahooError(_)) => result,
-		Err(SmtpError::GmailError(_)) => result,
-		// Only retry if the SMTP error was unknown.
-		Err(err) if err.get_description().is_none() => {
-			if count <= 1 {
-				result
-			} else {
+		match &result {
+			// Don't retry if we used Hotmail or Yahoo API. This two options should
+			// be non-callable, as this function only deals with actual SMTP
+			// connection errors.
+			Err(SmtpError::HeadlessError(_)) => return (result, retries),
+			Err(SmtpError::YahooError(_)) => return (result, retries),
+			Err(SmtpError::GmailError(_)) => return (result, retries),
+			// Inline waiting is disabled, retry right away as we always did.
+			Err(_) if retries.greylisted && greylisting.max_inline_wait_secs == 0 => {
 				tracing::debug!(
 					target: LOG_TARGET,
 					email=to_email.to_string(),
-					"Potential greylisting detected, retrying"
+					"Greylisted, retrying without waiting"
 				);
-				check_smtp_with_retry(to_email, mx_host, domain, verif_method, count - 1).await
 			}
+			// The server will only accept a retry after some time.
+			Err(_) if retries.greylisted => {
+				let delay = greylisting.retry_delay(retries.waits.len());
+				let waited: Duration = retries.waits.iter().sum();
+				if waited + delay > greylisting.max_inline_wait() {
+					tracing::debug!(
+						target: LOG_TARGET,
+						email=to_email.to_string(),
+						delay=?delay,
+						"Greylisted, but the retry delay exceeds the maximum wait"
+					);
+					return (result, retries);
+				}
+
+				tracing::debug!(
+					target: LOG_TARGET,
+					email=to_email.to_string(),
+					delay=?delay,
+					"Greylisted, waiting before retrying"
+				);
+				tokio::time::sleep(delay).await;
+				retries.waits.push(delay);
+			}
+			// Only retry if the SMTP error was unknown.
+			Err(err) if err.get_description().is_none() => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					email=to_email.to_string(),
+					"Unknown SMTP error, retrying"
+				);
+			}
+			_ => return (result, retries),
 		}
-		_ => result,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::smtp::catch_all::InMemoryCatchAllCache;
+	use crate::smtp::proxy_rotator::ProxyRotator;
+	use crate::smtp::session_pool::SmtpSessionPoolConfig;
+	use crate::smtp::transcript::SmtpTranscriptEntry;
+	use crate::smtp::verif_method::{
+		EverythingElseVerifMethod, ProxyPoolConfig, ProxyRetryConfig, ProxyRotationStrategy,
+		VerifMethod, VerifMethodSmtpConfig,
+	};
+	use crate::smtp::{check_smtp, SmtpDebugVerifMethod};
+	use crate::util::input_output::{CheckEmailInput, CheckEmailInputProxy};
+	use hickory_proto::rr::Name;
+	use std::sync::Arc;
+	use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+	use tokio::net::TcpListener;
+
+	/// Spawn a fake SMTP server on localhost, answering each command with the
+	/// reply of the first prefix matching the command. Returns its port.
+	async fn fake_smtp_server(replies: &'static [(&'static str, &'static str)]) -> u16 {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			while let Ok((socket, _)) = listener.accept().await {
+				let (read, mut write) = socket.into_split();
+				let mut lines = BufReader::new(read).lines();
+				write
+					.write_all(b"220 fake.example.com ESMTP\r\n")
+					.await
+					.unwrap();
+				while let Ok(Some(line)) = lines.next_line().await {
+					let reply = replies
+						.iter()
+						.find(|(prefix, _)| line.starts_with(prefix))
+						.map(|(_, reply)| *reply)
+						.unwrap_or("500 5.5.1 Unknown command\r\n");
+					write.write_all(reply.as_bytes()).await.unwrap();
+					if line.starts_with("QUIT") {
+						break;
+					}
+				}
+			}
+		});
+		port
+	}
+
+	/// Spawn a fake SOCKS5 proxy on localhost, without authentication,
+	/// forwarding every connection to `target_port` on localhost, whatever
+	/// the requested address. Returns its port.
+	async fn fake_socks5_proxy(target_port: u16) -> u16 {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			while let Ok((mut socket, _)) = listener.accept().await {
+				tokio::spawn(async move {
+					// Greeting: version, number of methods, methods.
+					let mut greeting = [0u8; 2];
+					socket.read_exact(&mut greeting).await?;
+					let mut methods = vec![0u8; greeting[1] as usize];
+					socket.read_exact(&mut methods).await?;
+					socket.write_all(&[5, 0]).await?;
+					// Request: version, command, reserved, address type, then
+					// the address and the port.
+					let mut request = [0u8; 4];
+					socket.read_exact(&mut request).await?;
+					let address_len = match request[3] {
+						1 => 4,
+						4 => 16,
+						_ => socket.read_u8().await? as usize,
+					};
+					let mut address = vec![0u8; address_len + 2];
+					socket.read_exact(&mut address).await?;
+					let mut target = TcpStream::connect(("127.0.0.1", target_port)).await?;
+					socket.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+					tokio::io::copy_bidirectional(&mut socket, &mut target).await?;
+					Ok::<_, std::io::Error>(())
+				});
+			}
+		});
+		port
+	}
+
+	/// An input verifying through the given proxies, rotated in order and
+	/// retried when blocked.
+	fn input_with_proxies(
+		smtp_port: u16,
+		proxies: &[(&str, u16)],
+		retry_timeout_secs: u64,
+	) -> CheckEmailInput {
+		let proxy_ids: Vec<String> = proxies
+			.iter()
+			.map(|(proxy_id, _)| proxy_id.to_string())
+			.collect();
+		CheckEmailInput {
+			verif_method: VerifMethod {
+				proxies: proxies
+					.iter()
+					.map(|(proxy_id, port)| {
+						let proxy = CheckEmailInputProxy {
+							host: "127.0.0.1".into(),
+							port: *port,
+							..Default::default()
+						};
+						(proxy_id.to_string(), proxy)
+					})
+					.collect(),
+				proxy_pool: ProxyPoolConfig {
+					enabled: true,
+					retry: ProxyRetryConfig {
+						enabled: true,
+						max_attempts: proxies.len(),
+						timeout_secs: retry_timeout_secs,
+					},
+					..Default::default()
+				},
+				everything_else: EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
+					smtp_port,
+					starttls: StartTlsMode::Opportunistic,
+					..Default::default()
+				}),
+				..Default::default()
+			},
+			proxy_rotator: Some(Arc::new(ProxyRotator::new(
+				proxy_ids,
+				ProxyRotationStrategy::RoundRobin,
+			))),
+			..Default::default()
+		}
+	}
+
+	fn verif_method(port: u16) -> VerifMethodSmtp {
+		VerifMethodSmtp::new(
+			VerifMethodSmtpConfig {
+				smtp_port: port,
+				starttls: StartTlsMode::Opportunistic,
+				..Default::default()
+			},
+			None,
+		)
+	}
+
+	#[tokio::test]
+	async fn should_verify_against_smtp_server() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250-fake.example.com\r\n250 SIZE 1000\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			("RCPT TO:<foo@", "250 2.1.5 OK\r\n"),
+			("RCPT TO", "550 5.1.1 User unknown\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let transcript = SmtpTranscript::default();
+		let mut timings = StageTimings::default();
+
+		let details = check_smtp_without_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method(port),
+			Some(&transcript),
+			SmtpShared::default(),
+			&mut timings,
+		)
+		.await
+		.unwrap();
+
+		assert!(details.can_connect_smtp);
+		assert!(details.is_deliverable);
+		assert!(!details.is_catch_all);
+		// STARTTLS isn't offered, so we don't try it even if opportunistic.
+		assert!(!details.tls.starttls_offered);
+		assert!(!details.tls.negotiated);
+		assert_eq!(
+			details.server.banner.as_deref(),
+			Some("fake.example.com ESMTP")
+		);
+		assert_eq!(details.server.extensions.size, Some(1000));
+		assert!(timings.proxy_connect.is_some());
+		assert!(timings.smtp_handshake.is_some());
+		assert!(timings.catch_all_rcpt.is_some());
+		assert!(timings.rcpt.is_some());
+
+		let entries: Vec<SmtpTranscriptEntry> = transcript.entries();
+		assert!(matches!(
+			entries[0].event,
+			SmtpTranscriptEvent::Connect { port: p, .. } if p == port
+		));
+		assert!(entries.iter().any(|e| matches!(
+			&e.event,
+			SmtpTranscriptEvent::Reply { code: Some(550), enhanced_code: Some(c), .. } if c == "5.1.1"
+		)));
+	}
+
+	#[tokio::test]
+	async fn should_record_stage_interrupted_by_timeout() {
+		// The server never answers RCPT.
+		let port = fake_smtp_server(&[
+			("EHLO", "250 fake.example.com\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			("RCPT TO", ""),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let mut verif_method = verif_method(port);
+		verif_method.config.smtp_timeout = Some(Duration::from_millis(200));
+		let mut timings = StageTimings::default();
+
+		let result = check_smtp_without_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method,
+			None,
+			SmtpShared {
+				known_catch_all: Some(false),
+				..Default::default()
+			},
+			&mut timings,
+		)
+		.await;
+
+		assert!(matches!(result, Err(SmtpError::Timeout(_))));
+		assert!(timings.smtp_handshake.is_some());
+		assert_eq!(timings.catch_all_rcpt, None);
+		assert!(timings.rcpt.unwrap() >= Duration::from_millis(100));
+	}
+
+	#[tokio::test]
+	async fn should_send_smtputf8_for_international_address() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250-fake.example.com\r\n250 SMTPUTF8\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			("RCPT TO:<用户@", "250 2.1.5 OK\r\n"),
+			("RCPT TO", "550 5.1.1 User unknown\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("用户@example.com").unwrap();
+		let transcript = SmtpTranscript::default();
+
+		let details = check_smtp_without_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method(port),
+			Some(&transcript),
+			SmtpShared::default(),
+			&mut StageTimings::default(),
+		)
+		.await
+		.unwrap();
+
+		assert!(details.is_deliverable);
+		assert!(transcript.entries().iter().any(|e| matches!(
+			&e.event,
+			SmtpTranscriptEvent::Command { text } if text.starts_with("MAIL FROM") && text.ends_with(" SMTPUTF8")
+		)));
+	}
+
+	#[tokio::test]
+	async fn should_fail_if_smtputf8_not_supported() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250 fake.example.com\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("用户@example.com").unwrap();
+
+		let res = check_smtp_without_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method(port),
+			None,
+			SmtpShared::default(),
+			&mut StageTimings::default(),
+		)
+		.await;
+
+		assert!(matches!(res, Err(SmtpError::EaiUnsupported)));
+	}
+
+	#[tokio::test]
+	async fn should_fail_if_starttls_required_but_not_offered() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250 fake.example.com\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let mut verif_method = verif_method(port);
+		verif_method.config.starttls = StartTlsMode::Required;
+
+		let res = check_smtp_without_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method,
+			None,
+			SmtpShared::default(),
+			&mut StageTimings::default(),
+		)
+		.await;
+
+		assert!(matches!(res, Err(SmtpError::TlsError(_))));
+	}
+
+	#[tokio::test]
+	async fn should_fail_if_starttls_required_but_rejected() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250-fake.example.com\r\n250 STARTTLS\r\n"),
+			("STARTTLS", "454 4.7.0 TLS not available\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let mut verif_method = verif_method(port);
+		verif_method.config.starttls = StartTlsMode::Required;
+
+		let res = check_smtp_without_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method,
+			None,
+			SmtpShared::default(),
+			&mut StageTimings::default(),
+		)
+		.await;
+
+		assert!(matches!(res, Err(SmtpError::TlsError(_))));
+	}
+
+	#[tokio::test]
+	async fn should_wait_and_retry_when_greylisted() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250 fake.example.com\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			(
+				"RCPT TO",
+				"451 4.7.1 Greylisted, please try again later\r\n",
+			),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let mut verif_method = verif_method(port);
+		verif_method.config.retries = 3;
+
+		let greylisting = GreylistingConfig {
+			retry_delay_secs: 0,
+			max_inline_wait_secs: 1,
+			..Default::default()
+		};
+		let (res, retries) = check_smtp_with_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method,
+			None,
+			&greylisting,
+			SmtpShared::default(),
+			&mut StageTimings::default(),
+		)
+		.await;
+		assert!(matches!(res, Err(ref err) if err.is_greylisted()));
+		assert_eq!(retries.attempts, 3);
+		assert_eq!(retries.waits, vec![Duration::ZERO; 2]);
+		assert!(retries.greylisted);
+
+		// The retry delay exceeds the maximum wait.
+		let greylisting = GreylistingConfig {
+			max_inline_wait_secs: 30,
+			..Default::default()
+		};
+		let (_, retries) = check_smtp_with_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method,
+			None,
+			&greylisting,
+			SmtpShared::default(),
+			&mut StageTimings::default(),
+		)
+		.await;
+		assert_eq!(retries.attempts, 1);
+		assert!(retries.waits.is_empty());
+		assert!(retries.greylisted);
+
+		// Inline waiting is disabled by default, so we retry immediately.
+		let (_, retries) = check_smtp_with_retry(
+			&to_email,
+			"127.0.0.1",
+			"example.com",
+			&verif_method,
+			None,
+			&GreylistingConfig::default(),
+			SmtpShared::default(),
+			&mut StageTimings::default(),
+		)
+		.await;
+		assert_eq!(retries.attempts, 3);
+		assert!(retries.waits.is_empty());
+		assert!(retries.greylisted);
+	}
+
+	#[tokio::test]
+	async fn should_reuse_session_with_pool() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250 fake.example.com\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			("RCPT TO:<foo@", "250 2.1.5 OK\r\n"),
+			("RCPT TO:<bar@", "250 2.1.5 OK\r\n"),
+			("RCPT TO", "550 5.1.1 User unknown\r\n"),
+			("RSET", "250 2.0.0 OK\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let verif_method = verif_method(port);
+		let pool = SmtpSessionPool::new(SmtpSessionPoolConfig {
+			enabled: true,
+			max_recipients_per_session: 2,
+			..Default::default()
+		});
+
+		let mut transcripts = vec![];
+		for email in ["foo@example.com", "bar@example.com", "baz@example.com"] {
+			let to_email = EmailAddress::from_str(email).unwrap();
+			let transcript = SmtpTranscript::default();
+			let details = check_smtp_without_retry(
+				&to_email,
+				"127.0.0.1",
+				"example.com",
+				&verif_method,
+				Some(&transcript),
+				SmtpShared {
+					session_pool: Some(&pool),
+					..Default::default()
+				},
+				&mut StageTimings::default(),
+			)
+			.await
+			.unwrap();
+			assert_eq!(details.is_deliverable, email != "baz@example.com");
+			assert!(!details.is_catch_all);
+			transcripts.push(transcript.entries());
+		}
+
+		let commands = |entries: &[SmtpTranscriptEntry], prefix: &str| {
+			entries
+				.iter()
+				.filter(
+					|e| matches!(&e.event, SmtpTranscriptEvent::Command { text } if text.starts_with(prefix)),
+				)
+				.count()
+		};
+		// The first session is reused once, then closed as it reached the
+		// maximum number of recipients.
+		assert!(matches!(
+			transcripts[0][0].event,
+			SmtpTranscriptEvent::Connect { .. }
+		));
+		assert!(matches!(
+			transcripts[1][0].event,
+			SmtpTranscriptEvent::Reuse { .. }
+		));
+		assert!(matches!(
+			transcripts[2][0].event,
+			SmtpTranscriptEvent::Connect { .. }
+		));
+		// The catch-all address is only probed once per pool, even over a new
+		// session.
+		assert_eq!(commands(&transcripts[0], "RCPT TO"), 2);
+		assert_eq!(commands(&transcripts[1], "RCPT TO"), 1);
+		assert_eq!(commands(&transcripts[1], "QUIT"), 1);
+		assert_eq!(commands(&transcripts[2], "RCPT TO"), 1);
+	}
+
+	#[tokio::test]
+	async fn should_close_expired_sessions() {
+		const REPLIES: &[(&str, &str)] = &[
+			("EHLO", "250 fake.example.com\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			("RCPT TO", "250 2.1.5 OK\r\n"),
+			("RSET", "250 2.0.0 OK\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		];
+		let pool = SmtpSessionPool::new(SmtpSessionPoolConfig {
+			enabled: true,
+			idle_timeout_secs: 0,
+			..Default::default()
+		});
+
+		// The sessions to one MX host are closed once expired, even if we
+		// only verify emails against another host afterwards.
+		for port in [
+			fake_smtp_server(REPLIES).await,
+			fake_smtp_server(REPLIES).await,
+		] {
+			check_smtp_without_retry(
+				&EmailAddress::from_str("foo@example.com").unwrap(),
+				"127.0.0.1",
+				"example.com",
+				&verif_method(port),
+				None,
+				SmtpShared {
+					session_pool: Some(&pool),
+					..Default::default()
+				},
+				&mut StageTimings::default(),
+			)
+			.await
+			.unwrap();
+		}
+		assert_eq!(pool.idle_count(), 1);
+	}
+
+	#[tokio::test]
+	async fn should_cache_catch_all_probe() {
+		let port = fake_smtp_server(&[
+			("EHLO", "250 fake.example.com\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			("RCPT TO:<foo@", "250 2.1.5 OK\r\n"),
+			("RCPT TO", "550 5.1.1 User unknown\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+		let cache = InMemoryCatchAllCache::default();
+
+		for known_catch_all in [None, Some(false)] {
+			let transcript = SmtpTranscript::default();
+			let details = check_smtp_without_retry(
+				&to_email,
+				"127.0.0.1",
+				"example.com",
+				&verif_method(port),
+				Some(&transcript),
+				SmtpShared {
+					catch_all_cache: Some(&cache),
+					known_catch_all,
+					..Default::default()
+				},
+				&mut StageTimings::default(),
+			)
+			.await
+			.unwrap();
+			assert!(details.is_deliverable);
+
+			let rcpt_count = transcript
+				.entries()
+				.iter()
+				.filter(
+					|e| matches!(&e.event, SmtpTranscriptEvent::Command { text } if text.starts_with("RCPT TO")),
+				)
+				.count();
+			assert_eq!(rcpt_count, if known_catch_all.is_some() { 1 } else { 2 });
+		}
+		assert_eq!(cache.get("example.com").await, Some(false));
+	}
+
+	#[tokio::test]
+	async fn should_retry_through_another_proxy() {
+		let smtp_port = fake_smtp_server(&[
+			("EHLO", "250-fake.example.com\r\n250 SIZE 1000\r\n"),
+			("MAIL FROM", "250 2.1.0 OK\r\n"),
+			("RCPT TO:<foo@", "250 2.1.5 OK\r\n"),
+			("RCPT TO", "550 5.1.1 User unknown\r\n"),
+			("QUIT", "221 2.0.0 Bye\r\n"),
+		])
+		.await;
+		let proxy_port = fake_socks5_proxy(smtp_port).await;
+		// Nothing listens on this port anymore, so the proxy is blocked.
+		let blocked_port = TcpListener::bind("127.0.0.1:0")
+			.await
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port();
+		let input = input_with_proxies(
+			smtp_port,
+			&[("blocked", blocked_port), ("working", proxy_port)],
+			120,
+		);
+
+		let (result, debug) = check_smtp(
+			&EmailAddress::from_str("foo@example.com").unwrap(),
+			&Name::from_str("localhost.").unwrap(),
+			"example.com",
+			&input,
+			&mut StageTimings::default(),
+		)
+		.await;
+		assert!(result.unwrap().is_deliverable);
+		let attempts: Vec<_> = debug
+			.proxy_attempts
+			.iter()
+			.map(|attempt| (attempt.proxy_id.as_str(), attempt.error.is_some()))
+			.collect();
+		assert_eq!(attempts, vec![("blocked", true), ("working", false)]);
+		match debug.verif_method {
+			SmtpDebugVerifMethod::Smtp(smtp) => {
+				assert_eq!(smtp.proxy_data, format!("proxy:127.0.0.1:{}", proxy_port))
+			}
+			_ => panic!("Expected an SMTP verification"),
+		}
+
+		let health = input.proxy_rotator.as_ref().unwrap().health();
+		assert_eq!((health[0].failures, health[1].successes), (1, 1));
+	}
+
+	#[tokio::test]
+	async fn should_bound_total_time_of_proxy_retries() {
+		// A proxy accepting connections but never answering.
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let hanging_port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			let mut sockets = Vec::new();
+			while let Ok((socket, _)) = listener.accept().await {
+				sockets.push(socket);
+			}
+		});
+		let input = input_with_proxies(
+			25,
+			&[("hanging1", hanging_port), ("hanging2", hanging_port)],
+			1,
+		);
+
+		let start = Instant::now();
+		let (result, debug) = check_smtp(
+			&EmailAddress::from_str("foo@example.com").unwrap(),
+			&Name::from_str("localhost.").unwrap(),
+			"example.com",
+			&input,
+			&mut StageTimings::default(),
+		)
+		.await;
+		assert!(start.elapsed() < Duration::from_secs(5));
+		assert!(matches!(result, Err(SmtpError::Timeout(_))));
+		// The timeout is spent, so the second proxy isn't tried.
+		assert_eq!(debug.proxy_attempts.len(), 1);
+		// Being cut short doesn't count against the proxy.
+		assert_eq!(
+			input.proxy_rotator.as_ref().unwrap().health()[0].failures,
+			0
+		);
 	}
 }
@@ -17,7 +17,7 @@
 use super::gmail::GmailError;
 use super::headless::HeadlessError;
 use super::outlook::microsoft365::Microsoft365Error;
-use super::parser;
+use super::parser::{self, SmtpReplyOutcome};
 use super::yahoo::YahooError;
 use crate::util::ser_with_display::ser_with_display;
 use async_smtp::error::Error as AsyncSmtpError;
@@ -56,6 +56,16 @@ pub enum SmtpError {
         #[error("SOCKS5 error: {0}")]
         #[serde(serialize_with = "ser_with_display")]
         Socks5(fast_socks5::SocksError),
+        /// STARTTLS was required, but TLS couldn't be negotiated.
+        #[error("TLS error: {0}")]
+        TlsError(String),
+        /// The email address is internationalized, but the SMTP server doesn't
+        /// support SMTPUTF8 (RFC 6531).
+        #[error("The SMTP server does not support internationalized email addresses (SMTPUTF8)")]
+        EaiUnsupported,
+        /// The SMTP verification was skipped because of the `SkipSmtp` rule.
+        #[error("SMTP verification skipped by rule")]
+        SkippedByRule,
         /// Anyhow error.
         /// This is a catch-all error type for any error that can't be categorized
         /// into the above types.
@@ -117,18 +127,68 @@ impl SmtpError {
         /// SmtpErrorDesc. This only parses the following known errors:
         /// - IP blacklisted
         /// - IP needs reverse DNS
+        /// - internationalized email addresses unsupported
         pub fn get_description(&self) -> Option<SmtpErrorDesc> {
                 match self {
+                        SmtpError::AsyncSmtpError(e) => match parser::classify_error(e)? {
+                                SmtpReplyOutcome::IpBlacklisted => Some(SmtpErrorDesc::IpBlacklisted),
+                                SmtpReplyOutcome::NeedsRdns => Some(SmtpErrorDesc::NeedsRDNS),
+                                _ => None,
+                        },
+                        SmtpError::EaiUnsupported => Some(SmtpErrorDesc::EaiUnsupported),
+                        _ => None,
+                }
+        }
+
+        /// Whether the server greylisted us, i.e. it temporarily refused the
+        /// email and expects us to retry later.
+        pub fn is_greylisted(&self) -> bool {
+                match self {
+                        SmtpError::AsyncSmtpError(e @ AsyncSmtpError::Transient(_)) => {
+                                parser::classify_error(e) == Some(SmtpReplyOutcome::Greylisted)
+                        }
+                        _ => false,
+                }
+        }
+
+        /// Whether the MX host itself couldn't be reached (timeout, I/O error,
+        /// proxy error), as opposed to the host answering about the email. In
+        /// that case, another MX host of the same domain might still answer.
+        pub fn is_unreachable_host(&self) -> bool {
+                matches!(
+                        self,
+                        SmtpError::Timeout(_)
+                                | SmtpError::IOError(_)
+                                | SmtpError::Socks5(_)
+                                | SmtpError::AsyncSmtpError(AsyncSmtpError::Io(_))
+                )
+        }
+
+        /// Whether the error counts against the health of the proxy the
+        /// verification went through: the proxy failed, timed out, or its IP
+        /// is blacklisted by the SMTP server.
+        pub fn is_proxy_failure(&self) -> bool {
+                match self {
+                        SmtpError::Socks5(_) | SmtpError::Timeout(_) => true,
                         SmtpError::AsyncSmtpError(_) => {
-                                if parser::is_err_ip_blacklisted(self) {
-                                        Some(SmtpErrorDesc::IpBlacklisted)
-                                } else if parser::is_err_needs_rdns(self) {
-                                        Some(SmtpErrorDesc::NeedsRDNS)
-                                } else {
-                                        None
-                                }
+                                matches!(self.get_description(), Some(SmtpErrorDesc::IpBlacklisted))
                         }
-                        _ => None,
+                        _ => false,
+                }
+        }
+
+        /// Whether the proxy the verification went through is blocked: the
+        /// proxy itself failed, or the SMTP server refused its IP. Another
+        /// proxy might still get an answer, see
+        /// [ProxyRetryConfig](super::verif_method::ProxyRetryConfig).
+        pub fn is_proxy_blocked(&self) -> bool {
+                match self {
+                        SmtpError::Socks5(_) => true,
+                        SmtpError::AsyncSmtpError(_) => matches!(
+                                self.get_description(),
+                                Some(SmtpErrorDesc::IpBlacklisted) | Some(SmtpErrorDesc::NeedsRDNS)
+                        ),
+                        _ => false,
                 }
         }
 
@@ -329,7 +389,7 @@ fn format_socks5_reply_error(reply_error: &fast_socks5::ReplyError) -> String {
         }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, PartialEq, Serialize)]
 /// SmtpErrorDesc describes a description of which category the error belongs
 /// to.
 pub enum SmtpErrorDesc {
@@ -337,4 +397,6 @@ pub enum SmtpErrorDesc {
         IpBlacklisted,
         /// The IP needs a reverse DNS entry.
         NeedsRDNS,
+        /// The SMTP server doesn't support internationalized email addresses.
+        EaiUnsupported,
 }
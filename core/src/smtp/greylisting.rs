@@ -0,0 +1,122 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Retry policy for SMTP servers that greylist us.
+//!
+//! A greylisting server temporarily refuses the first delivery attempt from
+//! an unknown sender, and only accepts a retry made after a few minutes.
+//! Retrying immediately is therefore useless: we wait between attempts, with
+//! an exponential backoff. Long waits shouldn't block the verification, so
+//! they are bounded by `max_inline_wait_secs`, and callers processing
+//! verifications from a queue can instead defer them, see `max_deferrals`.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How to retry an SMTP verification when the server greylists us.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct GreylistingConfig {
+	/// Delay before the first retry, in seconds.
+	///
+	/// Defaults to 60.
+	pub retry_delay_secs: u64,
+	/// Factor applied to the delay after each retry.
+	///
+	/// Defaults to 2.
+	pub backoff_multiplier: f64,
+	/// Upper bound of the delay between two retries, in seconds.
+	///
+	/// Defaults to 900.
+	pub max_delay_secs: u64,
+	/// Maximum total time to wait between the attempts of a single
+	/// verification, in seconds. A retry that would exceed it isn't made, and
+	/// the greylisting error is returned instead. The number of attempts is
+	/// still bounded by the `retries` field of the SMTP verification method.
+	///
+	/// Defaults to 0, i.e. never wait: greylisted verifications are then
+	/// retried immediately, like any other unknown SMTP error.
+	pub max_inline_wait_secs: u64,
+	/// Number of times a greylisted verification can be deferred, i.e.
+	/// started again later from scratch. This library never defers a
+	/// verification itself, this is left to the caller, e.g. the backend
+	/// worker re-publishing the task to its queue.
+	///
+	/// Defaults to 0.
+	pub max_deferrals: usize,
+}
+
+impl Default for GreylistingConfig {
+	fn default() -> Self {
+		Self {
+			retry_delay_secs: 60,
+			backoff_multiplier: 2.0,
+			max_delay_secs: 900,
+			max_inline_wait_secs: 0,
+			max_deferrals: 0,
+		}
+	}
+}
+
+impl GreylistingConfig {
+	/// Delay before the retry following `previous_retries` retries.
+	pub fn retry_delay(&self, previous_retries: usize) -> Duration {
+		let exponent = previous_retries.min(i32::MAX as usize) as i32;
+		let delay = self.retry_delay_secs as f64 * self.backoff_multiplier.max(1.0).powi(exponent);
+		Duration::from_secs_f64(delay.min(self.max_delay_secs as f64).max(0.0))
+	}
+
+	/// Maximum total time to wait between the attempts of a single
+	/// verification.
+	pub fn max_inline_wait(&self) -> Duration {
+		Duration::from_secs(self.max_inline_wait_secs)
+	}
+}
+
+/// How the SMTP verification against an MX host was retried.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Serialize)]
+pub struct SmtpDebugRetries {
+	/// Number of SMTP connections made.
+	pub attempts: usize,
+	/// The time waited before each retry.
+	pub waits: Vec<Duration>,
+	/// Whether the server greylisted us on the last attempt.
+	pub greylisted: bool,
+	/// Number of times the verification was deferred before this run, see
+	/// `GreylistingConfig.max_deferrals`.
+	pub deferrals: usize,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn should_back_off_up_to_max_delay() {
+		let config = GreylistingConfig {
+			retry_delay_secs: 60,
+			backoff_multiplier: 3.0,
+			max_delay_secs: 600,
+			..Default::default()
+		};
+
+		assert_eq!(config.retry_delay(0), Duration::from_secs(60));
+		assert_eq!(config.retry_delay(1), Duration::from_secs(180));
+		assert_eq!(config.retry_delay(2), Duration::from_secs(540));
+		assert_eq!(config.retry_delay(3), Duration::from_secs(600));
+		assert_eq!(config.retry_delay(usize::MAX), Duration::from_secs(600));
+	}
+}
@@ -14,26 +14,37 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod catch_all;
 mod connect;
 mod error;
 mod gmail;
+pub mod greylisting;
 mod headless;
 mod http_api;
 mod outlook;
 mod parser;
+pub mod patterns;
 pub mod proxy_rotator;
+mod server;
+mod session;
+pub mod session_pool;
+mod tls;
+pub mod transcript;
 pub mod verif_method;
 mod yahoo;
 
+use crate::rules::current_rules;
 use crate::util::input_output::CheckEmailInput;
 use crate::util::public_ip::get_public_ip;
-use crate::EmailAddress;
+use crate::{EmailAddress, StageTimings};
 use crate::LOG_TARGET;
-use connect::check_smtp_with_retry;
+use connect::{check_smtp_with_retry, SmtpShared};
+use greylisting::SmtpDebugRetries;
 use hickory_proto::rr::Name;
 use proxy_rotator::ProxyRotator;
 use serde::{Deserialize, Serialize};
 use std::default::Default;
+use transcript::{SmtpTranscript, SmtpTranscriptEntry};
 use verif_method::{
         EmailProvider, EverythingElseVerifMethod, GmailVerifMethod, HotmailB2BVerifMethod,
         HotmailB2CVerifMethod, VerifMethodSmtp, VerifMethodSmtpConfig, YahooVerifMethod,
@@ -41,6 +52,8 @@ use verif_method::{
 
 pub use crate::mx::{is_gmail, is_hotmail, is_hotmail_b2b, is_hotmail_b2c, is_yahoo};
 pub use error::*;
+pub use parser::{classify_reply, EnhancedStatusCode, SmtpReplyOutcome};
+pub use server::{SmtpExtensions, SmtpServerDetails, SmtpSoftware};
 
 use crate::util::input_output::CheckEmailInputProxy;
 
@@ -53,6 +66,8 @@ pub struct SmtpDebugVerifMethodSmtp {
         /// The actual proxy data - always required.
         /// Format: "proxy:host:port" or "proxy:host:port@username:password" when using proxy
         /// Format: "local:ip_address" when using local connection (no proxy)
+        /// If the verification was retried through other proxies, this is the
+        /// last one, see `SmtpDebug.proxy_attempts` for all of them.
         pub proxy_data: String,
 }
 
@@ -123,6 +138,60 @@ pub struct SmtpDetails {
         pub is_deliverable: bool,
         /// Is the email blocked or disabled by the provider?
         pub is_disabled: bool,
+        /// Details about the TLS support of the SMTP server.
+        #[serde(default)]
+        pub tls: SmtpTlsDetails,
+        /// Details about the SMTP server: its banner, advertised extensions
+        /// and software.
+        #[serde(default)]
+        pub server: SmtpServerDetails,
+}
+
+/// Details about the TLS support of the SMTP server, see the `starttls`
+/// field of the SMTP verification method configuration.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Serialize)]
+pub struct SmtpTlsDetails {
+        /// Did the server offer STARTTLS in its EHLO response?
+        pub starttls_offered: bool,
+        /// Did we negotiate TLS with the server?
+        pub negotiated: bool,
+        /// The negotiated TLS protocol version, e.g. "TLSv1_3".
+        pub protocol_version: Option<String>,
+        /// Is the server certificate valid for the MX host name? None if TLS
+        /// wasn't negotiated.
+        pub cert_valid: Option<bool>,
+        /// Why the TLS handshake failed, or why the certificate is invalid.
+        pub error: Option<String>,
+}
+
+/// Outcome of the SMTP verification against one MX host.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", content = "message")]
+pub enum SmtpDebugMxOutcome {
+        /// The MX host gave us an answer about the email.
+        Ok,
+        /// The MX host answered with an error, we didn't try other hosts.
+        Error(String),
+        /// The MX host couldn't be reached, we moved on to the next one.
+        Unreachable(String),
+}
+
+/// An MX host we tried to connect to during the SMTP verification.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SmtpDebugMxAttempt {
+        /// The MX host.
+        pub host: String,
+        /// What happened with this MX host.
+        pub outcome: SmtpDebugMxOutcome,
+}
+
+/// A proxy the SMTP verification went through.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SmtpDebugProxyAttempt {
+        /// The ID of the proxy.
+        pub proxy_id: String,
+        /// Why the verification failed through this proxy, if it did.
+        pub error: Option<String>,
 }
 
 /// Debug information on how the SMTP verification went.
@@ -130,24 +199,160 @@ pub struct SmtpDetails {
 pub struct SmtpDebug {
         /// The verification method used for the email.
         pub verif_method: SmtpDebugVerifMethod,
+        /// The MX hosts we tried, in order.
+        #[serde(default)]
+        pub mx_attempts: Vec<SmtpDebugMxAttempt>,
+        /// The proxies we tried against the last MX host, in order. There's
+        /// more than one if the first proxy was blocked, see
+        /// `VerifMethod.proxy_pool.retry`.
+        #[serde(default)]
+        pub proxy_attempts: Vec<SmtpDebugProxyAttempt>,
+        /// The full SMTP conversation, only recorded if
+        /// `CheckEmailInput.smtp_transcript` is set.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub transcript: Option<Vec<SmtpTranscriptEntry>>,
+        /// How the SMTP verification was retried against the last MX host
+        /// through the last proxy tried, e.g. after being greylisted.
+        #[serde(default)]
+        pub retries: SmtpDebugRetries,
+        /// Whether the catch-all status of the domain came from the catch-all
+        /// cache, in which case the catch-all probe was skipped.
+        #[serde(default)]
+        pub catch_all_cache_hit: bool,
 }
 
-/// Get all email details we can from one single `EmailAddress`, without
-/// retries.
+/// Run the SMTP verification against the MX hosts of the domain, in the
+/// given order. If a host is unreachable, we move on to the next one, up to
+/// the number of hosts allowed by `input.mx_fallback`. The time spent in each
+/// SMTP stage is added to `timings`. If `hosts` is empty, an error is
+/// returned.
+///
+/// Breaking change in 0.12: this now takes the `timings` argument.
+pub async fn check_smtp_with_mx_fallback(
+        to_email: &EmailAddress,
+        hosts: &[Name],
+        domain: &str,
+        input: &CheckEmailInput,
+        timings: &mut StageTimings,
+) -> (Result<SmtpDetails, SmtpError>, SmtpDebug) {
+        let max_hosts = input.mx_fallback.max_hosts();
+        let mut mx_attempts = Vec::new();
+        let mut last = None;
+
+        for host in hosts.iter().take(max_hosts) {
+                let (result, debug) = check_smtp(to_email, host, domain, input, timings).await;
+                // Only SMTP verifications go through the MX host, API and headless
+                // verifications won't give a different result with another host.
+                let is_smtp = matches!(debug.verif_method, SmtpDebugVerifMethod::Smtp(_));
+                let outcome = match &result {
+                        Ok(_) => SmtpDebugMxOutcome::Ok,
+                        Err(err) if is_smtp && err.is_unreachable_host() => {
+                                SmtpDebugMxOutcome::Unreachable(err.to_string())
+                        }
+                        Err(err) => SmtpDebugMxOutcome::Error(err.to_string()),
+                };
+                let is_unreachable = matches!(outcome, SmtpDebugMxOutcome::Unreachable(_));
+                mx_attempts.push(SmtpDebugMxAttempt {
+                        host: host.to_string(),
+                        outcome,
+                });
+                last = Some((result, debug));
+
+                if !is_unreachable {
+                        break;
+                }
+
+                tracing::debug!(
+                        target: LOG_TARGET,
+                        email=%to_email,
+                        host=%host,
+                        "MX host unreachable, trying next one"
+                );
+        }
+
+        let (result, mut debug) = match last {
+                Some(last) => last,
+                None => {
+                        return (
+                                Err(SmtpError::AnyhowError(anyhow::anyhow!(
+                                        "No MX host to verify the email against"
+                                ))),
+                                SmtpDebug::default(),
+                        )
+                }
+        };
+        debug.mx_attempts = mx_attempts;
+        (result, debug)
+}
+
+/// Get all email details we can from one single `EmailAddress`, against one
+/// MX host. If the proxy is blocked, the verification may be retried through
+/// other proxies, see `VerifMethod.proxy_pool.retry`, within its total
+/// timeout. The time spent in each SMTP stage is added to `timings`.
+///
+/// Breaking change in 0.12: this now takes the `timings` argument.
 pub async fn check_smtp(
         to_email: &EmailAddress,
         host: &Name,
         domain: &str,
         input: &CheckEmailInput,
+        timings: &mut StageTimings,
 ) -> (Result<SmtpDetails, SmtpError>, SmtpDebug) {
         let host_str = host.to_string();
         let to_email_str = to_email.to_string();
         let email_provider = EmailProvider::from_mx_host(&host_str);
+        let rules = current_rules().matching(domain, &host_str);
+
+        // Use the verification method forced by the rules, if any.
+        let forced_verif_methods = rules.verif_method.and_then(|method| {
+                let mut verif_methods = input.verif_method.clone();
+                if verif_methods.force(&email_provider, method) {
+                        Some(verif_methods)
+                } else {
+                        tracing::warn!(
+                                target: LOG_TARGET,
+                                email=%to_email,
+                                method=?method,
+                                "Verification method forced by rule is not supported by the email provider, ignoring it"
+                        );
+                        None
+                }
+        });
+        let all_verif_methods = forced_verif_methods.as_ref().unwrap_or(&input.verif_method);
+
+        if rules.skip_smtp {
+                tracing::debug!(
+                        target: LOG_TARGET,
+                        email=%to_email,
+                        host=%host,
+                        "Skipping SMTP verification by rule"
+                );
+                let proxy_data = get_public_ip().await;
+                let verif_method = match all_verif_methods.get_smtp_config(&email_provider) {
+                        Some(config) => {
+                                let mut config = config.clone();
+                                rules.apply_to_smtp_config(&mut config);
+                                SmtpDebugVerifMethod::Smtp(SmtpDebugVerifMethodSmtp {
+                                        host: host_str,
+                                        verif_method: config,
+                                        proxy_data,
+                                })
+                        }
+                        None => SmtpDebugVerifMethod::Skipped(SmtpDebugVerifMethodSkipped { proxy_data }),
+                };
+                return (
+                        Err(SmtpError::SkippedByRule),
+                        SmtpDebug {
+                                verif_method,
+                                ..Default::default()
+                        },
+                );
+        }
 
         // Handle all non-SMTP verifications first, and return early. For the rest,
         // we'll use SMTP, and return the config.
-        let smtp_verif_method_config = match &email_provider {
-                EmailProvider::HotmailB2C => match &input.verif_method.hotmailb2c {
+        let mut smtp_verif_method_config = match &email_provider {
+                EmailProvider::HotmailB2C => match &all_verif_methods.hotmailb2c {
                         HotmailB2CVerifMethod::Headless => {
                                 let local_ip = get_public_ip().await;
                                 return (
@@ -162,12 +367,13 @@ pub async fn check_smtp(
                                                 verif_method: SmtpDebugVerifMethod::Headless(SmtpDebugVerifMethodHeadless {
                                                         proxy_data: local_ip,
                                                 }),
+                                                ..Default::default()
                                         },
                                 );
                         }
                         HotmailB2CVerifMethod::Smtp(c) => c,
                 },
-                EmailProvider::Yahoo => match &input.verif_method.yahoo {
+                EmailProvider::Yahoo => match &all_verif_methods.yahoo {
                         YahooVerifMethod::Api => {
                                 let local_ip = get_public_ip().await;
                                 return (
@@ -178,6 +384,7 @@ pub async fn check_smtp(
                                                 verif_method: SmtpDebugVerifMethod::Api(SmtpDebugVerifMethodApi {
                                                         proxy_data: local_ip,
                                                 }),
+                                                ..Default::default()
                                         },
                                 );
                         }
@@ -195,79 +402,230 @@ pub async fn check_smtp(
                                                 verif_method: SmtpDebugVerifMethod::Headless(SmtpDebugVerifMethodHeadless {
                                                         proxy_data: local_ip,
                                                 }),
+                                                ..Default::default()
                                         },
                                 );
                         }
                         YahooVerifMethod::Smtp(c) => c,
                 },
-                EmailProvider::Gmail => match &input.verif_method.gmail {
+                EmailProvider::Gmail => match &all_verif_methods.gmail {
                         GmailVerifMethod::Smtp(c) => c,
                 },
-                EmailProvider::HotmailB2B => match &input.verif_method.hotmailb2b {
+                EmailProvider::HotmailB2B => match &all_verif_methods.hotmailb2b {
                         HotmailB2BVerifMethod::Smtp(c) => c,
                 },
-                EmailProvider::Mimecast => match &input.verif_method.mimecast {
+                EmailProvider::Mimecast => match &all_verif_methods.mimecast {
                         verif_method::MimecastVerifMethod::Smtp(c) => c,
                 },
-                EmailProvider::Proofpoint => match &input.verif_method.proofpoint {
+                EmailProvider::Proofpoint => match &all_verif_methods.proofpoint {
                         verif_method::ProofpointVerifMethod::Smtp(c) => c,
                 },
-                EmailProvider::EverythingElse => match &input.verif_method.everything_else {
+                EmailProvider::EverythingElse => match &all_verif_methods.everything_else {
                         EverythingElseVerifMethod::Smtp(c) => c,
                 },
         }
         .clone();
+        rules.apply_to_smtp_config(&mut smtp_verif_method_config);
 
-        let proxy = if input.verif_method.proxy_pool.enabled {
-                if let Some(rotator) = &input.proxy_rotator {
-                        input.verif_method.get_proxy_with_rotation(&email_provider, Some(rotator.as_ref()))
-                } else {
+        // A proxy forced by the rules takes precedence over all the others.
+        let forced_proxy_id = rules.proxy.as_ref().filter(|proxy_id| {
+                let is_configured = all_verif_methods.proxies.contains_key(*proxy_id);
+                if !is_configured {
                         tracing::warn!(
                                 target: LOG_TARGET,
                                 email=%to_email,
-                                "Proxy pool is enabled but no shared rotator provided. Creating a new rotator for this request. \
-                                This means round-robin rotation will not work correctly across requests."
+                                proxy=%proxy_id,
+                                "Proxy forced by rule is not configured, ignoring it"
                         );
-                        let proxy_ids: Vec<String> = input
-                                .verif_method
-                                .proxies
-                                .keys()
-                                .filter(|id| *id != verif_method::DEFAULT_PROXY_ID)
-                                .cloned()
-                                .collect();
-                        if proxy_ids.is_empty() {
-                                input.verif_method.get_proxy_with_rotation(&email_provider, None)
-                        } else {
-                                let rotator = ProxyRotator::new(
-                                        proxy_ids,
-                                        input.verif_method.proxy_pool.strategy.clone(),
-                                );
-                                input.verif_method.get_proxy_with_rotation(&email_provider, Some(&rotator))
-                        }
                 }
+                is_configured
+        });
+        // Without a shared rotator, the rotation starts over for each request.
+        let provider_pool = all_verif_methods.get_provider_proxy_pool(&email_provider);
+        let local_rotator = if forced_proxy_id.is_none()
+                && provider_pool.is_none()
+                && all_verif_methods.proxy_pool.enabled
+                && input.proxy_rotator.is_none()
+        {
+                tracing::warn!(
+                        target: LOG_TARGET,
+                        email=%to_email,
+                        "Proxy pool is enabled but no shared rotator provided. Creating a new rotator for this request. \
+                        This means round-robin rotation will not work correctly across requests."
+                );
+                let proxy_ids: Vec<String> = input
+                        .verif_method
+                        .proxies
+                        .keys()
+                        .filter(|id| *id != verif_method::DEFAULT_PROXY_ID)
+                        .cloned()
+                        .collect();
+                (!proxy_ids.is_empty()).then(|| {
+                        ProxyRotator::new(proxy_ids, all_verif_methods.proxy_pool.strategy.clone())
+                                .with_weights(&all_verif_methods.proxies)
+                })
+        } else {
+                None
+        };
+        let local_pool_rotators = if forced_proxy_id.is_none()
+                && provider_pool.is_some()
+                && input.proxy_pool_rotators.is_none()
+        {
+                tracing::warn!(
+                        target: LOG_TARGET,
+                        email=%to_email,
+                        pool=?provider_pool,
+                        "No shared proxy pool rotators provided. Creating new rotators for this request."
+                );
+                Some(all_verif_methods.new_pool_rotators())
         } else {
-                input.verif_method.get_proxy_with_rotation(&email_provider, None)
+                None
         };
-        let proxy_data = format_proxy_data(proxy).await;
-        let verif_method = VerifMethodSmtp::new(smtp_verif_method_config.clone(), proxy.cloned());
+        let rotator = all_verif_methods.get_rotator(
+                &email_provider,
+                input.proxy_rotator.as_deref().or(local_rotator.as_ref()),
+                input
+                        .proxy_pool_rotators
+                        .as_deref()
+                        .or(local_pool_rotators.as_ref()),
+        );
+        let mut proxy_id = if forced_proxy_id.is_some() {
+                smtp_verif_method_config.proxy = rules.proxy.clone();
+                forced_proxy_id
+        } else {
+                all_verif_methods.get_proxy_id_for_domain(&email_provider, Some(domain), rotator)
+        };
+        let transcript = input.smtp_transcript.then(SmtpTranscript::default);
 
-        (
-                check_smtp_with_retry(
+        // Rules about the catch-all check take precedence over the cache, and
+        // their outcome isn't cached.
+        let catch_all_cache = input
+                .catch_all_cache
+                .as_deref()
+                .filter(|_| !rules.skip_catch_all && !rules.treat_as_catch_all);
+        // The cache may be remote, so its lookup is bounded by the SMTP timeout
+        // too. If it times out, the catch-all address is probed.
+        let known_catch_all = match (catch_all_cache, smtp_verif_method_config.smtp_timeout) {
+                (Some(cache), Some(smtp_timeout)) => tokio::time::timeout(smtp_timeout, cache.get(domain))
+                        .await
+                        .unwrap_or_else(|_| {
+                                tracing::debug!(
+                                        target: LOG_TARGET,
+                                        email=%to_email,
+                                        timeout=?smtp_timeout,
+                                        "Catch-all cache lookup timed out"
+                                );
+                                None
+                        }),
+                (Some(cache), None) => cache.get(domain).await,
+                (None, _) => None,
+        };
+
+        // When retrying through other proxies, the total time of the
+        // verification is bounded, as each proxy could take the whole SMTP
+        // timeout.
+        let retry_config = &all_verif_methods.proxy_pool.retry;
+        let deadline = retry_config
+                .enabled
+                .then(|| tokio::time::Instant::now() + retry_config.timeout());
+        let mut proxy_attempts = Vec::new();
+        let mut tried_proxy_ids = Vec::new();
+        loop {
+                let proxy = proxy_id.and_then(|proxy_id| all_verif_methods.proxies.get(proxy_id));
+                let proxy_data = format_proxy_data(proxy).await;
+                let verif_method = VerifMethodSmtp::new(smtp_verif_method_config.clone(), proxy.cloned());
+                let in_flight = rotator.zip(proxy_id).map(|(rotator, proxy_id)| rotator.track_in_flight(proxy_id));
+
+                let attempt = check_smtp_with_retry(
                         to_email,
                         &host_str,
                         domain,
                         &verif_method,
-                        verif_method.config.retries,
-                )
-                .await,
-                SmtpDebug {
-                        verif_method: SmtpDebugVerifMethod::Smtp(SmtpDebugVerifMethodSmtp {
-                                host: host_str,
-                                verif_method: smtp_verif_method_config,
-                                proxy_data,
-                        }),
-                },
-        )
+                        transcript.as_ref(),
+                        &input.greylisting,
+                        SmtpShared {
+                                session_pool: input.smtp_session_pool.as_deref(),
+                                catch_all_cache,
+                                known_catch_all,
+                        },
+                        timings,
+                );
+                let (result, retries, timed_out) = match deadline {
+                        Some(deadline) => match tokio::time::timeout_at(deadline, attempt).await {
+                                Ok((result, retries)) => (result, retries, false),
+                                Err(_) => (
+                                        Err(SmtpError::Timeout(retry_config.timeout())),
+                                        SmtpDebugRetries::default(),
+                                        true,
+                                ),
+                        },
+                        None => {
+                                let (result, retries) = attempt.await;
+                                (result, retries, false)
+                        }
+                };
+                drop(in_flight);
+
+                // Feed the health of the proxy back to the rotator, so that it
+                // skips the proxies which keep failing. An attempt cut short by
+                // the total timeout says nothing about the proxy.
+                if let (Some(rotator), Some(proxy_id), false) = (rotator, proxy_id, timed_out) {
+                        match &result {
+                                Err(err) if err.is_proxy_failure() => rotator.record_failure(proxy_id),
+                                // The SMTP server answered through the proxy.
+                                Ok(_) | Err(SmtpError::AsyncSmtpError(_)) => rotator.record_success(proxy_id),
+                                Err(_) => {}
+                        }
+                }
+
+                // If the proxy is blocked, try again through another one of the
+                // pool, if the retry policy and the total timeout allow it.
+                let retry_proxy_id = match (&result, proxy_id) {
+                        (Err(err), Some(id))
+                                if forced_proxy_id.is_none()
+                                        && err.is_proxy_blocked()
+                                        && deadline.is_none_or(|deadline| tokio::time::Instant::now() < deadline) =>
+                        {
+                                tried_proxy_ids.push(id);
+                                all_verif_methods.get_retry_proxy_id(&email_provider, rotator, &tried_proxy_ids)
+                        }
+                        _ => None,
+                };
+                if let Some(id) = proxy_id {
+                        proxy_attempts.push(SmtpDebugProxyAttempt {
+                                proxy_id: id.clone(),
+                                error: result.as_ref().err().map(ToString::to_string),
+                        });
+                }
+
+                if retry_proxy_id.is_some() {
+                        tracing::debug!(
+                                target: LOG_TARGET,
+                                email=%to_email,
+                                proxy_id=?proxy_id,
+                                retry_proxy_id=?retry_proxy_id,
+                                "Proxy blocked, retrying through another one"
+                        );
+                        proxy_id = retry_proxy_id;
+                        continue;
+                }
+
+                return (
+                        result,
+                        SmtpDebug {
+                                verif_method: SmtpDebugVerifMethod::Smtp(SmtpDebugVerifMethodSmtp {
+                                        host: host_str,
+                                        verif_method: smtp_verif_method_config,
+                                        proxy_data,
+                                }),
+                                proxy_attempts,
+                                transcript: transcript.map(|t| t.entries()),
+                                retries,
+                                catch_all_cache_hit: known_catch_all.is_some(),
+                                ..Default::default()
+                        },
+                );
+        }
 }
 
 #[cfg(test)]
@@ -301,7 +659,13 @@ mod tests {
                         .build()
                         .unwrap();
 
-                let (res, smtp_debug) = runtime.block_on(check_smtp(&to_email, &host, "gmail.com", &input));
+                let (res, smtp_debug) = runtime.block_on(check_smtp(
+                        &to_email,
+                        &host,
+                        "gmail.com",
+                        &input,
+                        &mut StageTimings::default(),
+                ));
                 match smtp_debug.verif_method {
                         SmtpDebugVerifMethod::Smtp(SmtpDebugVerifMethodSmtp { host, verif_method, proxy_data }) => {
                                 assert_eq!(host, "alt4.aspmx.l.google.com.");
@@ -319,4 +683,24 @@ mod tests {
                         _ => panic!("check_smtp did not time out"),
                 }
         }
+
+        #[tokio::test]
+        async fn should_fail_without_mx_host() {
+                let to_email = EmailAddress::from_str("foo@example.com").unwrap();
+                let input = CheckEmailInputBuilder::default()
+                        .to_email("foo@example.com".into())
+                        .build()
+                        .unwrap();
+
+                let (res, smtp_debug) = check_smtp_with_mx_fallback(
+                        &to_email,
+                        &[],
+                        "example.com",
+                        &input,
+                        &mut StageTimings::default(),
+                )
+                .await;
+                assert!(matches!(res, Err(SmtpError::AnyhowError(_))));
+                assert!(smtp_debug.mx_attempts.is_empty());
+        }
 }
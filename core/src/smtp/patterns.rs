@@ -0,0 +1,324 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Database of the SMTP reply texts we know the meaning of, used by the
+//! reply classifier when the reply codes are not conclusive.
+//!
+//! The built-in patterns are read from `patterns.json`. A user-supplied file
+//! with the same format can extend (or replace) them, see [init_patterns].
+//! Each pattern is either a case-insensitive substring (`"contains"`) or a
+//! regular expression (`"regex"`), optionally tagged with the language of
+//! the reply (`"lang"`, defaults to "en").
+
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+
+/// The categories of SMTP replies we have patterns for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternCategory {
+	/// The mailbox doesn't exist.
+	Invalid,
+	/// The mailbox is full.
+	FullInbox,
+	/// The mailbox is disabled or blocked by the provider.
+	Disabled,
+	/// Our IP is on a blacklist.
+	IpBlacklisted,
+	/// Our IP needs a reverse DNS entry.
+	NeedsRdns,
+	/// The server greylisted us.
+	Greylisted,
+	/// We are sending too many requests to the server.
+	RateLimited,
+	/// The mailbox exists, but receives too many emails.
+	RecipientRateLimited,
+}
+
+/// One pattern, as written in a patterns file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PatternEntry {
+	/// Match if the reply contains this text, case-insensitively.
+	contains: Option<String>,
+	/// Match if the reply matches this regular expression,
+	/// case-insensitively.
+	regex: Option<String>,
+	/// The language of the reply, e.g. "en" or "fr".
+	lang: Option<String>,
+	#[serde(rename = "_comment")]
+	comment: Option<String>,
+}
+
+/// The content of a patterns file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PatternsFile {
+	/// If set in a user-supplied file, its patterns replace the built-in
+	/// ones instead of extending them.
+	#[serde(default)]
+	replace_defaults: bool,
+	#[serde(default)]
+	invalid: Vec<PatternEntry>,
+	#[serde(default)]
+	full_inbox: Vec<PatternEntry>,
+	#[serde(default)]
+	disabled: Vec<PatternEntry>,
+	#[serde(default)]
+	ip_blacklisted: Vec<PatternEntry>,
+	#[serde(default)]
+	needs_rdns: Vec<PatternEntry>,
+	#[serde(default)]
+	greylisted: Vec<PatternEntry>,
+	#[serde(default)]
+	rate_limited: Vec<PatternEntry>,
+	#[serde(default)]
+	recipient_rate_limited: Vec<PatternEntry>,
+}
+
+impl PatternsFile {
+	fn category(&self, category: PatternCategory) -> &[PatternEntry] {
+		match category {
+			PatternCategory::Invalid => &self.invalid,
+			PatternCategory::FullInbox => &self.full_inbox,
+			PatternCategory::Disabled => &self.disabled,
+			PatternCategory::IpBlacklisted => &self.ip_blacklisted,
+			PatternCategory::NeedsRdns => &self.needs_rdns,
+			PatternCategory::Greylisted => &self.greylisted,
+			PatternCategory::RateLimited => &self.rate_limited,
+			PatternCategory::RecipientRateLimited => &self.recipient_rate_limited,
+		}
+	}
+}
+
+/// Error when loading a patterns file.
+#[derive(Debug, Error)]
+pub enum PatternsError {
+	#[error("Cannot read SMTP patterns file {0}: {1}")]
+	Io(PathBuf, std::io::Error),
+	#[error("Invalid SMTP patterns file: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("Invalid SMTP pattern in \"{0}\": {1}")]
+	InvalidPattern(&'static str, String),
+}
+
+/// Configuration of the SMTP reply patterns.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SmtpPatternsConfig {
+	/// Path to a JSON file with additional patterns, in the same format as
+	/// the built-in `patterns.json`.
+	pub file: Option<PathBuf>,
+	/// Only use the patterns of these languages. If empty, all patterns are
+	/// used.
+	pub languages: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Matcher {
+	/// A lowercased substring.
+	Contains(String),
+	Regex(Regex),
+}
+
+/// The compiled patterns, by category.
+#[derive(Debug, Default)]
+pub struct SmtpPatterns {
+	patterns: Vec<(PatternCategory, Matcher)>,
+}
+
+const CATEGORIES: &[(PatternCategory, &str)] = &[
+	(PatternCategory::Invalid, "invalid"),
+	(PatternCategory::FullInbox, "full_inbox"),
+	(PatternCategory::Disabled, "disabled"),
+	(PatternCategory::IpBlacklisted, "ip_blacklisted"),
+	(PatternCategory::NeedsRdns, "needs_rdns"),
+	(PatternCategory::Greylisted, "greylisted"),
+	(PatternCategory::RateLimited, "rate_limited"),
+	(
+		PatternCategory::RecipientRateLimited,
+		"recipient_rate_limited",
+	),
+];
+
+impl SmtpPatterns {
+	/// Compile the patterns of the given files, keeping only those of the
+	/// given languages (all if empty).
+	fn compile(files: &[&PatternsFile], languages: &[String]) -> Result<Self, PatternsError> {
+		let mut patterns = vec![];
+		for (category, name) in CATEGORIES {
+			for entry in files.iter().flat_map(|f| f.category(*category)) {
+				let lang = entry.lang.as_deref().unwrap_or("en");
+				let matcher = match (&entry.contains, &entry.regex) {
+					(Some(text), None) if !text.is_empty() => {
+						Matcher::Contains(text.to_lowercase())
+					}
+					(None, Some(regex)) => Matcher::Regex(
+						RegexBuilder::new(regex)
+							.case_insensitive(true)
+							.build()
+							.map_err(|e| PatternsError::InvalidPattern(name, e.to_string()))?,
+					),
+					_ => return Err(PatternsError::InvalidPattern(
+						name,
+						"each pattern must have exactly one non-empty \"contains\" or \"regex\""
+							.into(),
+					)),
+				};
+				if languages.is_empty() || languages.iter().any(|l| l == lang) {
+					patterns.push((*category, matcher));
+				}
+			}
+		}
+
+		Ok(SmtpPatterns { patterns })
+	}
+
+	/// Does the reply text match any pattern of the given category?
+	pub fn matches(&self, category: PatternCategory, text: &str) -> bool {
+		let lowercase = text.to_lowercase();
+		self.patterns
+			.iter()
+			.filter(|(c, _)| *c == category)
+			.any(|(_, matcher)| match matcher {
+				Matcher::Contains(s) => lowercase.contains(s.as_str()),
+				Matcher::Regex(r) => r.is_match(text),
+			})
+	}
+}
+
+static DEFAULT_PATTERNS: Lazy<PatternsFile> = Lazy::new(|| {
+	serde_json::from_str(include_str!("patterns.json")).expect("Built-in patterns are valid. qed.")
+});
+
+static PATTERNS: Lazy<RwLock<Arc<SmtpPatterns>>> = Lazy::new(|| {
+	RwLock::new(Arc::new(
+		SmtpPatterns::compile(&[&DEFAULT_PATTERNS], &[])
+			.expect("Built-in patterns are valid. qed."),
+	))
+});
+
+/// The patterns currently used by the SMTP reply classifier.
+pub fn current_patterns() -> Arc<SmtpPatterns> {
+	PATTERNS
+		.read()
+		.map(|p| p.clone())
+		.unwrap_or_else(|e| e.into_inner().clone())
+}
+
+/// Load the patterns of the given configuration, validating the
+/// user-supplied file if any. This doesn't change the patterns in use, see
+/// [init_patterns].
+pub fn load_patterns(config: &SmtpPatternsConfig) -> Result<SmtpPatterns, PatternsError> {
+	let user_file = match &config.file {
+		Some(path) => {
+			let content =
+				std::fs::read_to_string(path).map_err(|e| PatternsError::Io(path.clone(), e))?;
+			Some(serde_json::from_str::<PatternsFile>(&content)?)
+		}
+		None => None,
+	};
+
+	match &user_file {
+		Some(file) if file.replace_defaults => SmtpPatterns::compile(&[file], &config.languages),
+		Some(file) => SmtpPatterns::compile(&[&DEFAULT_PATTERNS, file], &config.languages),
+		None => SmtpPatterns::compile(&[&DEFAULT_PATTERNS], &config.languages),
+	}
+}
+
+/// Load the patterns of the given configuration, and use them for all
+/// subsequent email verifications. This should be called once at startup,
+/// so that an invalid patterns file is reported early.
+pub fn init_patterns(config: &SmtpPatternsConfig) -> Result<(), PatternsError> {
+	let patterns = Arc::new(load_patterns(config)?);
+	match PATTERNS.write() {
+		Ok(mut p) => *p = patterns,
+		Err(e) => *e.into_inner() = patterns,
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::util::temp_file;
+
+	#[test]
+	fn should_match_built_in_patterns() {
+		let patterns = current_patterns();
+		assert!(patterns.matches(PatternCategory::Invalid, "550 5.1.1 User Unknown"));
+		assert!(patterns.matches(
+			PatternCategory::Invalid,
+			"permanent: 5.1.1 MXIN501 mailbox foo@bar.baz unknown"
+		));
+		assert!(!patterns.matches(PatternCategory::FullInbox, "550 5.1.1 User unknown"));
+	}
+
+	#[test]
+	fn should_extend_and_filter_by_language() {
+		let file =
+			temp_file(r#"{"invalid": [{"regex": "benutzer \\S+ unbekannt", "lang": "de"}]}"#);
+		let config = SmtpPatternsConfig {
+			file: Some(file.path().to_path_buf()),
+			languages: vec![],
+		};
+		let patterns = load_patterns(&config).unwrap();
+		assert!(patterns.matches(PatternCategory::Invalid, "Benutzer foo unbekannt"));
+		assert!(patterns.matches(PatternCategory::Invalid, "User unknown"));
+
+		let patterns = load_patterns(&SmtpPatternsConfig {
+			languages: vec!["de".into()],
+			..config
+		})
+		.unwrap();
+		assert!(patterns.matches(PatternCategory::Invalid, "Benutzer foo unbekannt"));
+		assert!(!patterns.matches(PatternCategory::Invalid, "User unknown"));
+	}
+
+	#[test]
+	fn should_replace_defaults() {
+		let file = temp_file(r#"{"replace_defaults": true, "invalid": [{"contains": "nope"}]}"#);
+		let patterns = load_patterns(&SmtpPatternsConfig {
+			file: Some(file.path().to_path_buf()),
+			..Default::default()
+		})
+		.unwrap();
+		assert!(patterns.matches(PatternCategory::Invalid, "NOPE"));
+		assert!(!patterns.matches(PatternCategory::Invalid, "User unknown"));
+	}
+
+	#[test]
+	fn should_reject_invalid_files() {
+		for content in [
+			r#"{"invalid": [{"regex": "("}]}"#,
+			r#"{"invalid": [{"contains": "a", "regex": "b"}]}"#,
+			r#"{"invalid": [{"contains": ""}]}"#,
+			r#"{"unknown_category": []}"#,
+		]
+		.iter()
+		{
+			let file = temp_file(content);
+			let config = SmtpPatternsConfig {
+				file: Some(file.path().to_path_buf()),
+				..Default::default()
+			};
+			assert!(load_patterns(&config).is_err(), "{}", content);
+		}
+	}
+}
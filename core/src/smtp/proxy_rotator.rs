@@ -14,18 +14,177 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+//! Rotation of the SMTP verifications through a pool of proxies, skipping the
+//! proxies that keep failing, see [ProxyHealthConfig].
+
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use rand::seq::SliceRandom;
+use fast_socks5::client::{Config, Socks5Stream};
+use futures::StreamExt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 use super::verif_method::ProxyRotationStrategy;
+use crate::util::input_output::CheckEmailInputProxy;
+use crate::LOG_TARGET;
+
+/// Maximum number of proxies probed at the same time.
+const PROBE_CONCURRENCY: usize = 16;
+
+/// Configuration of the proxy health checks. A proxy failing too many
+/// verifications in a row is ejected from the rotation for a cool-down
+/// period, which doubles each time it's ejected again, like a circuit
+/// breaker. An ejected proxy gets a new chance after its cool-down, or as
+/// soon as a background probe succeeds.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ProxyHealthConfig {
+        /// Whether to track the health of the proxies, and eject the unhealthy
+        /// ones.
+        ///
+        /// Defaults to true.
+        pub enabled: bool,
+        /// Number of consecutive failures after which a proxy is ejected. A
+        /// failure is a SOCKS5 error, a timeout, or a blacklisted IP, see
+        /// [SmtpError::is_proxy_failure](super::SmtpError::is_proxy_failure).
+        ///
+        /// Defaults to 5.
+        pub failure_threshold: u32,
+        /// How long a proxy is ejected the first time, in seconds.
+        ///
+        /// Defaults to 30.
+        pub cooldown_secs: u64,
+        /// Maximum time a proxy is ejected, in seconds.
+        ///
+        /// Defaults to 600, i.e. 10 minutes.
+        pub max_cooldown_secs: u64,
+        /// Interval between two background probes of all the proxies, in
+        /// seconds. A probe opens a SOCKS5 connection to `probe_host` through
+        /// the proxy. 0 disables the probes.
+        ///
+        /// Defaults to 0.
+        pub probe_interval_secs: u64,
+        /// Host the probes connect to through the proxies.
+        ///
+        /// Defaults to "gmail-smtp-in.l.google.com".
+        pub probe_host: String,
+        /// Port the probes connect to through the proxies.
+        ///
+        /// Defaults to 25.
+        pub probe_port: u16,
+        /// Timeout of a probe, in seconds.
+        ///
+        /// Defaults to 10.
+        pub probe_timeout_secs: u64,
+}
+
+impl Default for ProxyHealthConfig {
+        fn default() -> Self {
+                Self {
+                        enabled: true,
+                        failure_threshold: 5,
+                        cooldown_secs: 30,
+                        max_cooldown_secs: 600,
+                        probe_interval_secs: 0,
+                        probe_host: "gmail-smtp-in.l.google.com".into(),
+                        probe_port: 25,
+                        probe_timeout_secs: 10,
+                }
+        }
+}
+
+impl ProxyHealthConfig {
+        /// How long a proxy is ejected, given how many times in a row it was
+        /// already ejected.
+        fn cooldown(&self, previous_ejections: u32) -> Duration {
+                let secs = self
+                        .cooldown_secs
+                        .saturating_mul(1u64 << previous_ejections.min(32))
+                        .min(self.max_cooldown_secs);
+                Duration::from_secs(secs)
+        }
+}
+
+/// What we know about the health of a proxy.
+#[derive(Debug, Clone, Default)]
+struct HealthState {
+        consecutive_failures: u32,
+        successes: u64,
+        failures: u64,
+        /// Number of times the proxy was ejected since its last success.
+        ejections: u32,
+        ejected_until: Option<Instant>,
+}
+
+impl HealthState {
+        fn is_ejected(&self, now: Instant) -> bool {
+                matches!(self.ejected_until, Some(until) if until > now)
+        }
+}
 
-/// A thread-safe proxy rotator that cycles through a list of proxy IDs.
-/// Supports round-robin and random selection strategies.
+/// The rotators of the named proxy pools, by pool name, see
+/// [VerifMethod::proxy_pools](super::verif_method::VerifMethod::proxy_pools).
+pub type ProxyPoolRotators = HashMap<String, ProxyRotator>;
+
+/// Health of a proxy of the rotation, see [ProxyRotator::health].
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+pub struct ProxyHealth {
+        pub proxy_id: String,
+        /// The named proxy pool the proxy belongs to, None for the global
+        /// proxy pool. A proxy in several pools is tracked in each of them.
+        #[serde(default)]
+        pub pool: Option<String>,
+        /// Whether the proxy is currently in the rotation.
+        pub healthy: bool,
+        pub consecutive_failures: u32,
+        /// Total number of successful verifications and probes.
+        pub successes: u64,
+        /// Total number of failed verifications and probes.
+        pub failures: u64,
+        /// Number of times the proxy was ejected since its last success.
+        pub ejections: u32,
+        /// How long the proxy stays ejected, in seconds rounded up, if it is.
+        pub ejected_for_secs: Option<u64>,
+        /// Number of verifications currently going through the proxy.
+        pub in_flight: usize,
+}
+
+/// Counts a verification as in flight through a proxy until dropped, see
+/// [ProxyRotator::track_in_flight].
+pub struct InFlightGuard<'a> {
+        counter: Option<&'a AtomicUsize>,
+}
+
+impl Drop for InFlightGuard<'_> {
+        fn drop(&mut self) {
+                if let Some(counter) = self.counter {
+                        counter.fetch_sub(1, Ordering::SeqCst);
+                }
+        }
+}
+
+/// A thread-safe proxy rotator that cycles through a list of proxy IDs,
+/// following one of the [ProxyRotationStrategy]. Unhealthy proxies are
+/// skipped, see [ProxyHealthConfig].
 pub struct ProxyRotator {
         proxy_ids: Vec<String>,
         counter: AtomicUsize,
         strategy: ProxyRotationStrategy,
+        health_config: ProxyHealthConfig,
+        /// Index of each proxy ID in `proxy_ids`.
+        indexes: HashMap<String, usize>,
+        /// Health of each proxy, in the order of `proxy_ids`.
+        health: Mutex<Vec<HealthState>>,
+        /// Weight of each proxy for the weighted strategy, in the order of
+        /// `proxy_ids`.
+        weights: Vec<u32>,
+        /// Number of verifications in flight through each proxy, in the order
+        /// of `proxy_ids`. They're shared with the rotators built by
+        /// [with_state_from](Self::with_state_from).
+        in_flight: Vec<Arc<AtomicUsize>>,
 }
 
 impl std::fmt::Debug for ProxyRotator {
@@ -34,35 +193,311 @@ impl std::fmt::Debug for ProxyRotator {
                         .field("proxy_ids_count", &self.proxy_ids.len())
                         .field("counter", &self.counter.load(std::sync::atomic::Ordering::SeqCst))
                         .field("strategy", &self.strategy)
+                        .field("health_config", &self.health_config)
                         .finish()
         }
 }
 
 impl ProxyRotator {
-        /// Create a new ProxyRotator with the given proxy IDs and rotation strategy.
+        /// Create a new ProxyRotator with the given proxy IDs and rotation
+        /// strategy, and the default health checks.
         pub fn new(proxy_ids: Vec<String>, strategy: ProxyRotationStrategy) -> Self {
+                let indexes = proxy_ids
+                        .iter()
+                        .enumerate()
+                        .map(|(index, proxy_id)| (proxy_id.clone(), index))
+                        .collect();
+                let health = Mutex::new(vec![HealthState::default(); proxy_ids.len()]);
+                let weights = vec![1; proxy_ids.len()];
+                let in_flight = proxy_ids.iter().map(|_| Arc::new(AtomicUsize::new(0))).collect();
                 Self {
                         proxy_ids,
                         counter: AtomicUsize::new(0),
                         strategy,
+                        health_config: ProxyHealthConfig::default(),
+                        indexes,
+                        health,
+                        weights,
+                        in_flight,
                 }
         }
 
-        /// Get the next proxy ID based on the rotation strategy.
+        /// Use the `weight` of each proxy for the weighted strategy. Proxies
+        /// missing from `proxies` keep a weight of 1.
+        pub fn with_weights(mut self, proxies: &HashMap<String, CheckEmailInputProxy>) -> Self {
+                for (proxy_id, weight) in self.proxy_ids.iter().zip(self.weights.iter_mut()) {
+                        if let Some(proxy) = proxies.get(proxy_id) {
+                                *weight = proxy.weight.unwrap_or(1);
+                        }
+                }
+                self
+        }
+
+        /// Use the given health checks configuration.
+        pub fn with_health_config(mut self, health_config: ProxyHealthConfig) -> Self {
+                self.health_config = health_config;
+                self
+        }
+
+        /// Keep the state of `previous`, e.g. the rotator of the proxy list
+        /// before it was reloaded: the health and in-flight verifications of
+        /// the proxies found in both rotators, and the round-robin position.
+        pub fn with_state_from(self, previous: &ProxyRotator) -> Self {
+                let mut in_flight = self.in_flight;
+                {
+                        let previous_health = previous.health.lock().expect("Proxy health lock poisoned. qed.");
+                        let mut health = self.health.lock().expect("Proxy health lock poisoned. qed.");
+                        for (index, proxy_id) in self.proxy_ids.iter().enumerate() {
+                                if let Some(previous_index) = previous.indexes.get(proxy_id) {
+                                        health[index] = previous_health[*previous_index].clone();
+                                        in_flight[index] = previous.in_flight[*previous_index].clone();
+                                }
+                        }
+                }
+                self.counter
+                        .store(previous.counter.load(Ordering::SeqCst), Ordering::SeqCst);
+                Self { in_flight, ..self }
+        }
+
+        pub fn health_config(&self) -> &ProxyHealthConfig {
+                &self.health_config
+        }
+
+        /// Get the next proxy ID based on the rotation strategy, skipping the
+        /// ejected proxies. If all the proxies are ejected, the one whose
+        /// cool-down ends first is returned.
         /// Returns None if there are no proxies configured.
+        ///
+        /// The sticky-per-domain strategy falls back to round-robin, see
+        /// [get_proxy_id_for_domain](Self::get_proxy_id_for_domain).
         pub fn get_next_proxy_id(&self) -> Option<&String> {
-                if self.proxy_ids.is_empty() {
+                self.pick(None, &[])
+        }
+
+        /// Same as [get_next_proxy_id](Self::get_next_proxy_id), but with the
+        /// sticky-per-domain strategy, the verifications of a domain always go
+        /// through the same proxy, as long as it's healthy.
+        pub fn get_proxy_id_for_domain(&self, domain: &str) -> Option<&String> {
+                self.pick(Some(domain), &[])
+        }
+
+        /// Same as [get_next_proxy_id](Self::get_next_proxy_id), but never
+        /// returns the `excluded` proxies, e.g. the ones a verification already
+        /// went through.
+        pub fn get_next_proxy_id_excluding(&self, excluded: &[&String]) -> Option<&String> {
+                self.pick(None, excluded)
+        }
+
+        fn pick(&self, domain: Option<&str>, excluded: &[&String]) -> Option<&String> {
+                let len = self.proxy_ids.len();
+                if len == 0 {
                         return None;
                 }
+                let is_excluded = |index: &usize| excluded.contains(&&self.proxy_ids[*index]);
+
+                // Where to start looking for a healthy proxy.
+                let start = match (&self.strategy, domain) {
+                        (ProxyRotationStrategy::Random, _) | (ProxyRotationStrategy::Weighted, _) => {
+                                rand::thread_rng().gen_range(0..len)
+                        }
+                        (ProxyRotationStrategy::StickyPerDomain, Some(domain)) => {
+                                (fnv1a(&domain.to_lowercase()) % len as u64) as usize
+                        }
+                        _ => self.counter.fetch_add(1, Ordering::SeqCst) % len,
+                };
 
-                match self.strategy {
-                        ProxyRotationStrategy::RoundRobin => {
-                                let index = self.counter.fetch_add(1, Ordering::SeqCst) % self.proxy_ids.len();
-                                self.proxy_ids.get(index)
+                let now = Instant::now();
+                let health = self.health.lock().expect("Proxy health lock poisoned. qed.");
+                let mut candidates = (0..len)
+                        .map(|offset| (start + offset) % len)
+                        .filter(|index| !is_excluded(index))
+                        .filter(|index| !self.health_config.enabled || !health[*index].is_ejected(now));
+                let index = match self.strategy {
+                        ProxyRotationStrategy::Weighted => {
+                                let candidates: Vec<usize> = candidates.collect();
+                                let total: u64 = candidates.iter().map(|index| u64::from(self.weights[*index])).sum();
+                                if total == 0 {
+                                        candidates.first().copied()
+                                } else {
+                                        let mut target = rand::thread_rng().gen_range(0..total);
+                                        candidates.into_iter().find(|index| {
+                                                let weight = u64::from(self.weights[*index]);
+                                                if target < weight {
+                                                        true
+                                                } else {
+                                                        target -= weight;
+                                                        false
+                                                }
+                                        })
+                                }
                         }
-                        ProxyRotationStrategy::Random => {
-                                self.proxy_ids.choose(&mut rand::thread_rng())
+                        // Ties go to the first proxy in round-robin order.
+                        ProxyRotationStrategy::LeastInFlight => {
+                                candidates.min_by_key(|index| self.in_flight[*index].load(Ordering::SeqCst))
+                        }
+                        _ => candidates.next(),
+                }
+                .or_else(|| {
+                        (0..len)
+                                .filter(|index| !is_excluded(index))
+                                .min_by_key(|index| health[*index].ejected_until)
+                })?;
+                self.proxy_ids.get(index)
+        }
+
+        /// Count a verification as in flight through the proxy, until the
+        /// returned guard is dropped. This is what the least-in-flight
+        /// strategy balances.
+        pub fn track_in_flight(&self, proxy_id: &str) -> InFlightGuard<'_> {
+                let counter = self.indexes.get(proxy_id).map(|index| &*self.in_flight[*index]);
+                if let Some(counter) = counter {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                }
+                InFlightGuard { counter }
+        }
+
+        /// Record that a verification or a probe through the proxy succeeded.
+        /// This brings an ejected proxy back into the rotation.
+        pub fn record_success(&self, proxy_id: &str) {
+                self.update_health(proxy_id, |state, _| {
+                        state.successes += 1;
+                        state.consecutive_failures = 0;
+                        state.ejections = 0;
+                        state.ejected_until = None;
+                });
+        }
+
+        /// Record that a verification or a probe through the proxy failed
+        /// because of the proxy. The proxy is ejected if it failed too many
+        /// times in a row.
+        pub fn record_failure(&self, proxy_id: &str) {
+                self.update_health(proxy_id, |state, config| {
+                        state.failures += 1;
+                        state.consecutive_failures += 1;
+
+                        // Verifications started before the ejection may still fail,
+                        // they shouldn't extend it.
+                        let now = Instant::now();
+                        if state.consecutive_failures >= config.failure_threshold && !state.is_ejected(now) {
+                                let cooldown = config.cooldown(state.ejections);
+                                state.ejections += 1;
+                                state.ejected_until = Some(now + cooldown);
+                                tracing::warn!(
+                                        target: LOG_TARGET,
+                                        proxy_id=proxy_id,
+                                        consecutive_failures=state.consecutive_failures,
+                                        cooldown=?cooldown,
+                                        "Ejecting unhealthy proxy from the rotation"
+                                );
+                        }
+                });
+        }
+
+        fn update_health(&self, proxy_id: &str, update: impl FnOnce(&mut HealthState, &ProxyHealthConfig)) {
+                if !self.health_config.enabled {
+                        return;
+                }
+                // Proxies outside of the rotation, e.g. forced by a rule, aren't
+                // tracked.
+                if let Some(index) = self.indexes.get(proxy_id) {
+                        let mut health = self.health.lock().expect("Proxy health lock poisoned. qed.");
+                        update(&mut health[*index], &self.health_config);
+                }
+        }
+
+        /// The health of each proxy of the rotation.
+        pub fn health(&self) -> Vec<ProxyHealth> {
+                let now = Instant::now();
+                let health = self.health.lock().expect("Proxy health lock poisoned. qed.");
+                self.proxy_ids
+                        .iter()
+                        .zip(health.iter())
+                        .zip(self.in_flight.iter())
+                        .map(|((proxy_id, state), in_flight)| ProxyHealth {
+                                proxy_id: proxy_id.clone(),
+                                pool: None,
+                                healthy: !state.is_ejected(now),
+                                consecutive_failures: state.consecutive_failures,
+                                successes: state.successes,
+                                failures: state.failures,
+                                ejections: state.ejections,
+                                ejected_for_secs: state
+                                        .ejected_until
+                                        .filter(|until| *until > now)
+                                        .map(|until| {
+                                                let left = until - now;
+                                                left.as_secs() + u64::from(left.subsec_nanos() > 0)
+                                        }),
+                                in_flight: in_flight.load(Ordering::SeqCst),
+                        })
+                        .collect()
+        }
+
+        /// Probe all the proxies once, by opening a SOCKS5 connection to the
+        /// probe host through each of them, and record the outcomes.
+        pub async fn probe(&self, proxies: &HashMap<String, CheckEmailInputProxy>) {
+                futures::stream::iter(&self.proxy_ids)
+                        .filter_map(|proxy_id| async move { proxies.get(proxy_id).map(|proxy| (proxy_id, proxy)) })
+                        .for_each_concurrent(PROBE_CONCURRENCY, |(proxy_id, proxy)| async move {
+                                match self.probe_proxy(proxy).await {
+                                        Ok(()) => self.record_success(proxy_id),
+                                        Err(error) => {
+                                                tracing::debug!(
+                                                        target: LOG_TARGET,
+                                                        proxy_id=proxy_id,
+                                                        error=error,
+                                                        "Proxy probe failed"
+                                                );
+                                                self.record_failure(proxy_id);
+                                        }
+                                }
+                        })
+                        .await;
+        }
+
+        async fn probe_proxy(&self, proxy: &CheckEmailInputProxy) -> Result<(), String> {
+                let config = &self.health_config;
+                let connect = async {
+                        match (&proxy.username, &proxy.password) {
+                                (Some(username), Some(password)) => {
+                                        Socks5Stream::connect_with_password(
+                                                (proxy.host.as_ref(), proxy.port),
+                                                config.probe_host.clone(),
+                                                config.probe_port,
+                                                username.clone(),
+                                                password.clone(),
+                                                Config::default(),
+                                        )
+                                        .await
+                                }
+                                _ => {
+                                        Socks5Stream::connect(
+                                                (proxy.host.as_ref(), proxy.port),
+                                                config.probe_host.clone(),
+                                                config.probe_port,
+                                                Config::default(),
+                                        )
+                                        .await
+                                }
                         }
+                };
+                match tokio::time::timeout(Duration::from_secs(config.probe_timeout_secs), connect).await {
+                        Ok(Ok(_)) => Ok(()),
+                        Ok(Err(error)) => Err(error.to_string()),
+                        Err(_) => Err("Timeout".into()),
+                }
+        }
+
+        /// Probe all the proxies every `probe_interval_secs`, forever. Returns
+        /// immediately if the probes are disabled.
+        pub async fn run_probes(&self, proxies: &HashMap<String, CheckEmailInputProxy>) {
+                if !self.health_config.enabled || self.health_config.probe_interval_secs == 0 {
+                        return;
+                }
+                let mut interval = tokio::time::interval(Duration::from_secs(self.health_config.probe_interval_secs));
+                loop {
+                        interval.tick().await;
+                        self.probe(proxies).await;
                 }
         }
 
@@ -77,6 +512,15 @@ impl ProxyRotator {
         }
 }
 
+/// 64-bit FNV-1a hash, which unlike the standard library's hasher is stable
+/// across processes and Rust releases, so that a domain keeps its proxy with
+/// the sticky-per-domain strategy.
+fn fnv1a(value: &str) -> u64 {
+        value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
 #[cfg(test)]
 mod tests {
         use super::*;
@@ -133,4 +577,176 @@ mod tests {
                 assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy1".to_string()));
                 assert_eq!(rotator.len(), 1);
         }
+
+        fn rotator_with_threshold(failure_threshold: u32) -> ProxyRotator {
+                ProxyRotator::new(
+                        vec!["proxy1".to_string(), "proxy2".to_string()],
+                        ProxyRotationStrategy::RoundRobin,
+                )
+                .with_health_config(ProxyHealthConfig {
+                        failure_threshold,
+                        ..Default::default()
+                })
+        }
+
+        #[test]
+        fn test_eject_unhealthy_proxy() {
+                let rotator = rotator_with_threshold(2);
+
+                rotator.record_failure("proxy1");
+                assert!(rotator.health()[0].healthy);
+                rotator.record_failure("proxy1");
+                let health = rotator.health();
+                assert!(!health[0].healthy);
+                assert_eq!(health[0].ejected_for_secs.map(|secs| secs <= 30), Some(true));
+
+                // proxy1 is skipped.
+                for _ in 0..4 {
+                        assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy2".to_string()));
+                }
+
+                // A success brings it back.
+                rotator.record_success("proxy1");
+                assert!(rotator.health()[0].healthy);
+                assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy1".to_string()));
+        }
+
+        #[test]
+        fn test_all_proxies_ejected() {
+                let rotator = rotator_with_threshold(1);
+                rotator.record_failure("proxy2");
+                rotator.record_failure("proxy1");
+
+                // proxy2 comes back first.
+                assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy2".to_string()));
+                assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy2".to_string()));
+        }
+
+        #[test]
+        fn test_exponential_cooldown() {
+                let config = ProxyHealthConfig::default();
+                assert_eq!(config.cooldown(0), Duration::from_secs(30));
+                assert_eq!(config.cooldown(1), Duration::from_secs(60));
+                assert_eq!(config.cooldown(3), Duration::from_secs(240));
+                assert_eq!(config.cooldown(10), Duration::from_secs(600));
+                assert_eq!(config.cooldown(100), Duration::from_secs(600));
+        }
+
+        #[test]
+        fn test_ignore_health_if_disabled() {
+                let rotator = ProxyRotator::new(
+                        vec!["proxy1".to_string(), "proxy2".to_string()],
+                        ProxyRotationStrategy::RoundRobin,
+                )
+                .with_health_config(ProxyHealthConfig {
+                        enabled: false,
+                        failure_threshold: 1,
+                        ..Default::default()
+                });
+                rotator.record_failure("proxy1");
+                assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy1".to_string()));
+                assert_eq!(rotator.health()[0].failures, 0);
+        }
+
+        fn three_proxies(strategy: ProxyRotationStrategy) -> ProxyRotator {
+                ProxyRotator::new(
+                        vec!["proxy1".to_string(), "proxy2".to_string(), "proxy3".to_string()],
+                        strategy,
+                )
+        }
+
+        #[test]
+        fn test_weighted_rotation() {
+                let mut proxies = HashMap::new();
+                for (proxy_id, weight) in [("proxy1", 0), ("proxy2", 1), ("proxy3", 3)].iter() {
+                        proxies.insert(
+                                proxy_id.to_string(),
+                                CheckEmailInputProxy {
+                                        weight: Some(*weight),
+                                        ..Default::default()
+                                },
+                        );
+                }
+                let rotator = three_proxies(ProxyRotationStrategy::Weighted).with_weights(&proxies);
+
+                let mut counts: HashMap<String, usize> = HashMap::new();
+                for _ in 0..1000 {
+                        *counts.entry(rotator.get_next_proxy_id().unwrap().clone()).or_default() += 1;
+                }
+                assert_eq!(counts.get("proxy1"), None);
+                assert!(counts["proxy3"] > 2 * counts["proxy2"], "{:?}", counts);
+        }
+
+        #[test]
+        fn test_least_in_flight_rotation() {
+                let rotator = three_proxies(ProxyRotationStrategy::LeastInFlight);
+
+                let _guard1 = rotator.track_in_flight("proxy1");
+                let guard2 = rotator.track_in_flight("proxy2");
+                let _guard3 = rotator.track_in_flight("proxy3");
+                let _guard3_bis = rotator.track_in_flight("proxy3");
+                assert_eq!(rotator.health()[2].in_flight, 2);
+
+                drop(guard2);
+                assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy2".to_string()));
+                assert_eq!(rotator.get_next_proxy_id(), Some(&"proxy2".to_string()));
+                assert_eq!(rotator.health()[1].in_flight, 0);
+        }
+
+        #[test]
+        fn test_sticky_per_domain_rotation() {
+                let rotator = three_proxies(ProxyRotationStrategy::StickyPerDomain)
+                        .with_health_config(ProxyHealthConfig {
+                                failure_threshold: 1,
+                                ..Default::default()
+                        });
+
+                let proxy_id = rotator.get_proxy_id_for_domain("example.com").cloned().unwrap();
+                for _ in 0..10 {
+                        assert_eq!(rotator.get_proxy_id_for_domain("Example.com"), Some(&proxy_id));
+                }
+
+                // The domain moves to another proxy while its proxy is ejected.
+                rotator.record_failure(&proxy_id);
+                let other_proxy_id = rotator.get_proxy_id_for_domain("example.com").cloned().unwrap();
+                assert_ne!(other_proxy_id, proxy_id);
+                assert_eq!(rotator.get_proxy_id_for_domain("example.com"), Some(&other_proxy_id));
+        }
+
+        #[test]
+        fn test_sticky_per_domain_is_stable() {
+                let rotator = three_proxies(ProxyRotationStrategy::StickyPerDomain);
+
+                // The proxy of a domain only depends on the domain, across
+                // processes and Rust releases.
+                assert_eq!(rotator.get_proxy_id_for_domain("example.com"), Some(&"proxy3".to_string()));
+                assert_eq!(rotator.get_proxy_id_for_domain("outlook.com"), Some(&"proxy1".to_string()));
+        }
+
+        #[test]
+        fn test_keep_state_from_previous_rotator() {
+                let previous = rotator_with_threshold(1);
+                previous.record_failure("proxy1");
+                let guard = previous.track_in_flight("proxy2");
+
+                let rotator = ProxyRotator::new(
+                        vec!["proxy1".to_string(), "proxy3".to_string(), "proxy2".to_string()],
+                        ProxyRotationStrategy::RoundRobin,
+                )
+                .with_health_config(previous.health_config().clone())
+                .with_state_from(&previous);
+                let health = rotator.health();
+                assert!(!health[0].healthy);
+                assert_eq!(health[0].ejections, 1);
+                assert!(health[1].healthy);
+                assert_eq!(health[2].in_flight, 1);
+
+                // The verifications in flight through the previous rotator are
+                // still counted until they end.
+                drop(guard);
+                assert_eq!(rotator.health()[2].in_flight, 0);
+                for _ in 0..4 {
+                        assert_ne!(rotator.get_next_proxy_id(), Some(&"proxy1".to_string()));
+                }
+        }
 }
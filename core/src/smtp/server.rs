@@ -0,0 +1,217 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! What the SMTP server tells about itself: its greeting banner, the
+//! extensions advertised in its EHLO response, and the server software we
+//! infer from them.
+
+use async_smtp::response::Response;
+use serde::{Deserialize, Serialize};
+
+/// Details about the SMTP server we talked to.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Serialize)]
+pub struct SmtpServerDetails {
+	/// The greeting banner of the server, e.g. "mx.google.com ESMTP - gsmtp".
+	pub banner: Option<String>,
+	/// The extensions advertised in the EHLO response.
+	pub extensions: SmtpExtensions,
+	/// The server software, inferred from the banner and the EHLO response.
+	pub software: Option<SmtpSoftware>,
+}
+
+/// SMTP extensions advertised by the server in its EHLO response.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Serialize)]
+pub struct SmtpExtensions {
+	/// The maximum message size in bytes (RFC 1870), 0 meaning no fixed
+	/// limit. None if SIZE isn't advertised.
+	pub size: Option<u64>,
+	/// 8BITMIME (RFC 6152).
+	#[serde(rename = "8bitmime")]
+	pub eight_bit_mime: bool,
+	/// SMTPUTF8 (RFC 6531).
+	pub smtputf8: bool,
+	/// STARTTLS (RFC 3207).
+	pub starttls: bool,
+	/// PIPELINING (RFC 2920).
+	pub pipelining: bool,
+	/// CHUNKING (RFC 3030).
+	pub chunking: bool,
+}
+
+impl SmtpExtensions {
+	/// Parse the extensions from an EHLO response. The first line of the
+	/// response is the server's greeting, each other line is a keyword
+	/// optionally followed by parameters.
+	pub fn from_ehlo(response: &Response) -> Self {
+		let mut extensions = SmtpExtensions::default();
+		for line in response.message.iter().skip(1) {
+			let mut parts = line.split_whitespace();
+			let keyword = parts.next().unwrap_or_default().to_uppercase();
+			match keyword.as_str() {
+				"SIZE" => {
+					extensions.size = Some(parts.next().and_then(|s| s.parse().ok()).unwrap_or(0))
+				}
+				"8BITMIME" => extensions.eight_bit_mime = true,
+				"SMTPUTF8" => extensions.smtputf8 = true,
+				"STARTTLS" => extensions.starttls = true,
+				"PIPELINING" => extensions.pipelining = true,
+				"CHUNKING" => extensions.chunking = true,
+				_ => {}
+			}
+		}
+		extensions
+	}
+}
+
+/// SMTP server software, as inferred from the banner and EHLO response.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSoftware {
+	Barracuda,
+	Exim,
+	Google,
+	HMailServer,
+	IceWarp,
+	Kerio,
+	MDaemon,
+	MicrosoftExchange,
+	Microsoft365,
+	Mimecast,
+	OpenSmtpd,
+	Postfix,
+	Proofpoint,
+	Qmail,
+	Sendmail,
+	Yahoo,
+	Zimbra,
+	Zoho,
+}
+
+/// Patterns identifying the server software, matched against the lowercased
+/// banner and EHLO greeting. The more specific patterns come first, e.g.
+/// Microsoft 365 before on-premise Exchange.
+const SOFTWARE_PATTERNS: &[(&str, SmtpSoftware)] = &[
+	("mail.protection.outlook.com", SmtpSoftware::Microsoft365),
+	(
+		"microsoft esmtp mail service",
+		SmtpSoftware::MicrosoftExchange,
+	),
+	("gsmtp", SmtpSoftware::Google),
+	("mx.google.com", SmtpSoftware::Google),
+	("yahoo", SmtpSoftware::Yahoo),
+	("mimecast", SmtpSoftware::Mimecast),
+	("pphosted", SmtpSoftware::Proofpoint),
+	("proofpoint", SmtpSoftware::Proofpoint),
+	("barracuda", SmtpSoftware::Barracuda),
+	("zoho", SmtpSoftware::Zoho),
+	("postfix", SmtpSoftware::Postfix),
+	("exim", SmtpSoftware::Exim),
+	("sendmail", SmtpSoftware::Sendmail),
+	("qmail", SmtpSoftware::Qmail),
+	("opensmtpd", SmtpSoftware::OpenSmtpd),
+	("zimbra", SmtpSoftware::Zimbra),
+	("kerio", SmtpSoftware::Kerio),
+	("mdaemon", SmtpSoftware::MDaemon),
+	("hmailserver", SmtpSoftware::HMailServer),
+	("icewarp", SmtpSoftware::IceWarp),
+];
+
+/// Infer the server software from its banner and EHLO greeting. Returns
+/// None if the server doesn't reveal it.
+pub fn fingerprint(banner: &str, ehlo_greeting: &str) -> Option<SmtpSoftware> {
+	let haystack = format!("{} {}", banner, ehlo_greeting).to_lowercase();
+	SOFTWARE_PATTERNS
+		.iter()
+		.find(|(pattern, _)| haystack.contains(pattern))
+		.map(|(_, software)| *software)
+}
+
+impl SmtpServerDetails {
+	/// Build the server details from its greeting and EHLO response.
+	pub fn new(greeting: &Response, ehlo: &Response) -> Self {
+		let banner = greeting.message.join(" ");
+		let ehlo_greeting = ehlo.message.first().map(String::as_str).unwrap_or_default();
+		SmtpServerDetails {
+			software: fingerprint(&banner, ehlo_greeting),
+			banner: Some(banner),
+			extensions: SmtpExtensions::from_ehlo(ehlo),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	#[test]
+	fn should_parse_ehlo_extensions() {
+		let ehlo = Response::from_str(
+			"250-mx.example.com at your service\r\n250-SIZE 35882577\r\n250-8BITMIME\r\n250-STARTTLS\r\n250-ENHANCEDSTATUSCODES\r\n250-PIPELINING\r\n250-CHUNKING\r\n250 SMTPUTF8\r\n",
+		)
+		.unwrap();
+
+		assert_eq!(
+			SmtpExtensions::from_ehlo(&ehlo),
+			SmtpExtensions {
+				size: Some(35882577),
+				eight_bit_mime: true,
+				smtputf8: true,
+				starttls: true,
+				pipelining: true,
+				chunking: true,
+			}
+		);
+
+		let ehlo = Response::from_str("250-mx.example.com\r\n250 SIZE\r\n").unwrap();
+		assert_eq!(
+			SmtpExtensions::from_ehlo(&ehlo),
+			SmtpExtensions {
+				size: Some(0),
+				..Default::default()
+			}
+		);
+	}
+
+	#[test]
+	fn should_fingerprint_server_software() {
+		assert_eq!(
+			fingerprint(
+				"mx.google.com ESMTP a1-20020a05 - gsmtp",
+				"mx.google.com at your service"
+			),
+			Some(SmtpSoftware::Google)
+		);
+		assert_eq!(
+			fingerprint(
+				"AM0PR01.mail.protection.outlook.com Microsoft ESMTP MAIL Service ready",
+				""
+			),
+			Some(SmtpSoftware::Microsoft365)
+		);
+		assert_eq!(
+			fingerprint(
+				"mail.example.com ESMTP Postfix (Debian/GNU)",
+				"mail.example.com"
+			),
+			Some(SmtpSoftware::Postfix)
+		);
+		assert_eq!(
+			fingerprint("mail.example.com ESMTP", "mail.example.com"),
+			None
+		);
+	}
+}
@@ -0,0 +1,123 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Low-level SMTP session. Unlike async-smtp's `SmtpTransport`, it lets us
+//! read the server's greeting and EHLO response ourselves, and take the
+//! stream back to upgrade it to TLS after STARTTLS.
+
+use async_smtp::commands::EhloCommand;
+use async_smtp::error::{Error as AsyncSmtpError, SmtpResult};
+use async_smtp::extension::ClientId;
+use async_smtp::response::Response;
+use std::fmt::Display;
+use std::str::FromStr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+/// An SMTP session over a buffered stream. Commands and replies have the
+/// same semantics as async-smtp's: a negative reply is returned as an
+/// `Err(Transient)` or `Err(Permanent)`.
+pub struct SmtpSession<S> {
+	inner: S,
+}
+
+impl<S: AsyncBufRead + AsyncWrite + Unpin> SmtpSession<S> {
+	pub fn new(inner: S) -> Self {
+		SmtpSession { inner }
+	}
+
+	/// Return the underlying stream.
+	pub fn into_inner(self) -> S {
+		self.inner
+	}
+
+	/// Return a mutable reference to the underlying stream.
+	pub fn get_mut(&mut self) -> &mut S {
+		&mut self.inner
+	}
+
+	/// Send the EHLO command.
+	pub async fn ehlo(&mut self, client_id: ClientId) -> SmtpResult {
+		self.command(EhloCommand::new(client_id)).await
+	}
+
+	/// Send a command and read the server's reply.
+	pub async fn command(&mut self, command: impl Display) -> SmtpResult {
+		self.inner.write_all(command.to_string().as_bytes()).await?;
+		self.inner.flush().await?;
+		self.read_response().await
+	}
+
+	/// Read a (possibly multiline) reply from the server.
+	pub async fn read_response(&mut self) -> SmtpResult {
+		let mut buffer = String::with_capacity(100);
+
+		loop {
+			let start = buffer.len();
+			if self.inner.read_line(&mut buffer).await? == 0 {
+				// Same error as async-smtp, see `parser::is_err_io_errors`.
+				return Err(std::io::Error::other("incomplete").into());
+			}
+
+			// The last line of a reply has a space (or nothing) after the code,
+			// the other lines have a dash.
+			if buffer.as_bytes().get(start + 3) != Some(&b'-') {
+				break;
+			}
+		}
+
+		let response = Response::from_str(&buffer)?;
+		if response.is_positive() {
+			Ok(response)
+		} else {
+			Err(AsyncSmtpError::from(response))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_smtp::commands::QuitCommand;
+	use tokio::io::BufStream;
+
+	#[tokio::test]
+	async fn should_read_multiline_and_negative_replies() {
+		let server: &[u8] =
+			b"220 mx.example.com ESMTP\r\n250-mx.example.com\r\n250-STARTTLS\r\n250 SIZE 1000\r\n550 5.1.1 User unknown\r\n";
+		let mut session =
+			SmtpSession::new(BufStream::new(tokio::io::join(server, tokio::io::sink())));
+
+		let greeting = session.read_response().await.unwrap();
+		assert!(greeting.has_code(220));
+
+		let ehlo = session
+			.ehlo(ClientId::Domain("localhost".into()))
+			.await
+			.unwrap();
+		assert_eq!(
+			ehlo.message,
+			vec!["mx.example.com", "STARTTLS", "SIZE 1000"]
+		);
+
+		match session.command(QuitCommand).await {
+			Err(AsyncSmtpError::Permanent(r)) => assert!(r.has_code(550)),
+			r => panic!("Expected a permanent error, got {:?}", r),
+		}
+
+		// The server closed the connection.
+		assert!(session.read_response().await.is_err());
+	}
+}
@@ -0,0 +1,238 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Reuse of SMTP sessions across verifications.
+//!
+//! Verifying many addresses at the same domain with one connection each
+//! quickly gets us rate-limited. Instead, once a verification is done, its
+//! session is reset with RSET and kept open in a [SmtpSessionPool], so that
+//! the next verification against the same MX host sends its `RCPT TO` over
+//! the same connection. The result of the catch-all probe of a domain is
+//! also kept in the pool, so that it's only probed once while its emails are
+//! being verified.
+
+use super::connect::SmtpConnection;
+use super::verif_method::{StartTlsMode, VerifMethodSmtp};
+use super::{SmtpServerDetails, SmtpTlsDetails};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Configuration of the SMTP session reuse.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SmtpSessionPoolConfig {
+	/// Whether to reuse the SMTP sessions. Callers decide which verifications
+	/// go through the pool, e.g. the backend only uses it for bulk jobs.
+	///
+	/// Defaults to false.
+	pub enabled: bool,
+	/// Maximum number of emails verified over a single SMTP session, after
+	/// which the session is closed.
+	///
+	/// Defaults to 20.
+	pub max_recipients_per_session: usize,
+	/// Maximum number of idle sessions kept open per MX host.
+	///
+	/// Defaults to 4.
+	pub max_idle_sessions_per_host: usize,
+	/// How long an idle session is kept open, in seconds. Servers usually
+	/// close idle sessions after a few minutes. Expired sessions are closed
+	/// with QUIT the next time a session is given back to the pool. The result
+	/// of the catch-all probe of a domain is forgotten after the same delay
+	/// without verifying any email of the domain.
+	///
+	/// Defaults to 30.
+	pub idle_timeout_secs: u64,
+}
+
+impl Default for SmtpSessionPoolConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			max_recipients_per_session: 20,
+			max_idle_sessions_per_host: 4,
+			idle_timeout_secs: 30,
+		}
+	}
+}
+
+/// What identifies an SMTP session: sessions are only reused for the same
+/// MX host, port and proxy, and if we introduced ourselves the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(super) struct SessionKey {
+	host: String,
+	port: u16,
+	proxy: Option<(String, u16, Option<String>)>,
+	hello_name: String,
+	starttls: StartTlsMode,
+}
+
+impl SessionKey {
+	pub(super) fn new(mx_host: &str, verif_method: &VerifMethodSmtp) -> Self {
+		Self {
+			host: mx_host.to_string(),
+			port: verif_method.config.smtp_port,
+			proxy: verif_method
+				.proxy
+				.as_ref()
+				.map(|p| (p.host.clone(), p.port, p.username.clone())),
+			hello_name: verif_method.config.hello_name.clone(),
+			starttls: verif_method.config.starttls,
+		}
+	}
+}
+
+/// An open SMTP session, after EHLO (and STARTTLS), outside of any mail
+/// transaction.
+pub(super) struct OpenSession {
+	pub(super) conn: SmtpConnection,
+	pub(super) server: SmtpServerDetails,
+	pub(super) tls: SmtpTlsDetails,
+	/// Number of emails verified over this session.
+	pub(super) recipients: usize,
+}
+
+impl OpenSession {
+	pub(super) fn new(
+		conn: SmtpConnection,
+		server: SmtpServerDetails,
+		tls: SmtpTlsDetails,
+	) -> Self {
+		Self {
+			conn,
+			server,
+			tls,
+			recipients: 0,
+		}
+	}
+}
+
+/// A pool of idle SMTP sessions, shared across verifications.
+pub struct SmtpSessionPool {
+	config: SmtpSessionPoolConfig,
+	idle: Mutex<HashMap<SessionKey, Vec<(OpenSession, Instant)>>>,
+	/// Result of the catch-all probe of each domain, and when it was last
+	/// used.
+	catch_all: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl std::fmt::Debug for SmtpSessionPool {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("SmtpSessionPool")
+			.field("config", &self.config)
+			.field("idle_count", &self.idle_count())
+			.finish()
+	}
+}
+
+impl SmtpSessionPool {
+	pub fn new(config: SmtpSessionPoolConfig) -> Self {
+		Self {
+			config,
+			idle: Mutex::new(HashMap::new()),
+			catch_all: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn idle_timeout(&self) -> Duration {
+		Duration::from_secs(self.config.idle_timeout_secs)
+	}
+
+	/// Number of idle sessions in the pool.
+	pub(super) fn idle_count(&self) -> usize {
+		self.idle
+			.lock()
+			.map(|idle| idle.values().map(Vec::len).sum())
+			.unwrap_or(0)
+	}
+
+	/// Whether the session can verify more emails.
+	pub(super) fn accepts(&self, session: &OpenSession) -> bool {
+		session.recipients < self.config.max_recipients_per_session
+	}
+
+	/// Take an idle session for the given key, if any. Sessions idle for too
+	/// long are dropped, as the server has likely closed them.
+	pub(super) fn checkout(&self, key: &SessionKey) -> Option<OpenSession> {
+		let idle_timeout = self.idle_timeout();
+		let mut idle = self.idle.lock().ok()?;
+		let sessions = idle.get_mut(key)?;
+		sessions.retain(|(_, since)| since.elapsed() < idle_timeout);
+		let session = sessions.pop().map(|(session, _)| session);
+		if sessions.is_empty() {
+			idle.remove(key);
+		}
+		session
+	}
+
+	/// Give back a session after a verification. If it can't be reused, it's
+	/// returned to the caller, who should close it.
+	pub(super) fn checkin(&self, key: SessionKey, session: OpenSession) -> Option<OpenSession> {
+		if !self.accepts(&session) {
+			return Some(session);
+		}
+		let mut idle = match self.idle.lock() {
+			Ok(idle) => idle,
+			Err(_) => return Some(session),
+		};
+		let sessions = idle.entry(key).or_default();
+		if sessions.len() >= self.config.max_idle_sessions_per_host {
+			return Some(session);
+		}
+		sessions.push((session, Instant::now()));
+		None
+	}
+
+	/// Remove the sessions idle for too long, for all MX hosts. They should
+	/// be closed by the caller.
+	pub(super) fn take_expired(&self) -> Vec<OpenSession> {
+		let idle_timeout = self.idle_timeout();
+		let mut idle = match self.idle.lock() {
+			Ok(idle) => idle,
+			Err(_) => return vec![],
+		};
+		let mut expired = vec![];
+		idle.retain(|_, sessions| {
+			let (keep, old) = std::mem::take(sessions)
+				.into_iter()
+				.partition(|(_, since)| since.elapsed() < idle_timeout);
+			*sessions = keep;
+			expired.extend(old.into_iter().map(|(session, _)| session));
+			!sessions.is_empty()
+		});
+		expired
+	}
+
+	/// The result of the catch-all probe of the domain, if it was made
+	/// recently.
+	pub(super) fn get_catch_all(&self, domain: &str) -> Option<bool> {
+		let idle_timeout = self.idle_timeout();
+		let mut catch_all = self.catch_all.lock().ok()?;
+		catch_all.retain(|_, (_, since)| since.elapsed() < idle_timeout);
+		let (is_catch_all, since) = catch_all.get_mut(domain)?;
+		*since = Instant::now();
+		Some(*is_catch_all)
+	}
+
+	/// Remember the result of the catch-all probe of the domain.
+	pub(super) fn set_catch_all(&self, domain: &str, is_catch_all: bool) {
+		if let Ok(mut catch_all) = self.catch_all.lock() {
+			catch_all.insert(domain.to_string(), (is_catch_all, Instant::now()));
+		}
+	}
+}
@@ -0,0 +1,152 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! TLS upgrade of an SMTP session after the STARTTLS command.
+//!
+//! Most MX hosts present certificates that don't match their name, or that
+//! are self-signed. Since we never send any email content, we still accept
+//! those certificates, but report whether they are valid for the MX host.
+
+use once_cell::sync::Lazy;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::convert::TryFrom;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+static PROVIDER: Lazy<Arc<CryptoProvider>> = Lazy::new(|| Arc::new(ring::default_provider()));
+
+static WEBPKI_VERIFIER: Lazy<Arc<WebPkiServerVerifier>> = Lazy::new(|| {
+	let roots = RootCertStore {
+		roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+	};
+	WebPkiServerVerifier::builder_with_provider(Arc::new(roots), PROVIDER.clone())
+		.build()
+		.expect("Webpki roots are valid. qed.")
+});
+
+/// Information about the negotiated TLS session.
+#[derive(Debug)]
+pub struct TlsInfo {
+	/// The negotiated TLS protocol version, e.g. "TLSv1_3".
+	pub protocol_version: Option<String>,
+	/// Whether the server certificate is valid for the MX host name.
+	pub cert_valid: bool,
+	/// Why the server certificate is invalid, if it is.
+	pub cert_error: Option<String>,
+}
+
+/// Certificate verifier that accepts any certificate with a valid handshake
+/// signature, but records whether the certificate would pass the standard
+/// webpki verification.
+#[derive(Debug)]
+struct RecordingVerifier {
+	/// Result of the webpki verification, set during the handshake.
+	result: Mutex<Option<Result<(), String>>>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		intermediates: &[CertificateDer<'_>],
+		server_name: &ServerName<'_>,
+		ocsp_response: &[u8],
+		now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		let result = WEBPKI_VERIFIER
+			.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+			.map(|_| ())
+			.map_err(|e| e.to_string());
+		if let Ok(mut r) = self.result.lock() {
+			*r = Some(result);
+		}
+
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		WEBPKI_VERIFIER.verify_tls12_signature(message, cert, dss)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		WEBPKI_VERIFIER.verify_tls13_signature(message, cert, dss)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		WEBPKI_VERIFIER.supported_verify_schemes()
+	}
+}
+
+/// Perform the TLS handshake with the SMTP server, after it accepted the
+/// STARTTLS command.
+pub async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(
+	stream: S,
+	mx_host: &str,
+) -> io::Result<(TlsStream<S>, TlsInfo)> {
+	let verifier = Arc::new(RecordingVerifier {
+		result: Mutex::new(None),
+	});
+	let config = ClientConfig::builder_with_provider(PROVIDER.clone())
+		.with_safe_default_protocol_versions()
+		.map_err(io::Error::other)?
+		.dangerous()
+		.with_custom_certificate_verifier(verifier.clone())
+		.with_no_client_auth();
+	let server_name = ServerName::try_from(mx_host.to_string())
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+	let tls_stream = TlsConnector::from(Arc::new(config))
+		.connect(server_name, stream)
+		.await?;
+
+	let protocol_version = tls_stream
+		.get_ref()
+		.1
+		.protocol_version()
+		.map(|v| format!("{:?}", v));
+	let cert_result = verifier
+		.result
+		.lock()
+		.ok()
+		.and_then(|r| r.clone())
+		.unwrap_or_else(|| Err("No certificate presented".into()));
+
+	Ok((
+		tls_stream,
+		TlsInfo {
+			protocol_version,
+			cert_valid: cert_result.is_ok(),
+			cert_error: cert_result.err(),
+		},
+	))
+}
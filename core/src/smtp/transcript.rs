@@ -0,0 +1,281 @@
+// check-if-email-exists
+// Copyright (C) 2018-2023 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Recording of the SMTP conversation. The [TranscriptStream] sits between
+//! the SMTP client and the network stream, and records every command sent
+//! and every reply received into a shared [SmtpTranscript].
+
+use super::parser::parse_enhanced_code;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Lines longer than this are cut, to avoid buffering a misbehaving server
+/// forever.
+const MAX_LINE_LEN: usize = 2048;
+
+/// One event of the SMTP conversation.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SmtpTranscriptEvent {
+	/// A new connection was opened to the SMTP server.
+	Connect { host: String, port: u16 },
+	/// An idle connection to the SMTP server was reused, see
+	/// [SmtpSessionPool](super::session_pool::SmtpSessionPool).
+	Reuse { host: String, port: u16 },
+	/// A command sent to the SMTP server.
+	Command { text: String },
+	/// A (possibly multiline) reply from the SMTP server.
+	Reply {
+		/// The basic reply code, e.g. 250. None if the server sent a line
+		/// that isn't a valid SMTP reply.
+		code: Option<u16>,
+		/// The RFC 3463 enhanced status code, e.g. "5.1.1", if present.
+		enhanced_code: Option<String>,
+		/// The text of the reply, one line per line of the reply.
+		text: String,
+	},
+}
+
+/// An event of the SMTP conversation, with the time it happened.
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+pub struct SmtpTranscriptEntry {
+	pub timestamp: DateTime<Utc>,
+	#[serde(flatten)]
+	pub event: SmtpTranscriptEvent,
+}
+
+/// Shared handle to the recorded SMTP conversation. Cloning the handle
+/// doesn't clone the recorded entries.
+#[derive(Debug, Clone, Default)]
+pub struct SmtpTranscript(Arc<Mutex<Vec<SmtpTranscriptEntry>>>);
+
+impl SmtpTranscript {
+	/// Record a new event.
+	pub fn push(&self, event: SmtpTranscriptEvent) {
+		if let Ok(mut entries) = self.0.lock() {
+			entries.push(SmtpTranscriptEntry {
+				timestamp: Utc::now(),
+				event,
+			});
+		}
+	}
+
+	/// All the events recorded so far.
+	pub fn entries(&self) -> Vec<SmtpTranscriptEntry> {
+		self.0
+			.lock()
+			.map(|entries| entries.clone())
+			.unwrap_or_default()
+	}
+}
+
+/// Stream wrapper recording the SMTP conversation going through it. If no
+/// transcript is given, the stream is a simple passthrough.
+pub struct TranscriptStream<S> {
+	inner: S,
+	transcript: Option<SmtpTranscript>,
+	read_buf: Vec<u8>,
+	write_buf: Vec<u8>,
+	/// Lines of a multiline reply we haven't received the last line of yet.
+	pending_reply: Vec<String>,
+}
+
+impl<S> TranscriptStream<S> {
+	pub fn new(inner: S, transcript: Option<SmtpTranscript>) -> Self {
+		TranscriptStream {
+			inner,
+			transcript,
+			read_buf: vec![],
+			write_buf: vec![],
+			pending_reply: vec![],
+		}
+	}
+
+	/// Return the wrapped stream and the transcript, e.g. to upgrade the
+	/// stream to TLS. Partial lines not yet recorded are lost.
+	pub fn into_parts(self) -> (S, Option<SmtpTranscript>) {
+		(self.inner, self.transcript)
+	}
+
+	/// Record the rest of the conversation into another transcript, e.g.
+	/// when the connection is reused for another verification.
+	pub fn set_transcript(&mut self, transcript: Option<SmtpTranscript>) {
+		self.transcript = transcript;
+	}
+
+	fn push(&self, event: SmtpTranscriptEvent) {
+		if let Some(transcript) = &self.transcript {
+			transcript.push(event);
+		}
+	}
+
+	fn on_read(&mut self, bytes: &[u8]) {
+		if self.transcript.is_none() {
+			return;
+		}
+		self.read_buf.extend_from_slice(bytes);
+		while let Some(line) = take_line(&mut self.read_buf) {
+			self.on_reply_line(line);
+		}
+	}
+
+	fn on_reply_line(&mut self, line: String) {
+		let code = line.get(0..3).and_then(|c| c.parse::<u16>().ok());
+		let Some(code) = code else {
+			self.push(SmtpTranscriptEvent::Reply {
+				code: None,
+				enhanced_code: None,
+				text: line,
+			});
+			return;
+		};
+
+		let is_last = line.as_bytes().get(3) != Some(&b'-');
+		self.pending_reply
+			.push(line.get(4..).unwrap_or_default().to_string());
+
+		if is_last {
+			let lines = std::mem::take(&mut self.pending_reply);
+			self.push(SmtpTranscriptEvent::Reply {
+				code: Some(code),
+				enhanced_code: lines
+					.first()
+					.and_then(|l| parse_enhanced_code(l))
+					.map(|c| c.to_string()),
+				text: lines.join("\n"),
+			});
+		}
+	}
+
+	fn on_write(&mut self, bytes: &[u8]) {
+		if self.transcript.is_none() {
+			return;
+		}
+		self.write_buf.extend_from_slice(bytes);
+		while let Some(text) = take_line(&mut self.write_buf) {
+			self.push(SmtpTranscriptEvent::Command { text });
+		}
+	}
+}
+
+/// Remove the first full line (without its line ending) from the buffer.
+fn take_line(buf: &mut Vec<u8>) -> Option<String> {
+	let end = match buf.iter().position(|b| *b == b'\n') {
+		Some(pos) => pos + 1,
+		None if buf.len() > MAX_LINE_LEN => buf.len(),
+		None => return None,
+	};
+	let line: Vec<u8> = buf.drain(..end).collect();
+	Some(
+		String::from_utf8_lossy(&line)
+			.trim_end_matches(['\r', '\n'])
+			.to_string(),
+	)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TranscriptStream<S> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		let before = buf.filled().len();
+		let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+		if let Poll::Ready(Ok(())) = res {
+			this.on_read(&buf.filled()[before..]);
+		}
+		res
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TranscriptStream<S> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = res {
+			this.on_write(&buf[..n]);
+		}
+		res
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	#[tokio::test]
+	async fn should_record_commands_and_replies() {
+		let transcript = SmtpTranscript::default();
+		let server = tokio_test_stream(
+			b"220 mx.example.com ESMTP\r\n250-mx.example.com\r\n250 SIZE 1000\r\n550 5.1.1 User unknown\r\n",
+		);
+		let mut stream = TranscriptStream::new(server, Some(transcript.clone()));
+
+		stream.write_all(b"EHLO localhost\r\n").await.unwrap();
+		let mut out = String::new();
+		stream.read_to_string(&mut out).await.unwrap();
+
+		let events: Vec<SmtpTranscriptEvent> =
+			transcript.entries().into_iter().map(|e| e.event).collect();
+		assert_eq!(
+			events,
+			vec![
+				SmtpTranscriptEvent::Command {
+					text: "EHLO localhost".into()
+				},
+				SmtpTranscriptEvent::Reply {
+					code: Some(220),
+					enhanced_code: None,
+					text: "mx.example.com ESMTP".into()
+				},
+				SmtpTranscriptEvent::Reply {
+					code: Some(250),
+					enhanced_code: None,
+					text: "mx.example.com\nSIZE 1000".into()
+				},
+				SmtpTranscriptEvent::Reply {
+					code: Some(550),
+					enhanced_code: Some("5.1.1".into()),
+					text: "5.1.1 User unknown".into()
+				},
+			]
+		);
+	}
+
+	/// A stream reading the given bytes, and discarding everything written.
+	fn tokio_test_stream(data: &'static [u8]) -> impl AsyncRead + AsyncWrite + Unpin {
+		tokio::io::join(data, tokio::io::sink())
+	}
+}
@@ -20,10 +20,13 @@ use std::{collections::HashMap, time::Duration};
 
 use crate::{
         mx::{is_mimecast, is_proofpoint},
+        rules::ForcedVerifMethod,
         util::input_output::CheckEmailInputProxy,
 };
 use serde::{Deserialize, Serialize};
 
+use super::proxy_rotator::{ProxyHealthConfig, ProxyPoolRotators, ProxyRotator};
+
 /// Rotation strategy for proxy pool
 #[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
 #[serde(rename_all = "snake_case")]
@@ -31,6 +34,15 @@ pub enum ProxyRotationStrategy {
         #[default]
         RoundRobin,
         Random,
+        /// Pick proxies at random, in proportion to their `weight`.
+        Weighted,
+        /// Pick the proxy with the fewest verifications in flight.
+        LeastInFlight,
+        /// Always verify the emails of a domain through the same proxy, so that
+        /// its MX hosts always see the same IP, which helps with greylisting.
+        /// The proxy only depends on the domain and the list of proxies, so
+        /// it's kept across restarts.
+        StickyPerDomain,
 }
 
 /// Configuration for proxy pool with rotation
@@ -41,6 +53,53 @@ pub struct ProxyPoolConfig {
         pub enabled: bool,
         /// Rotation strategy
         pub strategy: ProxyRotationStrategy,
+        /// Health checks of the proxies, to skip the unhealthy ones
+        pub health: ProxyHealthConfig,
+        /// Retry the SMTP verification through another proxy of the pool when
+        /// the first one is blocked
+        pub retry: ProxyRetryConfig,
+}
+
+/// Configuration of the retries of an SMTP verification through other
+/// proxies of the pool, when the proxy it went through is blocked, see
+/// [SmtpError::is_proxy_blocked](super::SmtpError::is_proxy_blocked). Only
+/// the proxies picked from the rotation are replaced: a proxy configured for
+/// the email provider, or forced by a rule, is always used as is.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct ProxyRetryConfig {
+        /// Whether to retry through another proxy.
+        ///
+        /// Defaults to false.
+        pub enabled: bool,
+        /// Maximum number of proxies tried for a verification, including the
+        /// first one.
+        ///
+        /// Defaults to 3.
+        pub max_attempts: usize,
+        /// Maximum total time of a verification through all the proxies
+        /// tried, in seconds. Once it's spent, the current attempt is cut
+        /// short and no other proxy is tried.
+        ///
+        /// Defaults to 120.
+        pub timeout_secs: u64,
+}
+
+impl Default for ProxyRetryConfig {
+        fn default() -> Self {
+                Self {
+                        enabled: false,
+                        max_attempts: 3,
+                        timeout_secs: 120,
+                }
+        }
+}
+
+impl ProxyRetryConfig {
+        /// Maximum total time of a verification through all the proxies tried.
+        pub fn timeout(&self) -> Duration {
+                Duration::from_secs(self.timeout_secs)
+        }
 }
 
 use super::{is_gmail, is_hotmail_b2b, is_hotmail_b2c, is_yahoo};
@@ -85,6 +144,18 @@ impl EmailProvider {
         }
 }
 
+/// A named pool of proxies, which email providers can rotate through
+/// instead of the global proxy pool, see [VerifMethod::proxy_pools].
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+#[serde(default)]
+pub struct NamedProxyPool {
+        /// IDs of the proxies of the pool, which must exist in the
+        /// `VerifMethod.proxies` field.
+        pub proxies: Vec<ProxyID>,
+        /// Rotation strategy of the pool.
+        pub strategy: ProxyRotationStrategy,
+}
+
 type ProxyID = String;
 // This is a convention ID for the proxy in the top-level "proxy" field of the
 // request, which will be added as a key in the `proxies` map of the
@@ -103,6 +174,12 @@ pub struct VerifMethod {
         /// When enabled, requests will automatically rotate through all defined
         /// proxies instead of always using the default proxy.
         pub proxy_pool: ProxyPoolConfig,
+        /// Named proxy pools, e.g. a pool of residential proxies for Gmail and
+        /// Yahoo. An email provider uses a pool by setting the `proxy_pool`
+        /// field of its SMTP configuration, and each pool rotates on its own.
+        /// The health checks and retries of `proxy_pool` apply to all the
+        /// pools, even if the global pool is disabled.
+        pub proxy_pools: HashMap<String, NamedProxyPool>,
         /// Verification method for Gmail.
         pub gmail: GmailVerifMethod,
         /// Verification method for Hotmail B2B.
@@ -143,43 +220,53 @@ impl VerifMethod {
                         proxies,
                         gmail: GmailVerifMethod::Smtp(VerifMethodSmtpConfig {
                                 proxy: proxy_id.clone(),
+                                proxy_pool: None,
                                 hello_name: hello_name.clone(),
                                 from_email: from_email.clone(),
                                 smtp_port,
                                 smtp_timeout,
                                 retries,
+                                starttls: StartTlsMode::default(),
                         }),
                         hotmailb2b: HotmailB2BVerifMethod::Smtp(VerifMethodSmtpConfig {
                                 proxy: proxy_id.clone(),
+                                proxy_pool: None,
                                 hello_name: hello_name.clone(),
                                 from_email: from_email.clone(),
                                 smtp_port,
                                 smtp_timeout,
                                 retries,
+                                starttls: StartTlsMode::default(),
                         }),
                         mimecast: MimecastVerifMethod::Smtp(VerifMethodSmtpConfig {
                                 proxy: proxy_id.clone(),
+                                proxy_pool: None,
                                 hello_name: hello_name.clone(),
                                 from_email: from_email.clone(),
                                 smtp_port,
                                 smtp_timeout,
                                 retries,
+                                starttls: StartTlsMode::default(),
                         }),
                         proofpoint: ProofpointVerifMethod::Smtp(VerifMethodSmtpConfig {
                                 proxy: proxy_id.clone(),
+                                proxy_pool: None,
                                 hello_name: hello_name.clone(),
                                 from_email: from_email.clone(),
                                 smtp_port,
                                 smtp_timeout,
                                 retries,
+                                starttls: StartTlsMode::default(),
                         }),
                         everything_else: EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
                                 proxy: proxy_id,
+                                proxy_pool: None,
                                 hello_name,
                                 from_email,
                                 smtp_port,
                                 smtp_timeout,
                                 retries,
+                                starttls: StartTlsMode::default(),
                         }),
                         ..Default::default()
                 }
@@ -238,96 +325,257 @@ impl VerifMethod {
                         }
                 };
 
+                self.validate_proxy_pools()
+        }
+
+        /// Check that the proxy pools only contain configured proxies, and that
+        /// the email providers only reference configured pools.
+        fn validate_proxy_pools(&self) -> Result<(), VerifMethodError> {
+                for (pool, config) in &self.proxy_pools {
+                        if config.proxies.is_empty() {
+                                return Err(VerifMethodError::InvalidProxies(format!(
+                                        "Proxy pool {pool} has no proxies"
+                                )));
+                        }
+                        if let Some(proxy_id) = config.proxies.iter().find(|id| !self.proxies.contains_key(*id)) {
+                                return Err(VerifMethodError::InvalidProxies(format!(
+                                        "Invalid proxy {proxy_id} in proxy pool {pool}"
+                                )));
+                        }
+                }
+
+                let providers = [
+                        ("Gmail", EmailProvider::Gmail),
+                        ("Hotmail B2B", EmailProvider::HotmailB2B),
+                        ("Hotmail B2C", EmailProvider::HotmailB2C),
+                        ("Mimecast", EmailProvider::Mimecast),
+                        ("Proofpoint", EmailProvider::Proofpoint),
+                        ("Yahoo", EmailProvider::Yahoo),
+                        ("EverythingElse", EmailProvider::EverythingElse),
+                ];
+                for (name, email_provider) in providers.iter() {
+                        let pool = self
+                                .get_smtp_config(email_provider)
+                                .and_then(|c| c.proxy_pool.as_ref());
+                        if let Some(pool) = pool.filter(|pool| !self.proxy_pools.contains_key(*pool)) {
+                                return Err(VerifMethodError::InvalidProxies(format!(
+                                        "Invalid {name} proxy pool {pool}"
+                                )));
+                        }
+                }
+
                 Ok(())
         }
 
+        /// Force the verification method of the email provider, see the
+        /// `ForceVerifMethod` rule. Returns false if the email provider doesn't
+        /// support this method, in which case its method is left unchanged.
+        pub fn force(&mut self, email_provider: &EmailProvider, method: ForcedVerifMethod) -> bool {
+                let EverythingElseVerifMethod::Smtp(default_smtp_config) = &self.everything_else;
+                let default_smtp_config = default_smtp_config.clone();
+
+                match (email_provider, method) {
+                        (EmailProvider::Yahoo, ForcedVerifMethod::Api) => self.yahoo = YahooVerifMethod::Api,
+                        (EmailProvider::Yahoo, ForcedVerifMethod::Headless) => {
+                                self.yahoo = YahooVerifMethod::Headless
+                        }
+                        (EmailProvider::Yahoo, ForcedVerifMethod::Smtp) => {
+                                if !matches!(self.yahoo, YahooVerifMethod::Smtp(_)) {
+                                        self.yahoo = YahooVerifMethod::Smtp(default_smtp_config);
+                                }
+                        }
+                        (EmailProvider::HotmailB2C, ForcedVerifMethod::Headless) => {
+                                self.hotmailb2c = HotmailB2CVerifMethod::Headless
+                        }
+                        (EmailProvider::HotmailB2C, ForcedVerifMethod::Smtp) => {
+                                if !matches!(self.hotmailb2c, HotmailB2CVerifMethod::Smtp(_)) {
+                                        self.hotmailb2c = HotmailB2CVerifMethod::Smtp(default_smtp_config);
+                                }
+                        }
+                        // All the other email providers are only verified via SMTP.
+                        (_, ForcedVerifMethod::Smtp) => {}
+                        _ => return false,
+                }
+
+                true
+        }
+
         /// Get the proxy to use for the email provider. If there is a
         /// configuration error, such as an unconfigured proxy, this will return
         /// an error.
         pub fn get_proxy(&self, email_provider: EmailProvider) -> Option<&CheckEmailInputProxy> {
-                self.get_provider_specific_proxy(&email_provider)
+                self.get_provider_specific_proxy_id(&email_provider)
+                        .and_then(|proxy_id| self.proxies.get(proxy_id))
         }
 
-        /// Get the provider-specific proxy configuration.
-        /// Returns the proxy configured specifically for this provider, if any.
-        fn get_provider_specific_proxy(
-                &self,
-                email_provider: &EmailProvider,
-        ) -> Option<&CheckEmailInputProxy> {
+        /// The SMTP configuration of the email provider, if it's verified via
+        /// SMTP.
+        pub(crate) fn get_smtp_config(&self, email_provider: &EmailProvider) -> Option<&VerifMethodSmtpConfig> {
                 match email_provider {
                         EmailProvider::Gmail => match &self.gmail {
-                                GmailVerifMethod::Smtp(c) => c
-                                        .proxy
-                                        .as_ref()
-                                        .and_then(|proxy_id| self.proxies.get(proxy_id)),
+                                GmailVerifMethod::Smtp(c) => Some(c),
                         },
                         EmailProvider::HotmailB2B => match &self.hotmailb2b {
-                                HotmailB2BVerifMethod::Smtp(c) => c
-                                        .proxy
-                                        .as_ref()
-                                        .and_then(|proxy_id| self.proxies.get(proxy_id)),
+                                HotmailB2BVerifMethod::Smtp(c) => Some(c),
                         },
                         EmailProvider::HotmailB2C => match &self.hotmailb2c {
-                                HotmailB2CVerifMethod::Smtp(c) => c
-                                        .proxy
-                                        .as_ref()
-                                        .and_then(|proxy_id| self.proxies.get(proxy_id)),
+                                HotmailB2CVerifMethod::Smtp(c) => Some(c),
                                 _ => None,
                         },
                         EmailProvider::Mimecast => match &self.mimecast {
-                                MimecastVerifMethod::Smtp(c) => c
-                                        .proxy
-                                        .as_ref()
-                                        .and_then(|proxy_id| self.proxies.get(proxy_id)),
+                                MimecastVerifMethod::Smtp(c) => Some(c),
                         },
                         EmailProvider::Proofpoint => match &self.proofpoint {
-                                ProofpointVerifMethod::Smtp(c) => c
-                                        .proxy
-                                        .as_ref()
-                                        .and_then(|proxy_id| self.proxies.get(proxy_id)),
+                                ProofpointVerifMethod::Smtp(c) => Some(c),
                         },
                         EmailProvider::Yahoo => match &self.yahoo {
-                                YahooVerifMethod::Smtp(c) => c
-                                        .proxy
-                                        .as_ref()
-                                        .and_then(|proxy_id| self.proxies.get(proxy_id)),
+                                YahooVerifMethod::Smtp(c) => Some(c),
                                 _ => None,
                         },
                         EmailProvider::EverythingElse => match &self.everything_else {
-                                EverythingElseVerifMethod::Smtp(c) => c
-                                        .proxy
-                                        .as_ref()
-                                        .and_then(|proxy_id| self.proxies.get(proxy_id)),
+                                EverythingElseVerifMethod::Smtp(c) => Some(c),
                         },
                 }
         }
 
+        /// Get the ID of the provider-specific proxy.
+        /// Returns the ID of the proxy configured specifically for this
+        /// provider, if any.
+        fn get_provider_specific_proxy_id(&self, email_provider: &EmailProvider) -> Option<&String> {
+                self.get_smtp_config(email_provider)?
+                        .proxy
+                        .as_ref()
+                        .filter(|proxy_id| self.proxies.contains_key(*proxy_id))
+        }
+
+        /// Get the name of the proxy pool the email provider rotates through,
+        /// if any, see [VerifMethod::proxy_pools].
+        pub fn get_provider_proxy_pool(&self, email_provider: &EmailProvider) -> Option<&String> {
+                self.get_smtp_config(email_provider)?
+                        .proxy_pool
+                        .as_ref()
+                        .filter(|pool| self.proxy_pools.contains_key(*pool))
+        }
+
+        /// Whether the proxies of the email provider come from a rotation:
+        /// either its own proxy pool, or the global one if it has no proxy of
+        /// its own.
+        fn is_rotated(&self, email_provider: &EmailProvider) -> bool {
+                self.get_provider_proxy_pool(email_provider).is_some()
+                        || (self.proxy_pool.enabled && self.get_provider_specific_proxy_id(email_provider).is_none())
+        }
+
+        /// Get the rotator the proxies of the email provider come from: the
+        /// rotator of its proxy pool if it has one, else the global `rotator`.
+        pub fn get_rotator<'a>(
+                &self,
+                email_provider: &EmailProvider,
+                rotator: Option<&'a ProxyRotator>,
+                pool_rotators: Option<&'a ProxyPoolRotators>,
+        ) -> Option<&'a ProxyRotator> {
+                match self.get_provider_proxy_pool(email_provider) {
+                        Some(pool) => pool_rotators?.get(pool),
+                        None => rotator,
+                }
+        }
+
+        /// Create a rotator for each proxy pool, using the health checks of
+        /// the global `proxy_pool`.
+        pub fn new_pool_rotators(&self) -> ProxyPoolRotators {
+                self.proxy_pools
+                        .iter()
+                        .map(|(name, pool)| {
+                                let rotator = ProxyRotator::new(pool.proxies.clone(), pool.strategy.clone())
+                                        .with_weights(&self.proxies)
+                                        .with_health_config(self.proxy_pool.health.clone());
+                                (name.clone(), rotator)
+                        })
+                        .collect()
+        }
+
         /// Get the proxy to use for the email provider, with support for rotation.
         /// Priority order:
-        /// 1. Provider-specific proxy (if configured)
-        /// 2. Rotated proxy (if proxy_pool is enabled and rotator is provided)
-        /// 3. Default proxy (if exists)
-        /// 4. None
+        /// 1. Rotated proxy from the provider's proxy pool (if configured)
+        /// 2. Provider-specific proxy (if configured)
+        /// 3. Rotated proxy (if proxy_pool is enabled and rotator is provided)
+        /// 4. Default proxy (if exists)
+        /// 5. None
+        ///
+        /// The `rotator` must be the one of the provider, see
+        /// [get_rotator](Self::get_rotator).
         pub fn get_proxy_with_rotation(
                 &self,
                 email_provider: &EmailProvider,
-                rotator: Option<&super::proxy_rotator::ProxyRotator>,
+                rotator: Option<&ProxyRotator>,
         ) -> Option<&CheckEmailInputProxy> {
-                if let Some(provider_proxy) = self.get_provider_specific_proxy(email_provider) {
-                        return Some(provider_proxy);
-                }
+                self.get_proxy_id_with_rotation(email_provider, rotator)
+                        .and_then(|proxy_id| self.proxies.get(proxy_id))
+        }
 
-                if self.proxy_pool.enabled {
+        /// Same as [get_proxy_with_rotation](Self::get_proxy_with_rotation),
+        /// but returns the ID of the proxy.
+        pub fn get_proxy_id_with_rotation(
+                &self,
+                email_provider: &EmailProvider,
+                rotator: Option<&ProxyRotator>,
+        ) -> Option<&String> {
+                self.get_proxy_id_for_domain(email_provider, None, rotator)
+        }
+
+        /// Same as [get_proxy_id_with_rotation](Self::get_proxy_id_with_rotation),
+        /// but the verifications of the email `domain` stick to the same proxy
+        /// with the sticky-per-domain rotation strategy.
+        pub fn get_proxy_id_for_domain(
+                &self,
+                email_provider: &EmailProvider,
+                domain: Option<&str>,
+                rotator: Option<&ProxyRotator>,
+        ) -> Option<&String> {
+                if self.is_rotated(email_provider) {
                         if let Some(rotator) = rotator {
-                                if let Some(proxy_id) = rotator.get_next_proxy_id() {
-                                        if let Some(proxy) = self.proxies.get(proxy_id) {
-                                                return Some(proxy);
+                                let proxy_id = match domain {
+                                        Some(domain) => rotator.get_proxy_id_for_domain(domain),
+                                        None => rotator.get_next_proxy_id(),
+                                };
+                                if let Some(proxy_id) = proxy_id {
+                                        if let Some((proxy_id, _)) = self.proxies.get_key_value(proxy_id) {
+                                                return Some(proxy_id);
                                         }
                                 }
                         }
                 }
 
-                self.proxies.get(DEFAULT_PROXY_ID)
+                if let Some(provider_proxy_id) = self.get_provider_specific_proxy_id(email_provider) {
+                        return Some(provider_proxy_id);
+                }
+
+                self.proxies
+                        .get_key_value(DEFAULT_PROXY_ID)
+                        .map(|(proxy_id, _)| proxy_id)
+        }
+
+        /// Get another proxy from the rotation to retry a verification whose
+        /// proxy was blocked, skipping the proxies already `tried` whatever the
+        /// rotation strategy. Returns
+        /// None if retries are disabled, if the email provider has its own
+        /// proxy, or if there's no untried proxy left, see [ProxyRetryConfig].
+        /// The `rotator` must be the one of the provider, see
+        /// [get_rotator](Self::get_rotator).
+        pub fn get_retry_proxy_id(
+                &self,
+                email_provider: &EmailProvider,
+                rotator: Option<&ProxyRotator>,
+                tried: &[&String],
+        ) -> Option<&String> {
+                let retry = &self.proxy_pool.retry;
+                if !retry.enabled || tried.len() >= retry.max_attempts || !self.is_rotated(email_provider) {
+                        return None;
+                }
+
+                rotator?
+                        .get_next_proxy_id_excluding(tried)
+                        .and_then(|proxy_id| self.proxies.get_key_value(proxy_id))
+                        .map(|(proxy_id, _)| proxy_id)
         }
 }
 
@@ -427,6 +675,21 @@ impl Default for EverythingElseVerifMethod {
         }
 }
 
+/// Whether to upgrade the SMTP session to TLS with the STARTTLS command.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum StartTlsMode {
+        /// Never send STARTTLS.
+        #[default]
+        Disabled,
+        /// Send STARTTLS if the server offers it. If the TLS handshake fails, we
+        /// reconnect and continue without TLS.
+        Opportunistic,
+        /// Send STARTTLS, and fail the verification if the server doesn't offer
+        /// it or if the TLS handshake fails.
+        Required,
+}
+
 /// Configuration on the SMTP verification method. If it used mostly as a
 /// serializable struct, to be converted into the domain type
 /// `VerifMethodSmtp`.
@@ -447,6 +710,9 @@ pub struct VerifMethodSmtpConfig {
         /// Use a proxy to check if an email exists. This proxy must exist in the
         /// `VerifMethod.proxies` field, and it must be a SOCKS5 proxy.
         pub proxy: Option<ProxyID>,
+        /// Rotate through the proxies of this pool, which must exist in the
+        /// `VerifMethod.proxy_pools` field. Takes precedence over `proxy`.
+        pub proxy_pool: Option<String>,
         /// SMTP port to use for email validation. Generally, ports 25, 465, 587
         /// and 2525 are used.
         ///
@@ -467,6 +733,12 @@ pub struct VerifMethodSmtpConfig {
         ///
         /// Defaults to 1.
         pub retries: usize,
+        /// Whether to upgrade the SMTP session to TLS with STARTTLS. The server
+        /// certificate is checked against the MX host name, but an invalid
+        /// certificate is only reported, not rejected.
+        ///
+        /// Defaults to Disabled.
+        pub starttls: StartTlsMode,
 }
 
 impl Default for VerifMethodSmtpConfig {
@@ -475,9 +747,11 @@ impl Default for VerifMethodSmtpConfig {
                         from_email: "reacher@gmail.com".to_string(),
                         hello_name: "gmail.com".to_string(),
                         proxy: None,
+                        proxy_pool: None,
                         smtp_port: 25,
                         smtp_timeout: None,
                         retries: 1,
+                        starttls: StartTlsMode::Disabled,
                 }
         }
 }
@@ -601,6 +875,7 @@ mod tests {
                         proxy_pool: ProxyPoolConfig {
                                 enabled: false,
                                 strategy: ProxyRotationStrategy::RoundRobin,
+                                ..Default::default()
                         },
                         gmail: GmailVerifMethod::Smtp(VerifMethodSmtpConfig {
                                 proxy: Some("proxy1".to_string()),
@@ -622,7 +897,7 @@ mod tests {
 
         #[test]
         fn test_get_proxy_with_rotation_enabled() {
-                use super::proxy_rotator::ProxyRotator;
+                use crate::smtp::proxy_rotator::ProxyRotator;
 
                 let mut proxies = HashMap::new();
                 proxies.insert("proxy1".to_string(), CheckEmailInputProxy::default());
@@ -633,6 +908,7 @@ mod tests {
                         proxy_pool: ProxyPoolConfig {
                                 enabled: true,
                                 strategy: ProxyRotationStrategy::RoundRobin,
+                                ..Default::default()
                         },
                         ..Default::default()
                 };
@@ -657,7 +933,7 @@ mod tests {
 
         #[test]
         fn test_provider_routing_takes_priority_over_rotation() {
-                use super::proxy_rotator::ProxyRotator;
+                use crate::smtp::proxy_rotator::ProxyRotator;
 
                 let mut proxies = HashMap::new();
                 proxies.insert("proxy1".to_string(), CheckEmailInputProxy {
@@ -676,6 +952,7 @@ mod tests {
                         proxy_pool: ProxyPoolConfig {
                                 enabled: true,
                                 strategy: ProxyRotationStrategy::RoundRobin,
+                                ..Default::default()
                         },
                         gmail: GmailVerifMethod::Smtp(VerifMethodSmtpConfig {
                                 proxy: Some("proxy1".to_string()),
@@ -702,6 +979,140 @@ mod tests {
                 assert_eq!(other_proxy.unwrap().host, "proxy2.example.com");
         }
 
+        #[test]
+        fn test_get_retry_proxy_id() {
+                use crate::smtp::proxy_rotator::ProxyRotator;
+
+                let mut proxies = HashMap::new();
+                for proxy_id in ["proxy1", "proxy2", "proxy3"].iter() {
+                        proxies.insert(proxy_id.to_string(), CheckEmailInputProxy::default());
+                }
+                let proxy1 = "proxy1".to_string();
+                let proxy2 = "proxy2".to_string();
+                let proxy3 = "proxy3".to_string();
+
+                let mut verif_method = VerifMethod {
+                        proxies,
+                        proxy_pool: ProxyPoolConfig {
+                                enabled: true,
+                                retry: ProxyRetryConfig {
+                                        enabled: true,
+                                        max_attempts: 2,
+                                        ..Default::default()
+                                },
+                                ..Default::default()
+                        },
+                        gmail: GmailVerifMethod::Smtp(VerifMethodSmtpConfig {
+                                proxy: Some(proxy1.clone()),
+                                ..Default::default()
+                        }),
+                        ..Default::default()
+                };
+                let rotator = ProxyRotator::new(
+                        vec![proxy1.clone(), proxy2.clone(), proxy3.clone()],
+                        ProxyRotationStrategy::RoundRobin,
+                );
+
+                // The next proxy of the rotation is proxy1, which was tried.
+                assert_eq!(
+                        verif_method.get_retry_proxy_id(&EmailProvider::EverythingElse, Some(&rotator), &[&proxy1]),
+                        Some(&proxy2)
+                );
+                // At most 2 proxies are tried.
+                assert_eq!(
+                        verif_method.get_retry_proxy_id(
+                                &EmailProvider::EverythingElse,
+                                Some(&rotator),
+                                &[&proxy1, &proxy2]
+                        ),
+                        None
+                );
+                // The Gmail proxy isn't replaced.
+                assert_eq!(
+                        verif_method.get_retry_proxy_id(&EmailProvider::Gmail, Some(&rotator), &[&proxy1]),
+                        None
+                );
+
+                verif_method.proxy_pool.retry.max_attempts = 3;
+                assert_eq!(
+                        verif_method.get_retry_proxy_id(
+                                &EmailProvider::EverythingElse,
+                                Some(&rotator),
+                                &[&proxy1, &proxy3]
+                        ),
+                        Some(&proxy2)
+                );
+
+                verif_method.proxy_pool.retry.enabled = false;
+                assert_eq!(
+                        verif_method.get_retry_proxy_id(&EmailProvider::EverythingElse, Some(&rotator), &[&proxy1]),
+                        None
+                );
+        }
+
+        #[test]
+        fn test_proxy_pools() {
+                let mut proxies = HashMap::new();
+                for proxy_id in ["residential1", "residential2", "datacenter"].iter() {
+                        proxies.insert(proxy_id.to_string(), CheckEmailInputProxy::default());
+                }
+                let mut proxy_pools = HashMap::new();
+                proxy_pools.insert(
+                        "residential".to_string(),
+                        NamedProxyPool {
+                                proxies: vec!["residential1".to_string(), "residential2".to_string()],
+                                ..Default::default()
+                        },
+                );
+                let mut verif_method = VerifMethod {
+                        proxies,
+                        proxy_pools,
+                        gmail: GmailVerifMethod::Smtp(VerifMethodSmtpConfig {
+                                proxy: Some("datacenter".to_string()),
+                                proxy_pool: Some("residential".to_string()),
+                                ..Default::default()
+                        }),
+                        everything_else: EverythingElseVerifMethod::Smtp(VerifMethodSmtpConfig {
+                                proxy: Some("datacenter".to_string()),
+                                ..Default::default()
+                        }),
+                        ..Default::default()
+                };
+                assert!(verif_method.validate_proxies().is_ok());
+
+                // Gmail rotates through its pool, even if the global pool is
+                // disabled.
+                let pool_rotators = verif_method.new_pool_rotators();
+                let rotator = verif_method.get_rotator(&EmailProvider::Gmail, None, Some(&pool_rotators));
+                let gmail_proxies: Vec<_> = (0..3)
+                        .map(|_| verif_method.get_proxy_id_with_rotation(&EmailProvider::Gmail, rotator))
+                        .collect();
+                assert_eq!(
+                        gmail_proxies,
+                        vec![
+                                Some(&"residential1".to_string()),
+                                Some(&"residential2".to_string()),
+                                Some(&"residential1".to_string())
+                        ]
+                );
+                let rotator = verif_method.get_rotator(&EmailProvider::EverythingElse, None, Some(&pool_rotators));
+                assert_eq!(
+                        verif_method.get_proxy_id_with_rotation(&EmailProvider::EverythingElse, rotator),
+                        Some(&"datacenter".to_string())
+                );
+
+                verif_method
+                        .proxy_pools
+                        .get_mut("residential")
+                        .unwrap()
+                        .proxies
+                        .push("proxy3".to_string());
+                assert!(verif_method.validate_proxies().is_err());
+
+                verif_method.proxy_pools.clear();
+                assert!(verif_method.validate_proxies().is_err());
+        }
+
         #[test]
         fn test_fallback_to_default_when_rotation_pool_empty() {
                 let mut proxies = HashMap::new();
@@ -716,6 +1127,7 @@ mod tests {
                         proxy_pool: ProxyPoolConfig {
                                 enabled: true,
                                 strategy: ProxyRotationStrategy::RoundRobin,
+                                ..Default::default()
                         },
                         ..Default::default()
                 };
@@ -729,7 +1141,7 @@ mod tests {
 
         #[test]
         fn test_rotation_excludes_default_proxy_from_pool() {
-                use super::proxy_rotator::ProxyRotator;
+                use crate::smtp::proxy_rotator::ProxyRotator;
 
                 let mut proxies = HashMap::new();
                 proxies.insert(DEFAULT_PROXY_ID.to_string(), CheckEmailInputProxy {
@@ -748,6 +1160,7 @@ mod tests {
                         proxy_pool: ProxyPoolConfig {
                                 enabled: true,
                                 strategy: ProxyRotationStrategy::RoundRobin,
+                                ..Default::default()
                         },
                         ..Default::default()
                 };
@@ -20,16 +20,15 @@ use crate::EmailAddress;
 use levenshtein::levenshtein;
 use normalize::normalize_email;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
 
 /// Syntax information after parsing an email address
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct SyntaxDetails {
 	/// The email address as a async_smtp `EmailAddress`. It will be `None` if
-	/// the email address is ill-formed.
+	/// the email address is ill-formed. Its domain is in ASCII (punycode) form.
 	pub address: Option<EmailAddress>,
-	/// The domain name, after "@". It will be the empty string if the email
-	/// address if ill-formed.
+	/// The domain name, after "@", in ASCII (punycode) form. It will be the
+	/// empty string if the email address if ill-formed.
 	pub domain: String,
 	/// Does the email have a valid syntax?
 	pub is_valid_syntax: bool,
@@ -40,6 +39,14 @@ pub struct SyntaxDetails {
 	/// address is ill-formed.
 	pub normalized_email: Option<String>,
 	pub suggestion: Option<String>,
+	/// The domain name in Unicode form, if it's an internationalized domain
+	/// name, e.g. "bücher.example" for "xn--bcher-kva.example".
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub unicode_domain: Option<String>,
+	/// Does the username contain non-ASCII characters (RFC 6531)? Such an
+	/// address can only be verified on SMTP servers supporting SMTPUTF8.
+	#[serde(default)]
+	pub is_international: bool,
 }
 
 impl Default for SyntaxDetails {
@@ -51,59 +58,61 @@ impl Default for SyntaxDetails {
 			username: "".into(),
 			normalized_email: None,
 			suggestion: None,
+			unicode_domain: None,
+			is_international: false,
 		}
 	}
 }
 
+/// Convert a domain name to its ASCII form, using punycode for the
+/// internationalized labels (IDNA). ASCII domains are returned unchanged.
+/// Returns None if the domain isn't a valid internationalized domain name.
+pub fn domain_to_ascii(domain: &str) -> Option<String> {
+	if domain.is_ascii() {
+		return Some(domain.to_string());
+	}
+	idna::domain_to_ascii(domain).ok()
+}
+
 /// From an `email_address` string, compute syntax information about it, such as
 /// username and domain.
+///
+/// Internationalized email addresses are supported: the domain may be an
+/// internationalized domain name, and the username may contain UTF-8
+/// characters as per RFC 6531.
 pub fn check_syntax(email_address: &str) -> SyntaxDetails {
-	let email_address = match EmailAddress::from_str(email_address) {
-		Ok(m) => {
-			if mailchecker::is_valid(email_address) {
-				m
-			} else {
-				return SyntaxDetails {
-					address: None,
-					domain: "".into(),
-					is_valid_syntax: false,
-					username: "".into(),
-					normalized_email: None,
-					suggestion: None,
-				};
-			}
-		}
-		_ => {
-			return SyntaxDetails {
-				address: None,
-				domain: "".into(),
-				is_valid_syntax: false,
-				username: "".into(),
-				normalized_email: None,
-				suggestion: None,
-			}
-		}
+	let Some((username, input_domain)) = email_address.rsplit_once('@') else {
+		return SyntaxDetails::default();
+	};
+	let Some(domain) = domain_to_ascii(input_domain) else {
+		return SyntaxDetails::default();
 	};
 
-	let iter: &str = email_address.as_ref();
-	let mut iter = iter.split('@');
-	let username: String = iter
-		.next()
-		.expect("We checked above that email is valid. qed.")
-		.into();
-	let domain: String = iter
-		.next()
-		.expect("We checked above that email is valid. qed.")
-		.into();
-	let normalized_email = normalize_email(&username, &domain);
+	// The syntax checks below only know about ASCII addresses. RFC 6531 allows
+	// UTF-8 characters wherever ASCII letters are allowed in the username, so
+	// we check the address with its non-ASCII characters replaced by a letter.
+	let ascii_username: String = username
+		.chars()
+		.map(|c| if c.is_ascii() { c } else { 'a' })
+		.collect();
+	if !mailchecker::is_valid(&format!("{}@{}", ascii_username, domain)) {
+		return SyntaxDetails::default();
+	}
+	let Ok(email_address) = EmailAddress::new(format!("{}@{}", username, domain)) else {
+		return SyntaxDetails::default();
+	};
+
+	let normalized_email = normalize_email(username, &domain);
 
 	SyntaxDetails {
 		address: Some(email_address),
+		unicode_domain: (domain != input_domain).then(|| input_domain.to_string()),
 		domain,
 		is_valid_syntax: true,
-		username,
+		username: username.to_string(),
 		normalized_email: Some(normalized_email),
 		suggestion: None,
+		is_international: !username.is_ascii(),
 	}
 }
 
@@ -149,6 +158,8 @@ mod tests {
 				username: "".into(),
 				normalized_email: None,
 				suggestion: None,
+				unicode_domain: None,
+				is_international: false,
 			}
 		);
 	}
@@ -164,6 +175,8 @@ mod tests {
 				username: "".into(),
 				normalized_email: None,
 				suggestion: None,
+				unicode_domain: None,
+				is_international: false,
 			}
 		);
 	}
@@ -179,6 +192,8 @@ mod tests {
 				username: "foo".into(),
 				normalized_email: Some("foo@bar.com".into()),
 				suggestion: None,
+				unicode_domain: None,
+				is_international: false,
 			}
 		);
 	}
@@ -192,8 +207,38 @@ mod tests {
 			username: "test".into(),
 			normalized_email: Some("test@gmali.com".into()),
 			suggestion: None,
+			..Default::default()
 		};
 		get_similar_mail_provider(&mut syntax);
 		assert_eq!(syntax.suggestion, Some("test@gmail.com".to_string()))
 	}
+
+	#[test]
+	fn should_convert_idn_domain_to_punycode() {
+		let syntax = check_syntax("foo@bücher.example");
+		assert!(syntax.is_valid_syntax);
+		assert_eq!(syntax.domain, "xn--bcher-kva.example");
+		assert_eq!(syntax.unicode_domain, Some("bücher.example".into()));
+		assert_eq!(
+			syntax.address.map(|a| a.to_string()),
+			Some("foo@xn--bcher-kva.example".into())
+		);
+		assert!(!syntax.is_international);
+	}
+
+	#[test]
+	fn should_accept_utf8_username() {
+		let syntax = check_syntax("用户@例子.广告");
+		assert!(syntax.is_valid_syntax);
+		assert_eq!(syntax.username, "用户");
+		assert_eq!(syntax.domain, "xn--fsqu00a.xn--4rr70v");
+		assert!(syntax.is_international);
+		assert!(syntax.address.unwrap().is_international());
+	}
+
+	#[test]
+	fn should_return_invalid_for_invalid_utf8_username() {
+		assert!(!check_syntax("用户..名@example.com").is_valid_syntax);
+		assert!(!check_syntax("用户 名@example.com").is_valid_syntax);
+	}
 }
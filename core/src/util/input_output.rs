@@ -15,8 +15,14 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::misc::{MiscDetails, MiscError};
-use crate::mx::{MxDetails, MxError};
-use crate::smtp::proxy_rotator::ProxyRotator;
+use crate::mx::resolver::DnsResolver;
+use crate::mx::{MxDetails, MxError, MxFallbackStrategy};
+use crate::reachability::{ReachabilityPolicy, ReasonCode};
+use crate::score::ScoreWeights;
+use crate::smtp::catch_all::CatchAllCache;
+use crate::smtp::greylisting::GreylistingConfig;
+use crate::smtp::proxy_rotator::{ProxyPoolRotators, ProxyRotator};
+use crate::smtp::session_pool::SmtpSessionPool;
 use crate::smtp::verif_method::VerifMethod;
 use crate::smtp::{SmtpDebug, SmtpDetails, SmtpError, SmtpErrorDesc};
 use crate::syntax::SyntaxDetails;
@@ -25,15 +31,21 @@ use async_smtp::EmailAddress as AsyncSmtpEmailAddress;
 use chrono::{DateTime, Utc};
 use derive_builder::Builder;
 use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+use std::convert::TryFrom;
 use std::fmt::Display;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
-
-/// Wrapper around the `EmailAddress` from `async_smtp` to allow for
-/// serialization and deserialization.
+use std::time::{Duration, Instant, SystemTime};
+
+/// An email address, checked to be safe to use in SMTP commands. Unlike
+/// the `EmailAddress` from `async_smtp`, non-ASCII characters are allowed,
+/// for internationalized email addresses (RFC 6531).
+///
+/// Breaking change in 0.12: this used to wrap the `EmailAddress` from
+/// `async_smtp`. Convert between the two with `From` and `TryFrom`, the
+/// latter failing for internationalized email addresses.
 #[derive(Debug, Eq, PartialEq, Clone)]
-pub struct EmailAddress(AsyncSmtpEmailAddress);
+pub struct EmailAddress(String);
 
 impl Serialize for EmailAddress {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -50,9 +62,7 @@ impl<'de> Deserialize<'de> for EmailAddress {
                 D: serde::Deserializer<'de>,
         {
                 let s = String::deserialize(deserializer)?;
-                Ok(EmailAddress(
-                        AsyncSmtpEmailAddress::from_str(&s).map_err(serde::de::Error::custom)?,
-                ))
+                EmailAddress::new(s).map_err(serde::de::Error::custom)
         }
 }
 
@@ -66,29 +76,54 @@ impl FromStr for EmailAddress {
         type Err = anyhow::Error;
 
         fn from_str(s: &str) -> Result<Self, Self::Err> {
-                Ok(EmailAddress(AsyncSmtpEmailAddress::from_str(s)?))
+                EmailAddress::new(s.to_string())
         }
 }
 
 impl EmailAddress {
+        /// Create a new email address. Like `async_smtp`, we only check that
+        /// it doesn't contain characters that would allow injecting commands
+        /// into the SMTP conversation; see `check_syntax` for the actual
+        /// validation.
         pub fn new(email: String) -> Result<Self, anyhow::Error> {
-                Ok(EmailAddress(AsyncSmtpEmailAddress::new(email)?))
+                if email
+                        .chars()
+                        .any(|c| c.is_control() || c.is_whitespace() || c == '<' || c == '>')
+                {
+                        anyhow::bail!("invalid email address");
+                }
+
+                Ok(EmailAddress(email))
+        }
+
+        /// Does the email address contain non-ASCII characters? Such an
+        /// address can only be sent to SMTP servers supporting SMTPUTF8.
+        pub fn is_international(&self) -> bool {
+                !self.0.is_ascii()
         }
 
-        pub fn into_inner(self) -> AsyncSmtpEmailAddress {
+        pub fn into_inner(self) -> String {
                 self.0
         }
 }
 
-impl AsRef<AsyncSmtpEmailAddress> for EmailAddress {
-        fn as_ref(&self) -> &AsyncSmtpEmailAddress {
+impl AsRef<str> for EmailAddress {
+        fn as_ref(&self) -> &str {
                 &self.0
         }
 }
 
-impl AsRef<str> for EmailAddress {
-        fn as_ref(&self) -> &str {
-                self.0.as_ref()
+impl From<AsyncSmtpEmailAddress> for EmailAddress {
+        fn from(email: AsyncSmtpEmailAddress) -> Self {
+                EmailAddress(email.to_string())
+        }
+}
+
+impl TryFrom<EmailAddress> for AsyncSmtpEmailAddress {
+        type Error = anyhow::Error;
+
+        fn try_from(email: EmailAddress) -> Result<Self, Self::Error> {
+                AsyncSmtpEmailAddress::new(email.0)
         }
 }
 
@@ -106,6 +141,10 @@ pub struct CheckEmailInputProxy {
         pub password: Option<String>,
         /// The timeout for the connection to the proxy, in ms.
         pub timeout_ms: Option<u64>,
+        /// Weight of the proxy in the proxy pool, when using the weighted
+        /// rotation strategy. A proxy with a weight of 0 is only picked if no
+        /// other proxy is available. Defaults to 1.
+        pub weight: Option<u32>,
 }
 
 /// Builder pattern for the input argument into the main `email_exists`
@@ -118,6 +157,30 @@ pub struct CheckEmailInput {
 
         pub verif_method: VerifMethod,
 
+        /// How to pick the MX hosts to connect to during the SMTP verification.
+        ///
+        /// Defaults to trying up to 3 MX hosts by order of preference.
+        #[serde(default)]
+        pub mx_fallback: MxFallbackStrategy,
+
+        /// How to retry the SMTP verification when the server greylists us.
+        ///
+        /// Defaults to not waiting, see [GreylistingConfig].
+        #[serde(default)]
+        pub greylisting: GreylistingConfig,
+
+        /// Which signals make the email risky or invalid, see
+        /// [ReachabilityPolicy].
+        ///
+        /// Defaults to the historical behavior.
+        #[serde(default)]
+        pub reachability_policy: ReachabilityPolicy,
+
+        /// Weights of the signals in the deliverability score, see
+        /// [ScoreWeights].
+        #[serde(default)]
+        pub score_weights: ScoreWeights,
+
         /// Shared proxy rotator for round-robin proxy selection across requests.
         /// This should be created once and shared across all email verification requests
         /// to ensure proper rotation. If None, a new rotator will be created for each request
@@ -126,6 +189,45 @@ pub struct CheckEmailInput {
         #[builder(default)]
         pub proxy_rotator: Option<Arc<ProxyRotator>>,
 
+        /// Shared rotators of the named proxy pools, see
+        /// [VerifMethod::new_pool_rotators]. Like `proxy_rotator`, they should
+        /// be created once and shared across all email verifications. If None,
+        /// new rotators are created for each request.
+        #[serde(skip)]
+        #[builder(default)]
+        pub proxy_pool_rotators: Option<Arc<ProxyPoolRotators>>,
+
+        /// Shared DNS resolver used for the MX lookups, see
+        /// [build_resolver](crate::mx::resolver::build_resolver). It should be
+        /// created once and shared across all email verifications, so that its
+        /// cache is reused. If None, a new resolver is built from the system
+        /// configuration for each request.
+        #[serde(skip)]
+        #[builder(default)]
+        pub dns_resolver: Option<Arc<DnsResolver>>,
+
+        /// Shared pool of idle SMTP sessions. If set, the SMTP verification
+        /// reuses an idle session to the same MX host when possible, instead
+        /// of opening a new connection, see [SmtpSessionPool]. This is meant
+        /// for verifying many emails at the same domains, e.g. bulk jobs.
+        #[serde(skip)]
+        #[builder(default)]
+        pub smtp_session_pool: Option<Arc<SmtpSessionPool>>,
+
+        /// Shared cache of the catch-all probes by domain. If set, the
+        /// catch-all probe is skipped for domains probed recently, see
+        /// [CatchAllCache].
+        #[serde(skip)]
+        #[builder(default)]
+        pub catch_all_cache: Option<Arc<dyn CatchAllCache>>,
+
+        /// Whether to record the full SMTP conversation (commands, replies and
+        /// their timestamps) in `debug.smtp.transcript`.
+        ///
+        /// Defaults to false.
+        #[serde(default)]
+        pub smtp_transcript: bool,
+
         /// Whether to check if a gravatar image is existing for the given email.
         /// Adds a bit of latency to the verification process.
         ///
@@ -134,6 +236,13 @@ pub struct CheckEmailInput {
         /// Check if a the email address is present in HaveIBeenPwned API.
         /// If the api_key is filled, HaveIBeenPwned API is checked
         pub haveibeenpwned_api_key: Option<String>,
+        /// Timeout of each of the Gravatar and HaveIBeenPwned lookups. They
+        /// run concurrently with the SMTP verification, a lookup timing out
+        /// is left empty in the output.
+        ///
+        /// Defaults to 10 seconds.
+        #[serde(default = "default_misc_timeout")]
+        pub misc_timeout: Option<Duration>,
 
         /// The WebDriver address to use for headless verifications.
         ///
@@ -155,14 +264,28 @@ pub struct CheckEmailInput {
         pub sentry_dsn: Option<String>,
 }
 
+fn default_misc_timeout() -> Option<Duration> {
+        Some(Duration::from_secs(10))
+}
+
 impl Default for CheckEmailInput {
         fn default() -> Self {
                 CheckEmailInput {
                         to_email: "".into(),
                         verif_method: VerifMethod::default(),
+                        mx_fallback: MxFallbackStrategy::default(),
+                        greylisting: GreylistingConfig::default(),
+                        reachability_policy: ReachabilityPolicy::default(),
+                        score_weights: ScoreWeights::default(),
                         proxy_rotator: None,
+                        proxy_pool_rotators: None,
+                        dns_resolver: None,
+                        smtp_session_pool: None,
+                        catch_all_cache: None,
+                        smtp_transcript: false,
                         check_gravatar: false,
                         haveibeenpwned_api_key: None,
+                        misc_timeout: default_misc_timeout(),
                         webdriver_addr: "http://localhost:9515".into(),
                         webdriver_config: WebdriverConfig::default(),
                         backend_name: "backend-dev".into(),
@@ -193,6 +316,56 @@ pub enum Reachable {
         Unknown,
 }
 
+/// Time spent in each stage of the email verification. A stage is `None` if
+/// it didn't run. The SMTP stages add up over all the connections made, e.g.
+/// on retries or MX fallbacks.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Serialize)]
+pub struct StageTimings {
+        /// Syntax check.
+        pub syntax: Option<Duration>,
+        /// MX lookup.
+        pub mx_lookup: Option<Duration>,
+        /// All the misc checks, including the Gravatar and HaveIBeenPwned
+        /// lookups.
+        pub misc: Option<Duration>,
+        /// Gravatar lookup.
+        pub gravatar: Option<Duration>,
+        /// HaveIBeenPwned lookup.
+        pub haveibeenpwned: Option<Duration>,
+        /// Connection to the SMTP server, including the SOCKS5 handshake if
+        /// a proxy is used.
+        pub proxy_connect: Option<Duration>,
+        /// SMTP banner, EHLO, and STARTTLS if negotiated.
+        pub smtp_handshake: Option<Duration>,
+        /// RCPT TO of the random address probing whether the domain is
+        /// catch-all.
+        pub catch_all_rcpt: Option<Duration>,
+        /// RCPT TO of the verified email.
+        pub rcpt: Option<Duration>,
+}
+
+impl StageTimings {
+        /// The name and timing of each stage, in the order they run.
+        pub fn stages(&self) -> [(&'static str, Option<Duration>); 9] {
+                [
+                        ("syntax", self.syntax),
+                        ("mx_lookup", self.mx_lookup),
+                        ("misc", self.misc),
+                        ("gravatar", self.gravatar),
+                        ("haveibeenpwned", self.haveibeenpwned),
+                        ("proxy_connect", self.proxy_connect),
+                        ("smtp_handshake", self.smtp_handshake),
+                        ("catch_all_rcpt", self.catch_all_rcpt),
+                        ("rcpt", self.rcpt),
+                ]
+        }
+
+        /// Add the time elapsed since `start` to a stage.
+        pub fn record(stage: &mut Option<Duration>, start: Instant) {
+                *stage = Some(stage.unwrap_or_default() + start.elapsed());
+        }
+}
+
 /// Details about the email verification used for debugging.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DebugDetails {
@@ -206,6 +379,9 @@ pub struct DebugDetails {
         pub duration: Duration,
         /// Details about the email verification used for debugging.
         pub smtp: SmtpDebug,
+        /// Time spent in each stage of the email verification.
+        #[serde(default)]
+        pub timings: StageTimings,
 }
 
 impl Default for DebugDetails {
@@ -215,6 +391,7 @@ impl Default for DebugDetails {
                         end_time: SystemTime::now().into(),
                         duration: Duration::default(),
                         smtp: SmtpDebug::default(),
+                        timings: StageTimings::default(),
                         backend_name: "backend-dev".into(),
                 }
         }
@@ -229,6 +406,14 @@ pub struct CheckEmailOutput {
         /// The reason why the email is classified as risky, invalid, or unknown.
         /// This field is always present and provides a human-readable explanation.
         pub reason: String,
+        /// Machine-readable codes of the reason, e.g. `disposable` or
+        /// `smtp_timeout`. A safe email only gets the codes of the signals
+        /// mentioned in its reason, see
+        /// [SignalOutcome::Safe](crate::reachability::SignalOutcome::Safe).
+        pub reason_codes: Vec<ReasonCode>,
+        /// Deliverability score, between 0 (undeliverable) and 100 (safe to
+        /// send), see [calculate_score](crate::score::calculate_score).
+        pub score: u8,
         /// Misc details about the email address.
         pub misc: Result<MiscDetails, MiscError>,
         /// Details about the MX host.
@@ -247,6 +432,8 @@ impl Default for CheckEmailOutput {
                         input: String::default(),
                         is_reachable: Reachable::Unknown,
                         reason: "No verification performed".to_string(),
+                        reason_codes: Vec::new(),
+                        score: 0,
                         misc: Ok(MiscDetails::default()),
                         mx: Ok(MxDetails::default()),
                         smtp: Ok(SmtpDetails::default()),
@@ -256,6 +443,17 @@ impl Default for CheckEmailOutput {
         }
 }
 
+impl CheckEmailOutput {
+        /// The primary reason code, i.e. the first one. None if the email is
+        /// safe, even if it has reason codes.
+        pub fn sub_status(&self) -> Option<ReasonCode> {
+                match self.is_reachable {
+                        Reachable::Safe => None,
+                        _ => self.reason_codes.first().copied(),
+                }
+        }
+}
+
 // Implement a custom serialize.
 impl Serialize for CheckEmailOutput {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -276,6 +474,9 @@ impl Serialize for CheckEmailOutput {
                 map.serialize_entry("input", &self.input)?;
                 map.serialize_entry("is_reachable", &self.is_reachable)?;
                 map.serialize_entry("reason", &self.reason)?;
+                map.serialize_entry("reason_codes", &self.reason_codes)?;
+                map.serialize_entry("sub_status", &self.sub_status())?;
+                map.serialize_entry("score", &self.score)?;
                 match &self.misc {
                         Ok(t) => map.serialize_entry("misc", &t)?,
                         Err(error) => map.serialize_entry(
@@ -319,9 +520,54 @@ pub struct WebdriverConfig {
 
 #[cfg(test)]
 mod tests {
-        use super::{CheckEmailOutput, DebugDetails};
+        use super::{CheckEmailInput, CheckEmailOutput, DebugDetails, ReasonCode};
+        use std::time::Duration;
         use async_smtp::response::{Category, Code, Detail, Response, Severity};
 
+        #[test]
+        fn should_default_misc_timeout_when_deserializing() {
+                let mut input = serde_json::to_value(CheckEmailInput::default()).unwrap();
+                input.as_object_mut().unwrap().remove("misc_timeout");
+                let input: CheckEmailInput = serde_json::from_value(input).unwrap();
+                assert_eq!(input.misc_timeout, Some(Duration::from_secs(10)));
+        }
+
+        #[test]
+        fn should_have_no_sub_status_when_safe() {
+                let output = CheckEmailOutput {
+                        is_reachable: super::Reachable::Safe,
+                        ..Default::default()
+                };
+                assert_eq!(output.sub_status(), None);
+                let actual = serde_json::to_string(&output).unwrap();
+                assert!(actual.contains(r#""reason_codes":[],"sub_status":null"#));
+
+                // The signals mentioned in the reason of a safe email have codes.
+                let output = CheckEmailOutput {
+                        is_reachable: super::Reachable::Safe,
+                        reason: "Safe: role-based account (e.g., admin@, support@)".to_string(),
+                        reason_codes: vec![ReasonCode::RoleAccount],
+                        ..Default::default()
+                };
+                assert_eq!(output.sub_status(), None);
+                let actual = serde_json::to_string(&output).unwrap();
+                assert!(actual.contains(r#""reason_codes":["role_account"],"sub_status":null"#));
+        }
+
+        #[test]
+        fn should_convert_from_and_to_async_smtp() {
+                use super::{AsyncSmtpEmailAddress, EmailAddress};
+                use std::convert::TryFrom;
+                use std::str::FromStr;
+
+                let email = EmailAddress::from(AsyncSmtpEmailAddress::from_str("foo@bar.com").unwrap());
+                assert_eq!(email.to_string(), "foo@bar.com");
+                assert!(AsyncSmtpEmailAddress::try_from(email).is_ok());
+
+                let email = EmailAddress::from_str("用户@例子.广告").unwrap();
+                assert!(AsyncSmtpEmailAddress::try_from(email).is_err());
+        }
+
         #[test]
         fn should_serialize_correctly() {
                 // create a dummy CheckEmailOutput, with a given message as a transient
@@ -340,6 +586,8 @@ mod tests {
                                 input: "foo".to_string(),
                                 is_reachable: super::Reachable::Unknown,
                                 reason: "Unknown: SMTP error for testing".to_string(),
+                                reason_codes: Vec::new(),
+                                score: 0,
                                 misc: Ok(super::MiscDetails::default()),
                                 mx: Ok(super::MxDetails::default()),
                                 syntax: super::SyntaxDetails::default(),
@@ -19,3 +19,13 @@ pub mod public_ip;
 #[cfg(feature = "sentry")]
 pub mod sentry;
 pub mod ser_with_display;
+
+/// Write a temporary file for tests, removed when dropped.
+#[cfg(test)]
+pub(crate) fn temp_file(content: &str) -> tempfile::NamedTempFile {
+	use std::io::Write;
+
+	let mut file = tempfile::NamedTempFile::new().unwrap();
+	file.write_all(content.as_bytes()).unwrap();
+	file
+}
@@ -14,13 +14,12 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use check_if_email_exists::CheckEmailOutput;
 use lambda_runtime::{service_fn, Error, LambdaEvent};
 use reacher_backend::config::{load_config, BackendConfig};
 use reacher_backend::http::CheckEmailRequest;
 use reacher_backend::storage::commercial_license_trial::send_to_reacher;
 use reacher_backend::worker::do_work::{
-	check_email_and_send_result, CheckEmailJobId, CheckEmailTask, TaskWebhook,
+	check_email_and_send_result, CheckEmailJobId, CheckEmailTask, TaskOutput, TaskWebhook,
 };
 use serde::Deserialize;
 use std::process::Command;
@@ -60,6 +59,8 @@ impl CheckEmailPartialTask {
 			input: self.input.to_check_email_input(backend_config),
 			job_id: self.job_id,
 			webhook: self.webhook,
+			deferrals: 0,
+			bypass_cache: false,
 		}
 	}
 }
@@ -88,7 +89,7 @@ async fn main() -> Result<(), Error> {
 	Ok(())
 }
 
-async fn handler(event: LambdaEvent<SQSPayload>) -> Result<CheckEmailOutput, Error> {
+async fn handler(event: LambdaEvent<SQSPayload>) -> Result<TaskOutput, Error> {
 	let (request, _context) = event.into_parts();
 	// Since we're only fetching a single message, we can safely unwrap here.
 	let message = request.records.first().expect("No messages in the event");
@@ -100,10 +101,10 @@ async fn handler(event: LambdaEvent<SQSPayload>) -> Result<CheckEmailOutput, Err
 
 	let task = &task.into_check_email_task(backend_config.clone());
 
-	let worker_output = check_email_and_send_result(task).await;
+	let worker_output = check_email_and_send_result(task, backend_config.clone()).await;
 	match worker_output.as_ref() {
 		Ok(output) => {
-			info!(email = ?output.input, is_reachable = ?output.is_reachable, "Task completed");
+			info!(email = ?task.input.to_email, is_reachable = ?output.is_reachable(), "Task completed");
 		}
 		Err(e) => {
 			info!(email = ?task.input.to_email, err = ?e, "Task failed");